@@ -0,0 +1,110 @@
+use crate::error::CasError;
+use async_trait::async_trait;
+use common::Digest;
+use prost::Message;
+use protos::re::ActionResult;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::{fs, sync::Mutex};
+
+/// Storage for the results of previously executed actions, keyed by action digest.
+#[async_trait]
+pub trait ActionCache: Clone + Send + Sync + 'static {
+    async fn get_action_result(
+        &self,
+        action_digest: &Digest,
+    ) -> Result<Option<ActionResult>, CasError>;
+
+    async fn update_action_result(
+        &self,
+        action_digest: Digest,
+        result: ActionResult,
+    ) -> Result<(), CasError>;
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct InMemoryActionCache {
+    results: Arc<Mutex<HashMap<Digest, ActionResult>>>,
+}
+
+#[async_trait]
+impl ActionCache for InMemoryActionCache {
+    async fn get_action_result(
+        &self,
+        action_digest: &Digest,
+    ) -> Result<Option<ActionResult>, CasError> {
+        let results = self.results.lock().await;
+        Ok(results.get(action_digest).cloned())
+    }
+
+    async fn update_action_result(
+        &self,
+        action_digest: Digest,
+        result: ActionResult,
+    ) -> Result<(), CasError> {
+        let mut results = self.results.lock().await;
+        results.insert(action_digest, result);
+        Ok(())
+    }
+}
+
+/// An action cache persisting encoded `ActionResult`s to a local directory,
+/// using the same sharded layout as [`crate::OnDisk`]. Entries are named by
+/// both the hash and size of the action digest, as in-memory entries are keyed.
+#[derive(Debug, Clone)]
+pub struct OnDiskActionCache {
+    root: Arc<PathBuf>,
+}
+
+impl OnDiskActionCache {
+    pub fn new(root: impl AsRef<Path>) -> Result<Self, CasError> {
+        let root = root.as_ref().to_path_buf();
+        std::fs::create_dir_all(root.join(crate::disk::TMP_DIR))?;
+        Ok(OnDiskActionCache {
+            root: Arc::new(root),
+        })
+    }
+
+    /// Where the result of `action_digest` is kept, `{root}/ab/abcd..._42` for
+    /// the action `abcd...:42`.
+    fn entry_path(&self, action_digest: &Digest) -> Option<PathBuf> {
+        let path = crate::disk::sharded_path(&self.root, action_digest)?;
+        Some(path.with_file_name(format!(
+            "{}_{}",
+            action_digest.hash(),
+            action_digest.size_bytes()
+        )))
+    }
+}
+
+#[async_trait]
+impl ActionCache for OnDiskActionCache {
+    async fn get_action_result(
+        &self,
+        action_digest: &Digest,
+    ) -> Result<Option<ActionResult>, CasError> {
+        let Some(path) = self.entry_path(action_digest) else {
+            return Ok(None);
+        };
+        let data = match fs::read(&path).await {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let result = ActionResult::decode(data.as_slice())
+            .map_err(|_| CasError::CorruptActionResult(action_digest.clone()))?;
+        Ok(Some(result))
+    }
+
+    async fn update_action_result(
+        &self,
+        action_digest: Digest,
+        result: ActionResult,
+    ) -> Result<(), CasError> {
+        let path = self
+            .entry_path(&action_digest)
+            .ok_or_else(|| CasError::InvalidActionDigest(action_digest.clone()))?;
+        crate::disk::write_atomic(&self.root, &path, &result.encode_to_vec()).await
+    }
+}
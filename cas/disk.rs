@@ -0,0 +1,210 @@
+use crate::error::CasError;
+use crate::stream::Hashing;
+use crate::{BlobReader, BlobWriter};
+use async_trait::async_trait;
+use common::Digest;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::fs;
+use tokio::io::{AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+
+/// Directory under the storage root that in-flight writes are staged in.
+pub(crate) const TMP_DIR: &str = "tmp";
+
+/// A content addressable store that persists blobs to a local directory.
+///
+/// Blobs are sharded by the first two characters of their hash, so the blob
+/// `abcd...:42` lives at `{root}/ab/abcd...`. Writes are staged in
+/// `{root}/tmp` and renamed into place so a partially written blob is never
+/// visible to readers.
+#[derive(Debug, Clone)]
+pub struct OnDisk {
+    root: Arc<PathBuf>,
+}
+
+impl OnDisk {
+    pub fn new(root: impl AsRef<Path>) -> Result<Self, CasError> {
+        let root = root.as_ref().to_path_buf();
+        std::fs::create_dir_all(root.join(TMP_DIR))?;
+        Ok(OnDisk {
+            root: Arc::new(root),
+        })
+    }
+}
+
+/// Location of a digest under `root`, or `None` if the digest can't name one.
+pub(crate) fn sharded_path(root: &Path, digest: &Digest) -> Option<PathBuf> {
+    let hash = digest.hash();
+    // Digests converted from protos are unvalidated, don't let them walk the filesystem.
+    if hash.len() < 2 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut path = root.to_path_buf();
+    path.push(&hash[..2]);
+    path.push(hash);
+    Some(path)
+}
+
+/// Write `data` to `path` by staging it in `{root}/tmp` and renaming it into place.
+pub(crate) async fn write_atomic(root: &Path, path: &Path, data: &[u8]) -> Result<(), CasError> {
+    let tmp_path = root.join(TMP_DIR).join(Uuid::new_v4().to_string());
+    let staged = async {
+        let mut file = fs::File::create(&tmp_path).await?;
+        file.write_all(data).await?;
+        file.sync_all().await?;
+        if let Some(shard) = path.parent() {
+            fs::create_dir_all(shard).await?;
+        }
+        fs::rename(&tmp_path, path).await?;
+        Ok::<(), CasError>(())
+    };
+    if let Err(err) = staged.await {
+        let _ = fs::remove_file(&tmp_path).await;
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// A blob hashed as it is staged in `{root}/tmp`, to be renamed into place once
+/// it is committed. The staged file is removed if it never is.
+struct DiskWriter {
+    root: Arc<PathBuf>,
+    tmp_path: PathBuf,
+    file: Hashing<fs::File>,
+    committed: bool,
+}
+
+impl AsyncWrite for DiskWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.file).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl BlobWriter for DiskWriter {
+    async fn commit(mut self: Box<Self>, expected: Option<Digest>) -> Result<Digest, CasError> {
+        self.file.flush().await?;
+        self.file.get_mut().sync_all().await?;
+        let actual_digest = self.file.digest();
+        if let Some(expected_digest) = expected {
+            if actual_digest != expected_digest {
+                return Err(CasError::InvalidDigest(actual_digest, expected_digest));
+            }
+        }
+
+        let path = sharded_path(&self.root, &actual_digest)
+            .expect("computed digests are always valid hex");
+        if !fs::try_exists(&path).await? {
+            if let Some(shard) = path.parent() {
+                fs::create_dir_all(shard).await?;
+            }
+            fs::rename(&self.tmp_path, &path).await?;
+            self.committed = true;
+        }
+        Ok(actual_digest)
+    }
+}
+
+impl Drop for DiskWriter {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        let tmp_path = std::mem::take(&mut self.tmp_path);
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(move || std::fs::remove_file(tmp_path));
+            }
+            Err(_) => {
+                let _ = std::fs::remove_file(tmp_path);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl crate::ContentAddressableStorage for OnDisk {
+    async fn write_blob(
+        &self,
+        data: &[u8],
+        expected_digest: Option<Digest>,
+    ) -> Result<Digest, CasError> {
+        let actual_digest = crate::digest_of(data);
+        if let Some(expected_digest) = expected_digest {
+            if actual_digest != expected_digest {
+                return Err(CasError::InvalidDigest(actual_digest, expected_digest));
+            }
+        }
+
+        let path = sharded_path(&self.root, &actual_digest)
+            .expect("computed digests are always valid hex");
+        if !fs::try_exists(&path).await? {
+            write_atomic(&self.root, &path, data).await?;
+        }
+        Ok(actual_digest)
+    }
+
+    async fn read_blob(&self, digest: Digest) -> Result<Vec<u8>, CasError> {
+        let path = sharded_path(&self.root, &digest)
+            .ok_or_else(|| CasError::BlobNotFound(digest.clone()))?;
+        match fs::read(&path).await {
+            Ok(data) => Ok(data),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                Err(CasError::BlobNotFound(digest))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn has_blob(&self, digest: &Digest) -> Result<bool, CasError> {
+        let Some(path) = sharded_path(&self.root, digest) else {
+            return Ok(false);
+        };
+        match fs::metadata(&path).await {
+            Ok(metadata) => Ok(metadata.len() as i64 == digest.size_bytes()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn blob_writer(&self) -> Result<Box<dyn BlobWriter>, CasError> {
+        let tmp_path = self.root.join(TMP_DIR).join(Uuid::new_v4().to_string());
+        let file = fs::File::create(&tmp_path).await?;
+        Ok(Box::new(DiskWriter {
+            root: self.root.clone(),
+            tmp_path,
+            file: Hashing::new(file),
+            committed: false,
+        }))
+    }
+
+    async fn blob_reader(&self, digest: Digest, offset: u64) -> Result<BlobReader, CasError> {
+        let path = sharded_path(&self.root, &digest)
+            .ok_or_else(|| CasError::BlobNotFound(digest.clone()))?;
+        let mut file = match fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(CasError::BlobNotFound(digest))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok(Box::new(file))
+    }
+}
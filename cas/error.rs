@@ -11,4 +11,10 @@ pub enum CasError {
     IoError(#[from] std::io::Error),
     #[error("Digest {0} does not match expected {1}")]
     InvalidDigest(Digest, Digest),
+    #[error("Action digest {0} can not be stored")]
+    InvalidActionDigest(Digest),
+    #[error("Stored action result for {0} could not be decoded")]
+    CorruptActionResult(Digest),
+    #[error("Request to a remote content store failed: {0}")]
+    Remote(String),
 }
@@ -1,12 +1,20 @@
 use async_trait::async_trait;
 use common::Digest;
+use sha2::{Digest as _, Sha256};
 use std::path::Path;
+use std::str::FromStr;
 
+mod action_cache;
+mod disk;
 mod error;
 mod memory;
+mod stream;
 
+pub use action_cache::{ActionCache, InMemoryActionCache, OnDiskActionCache};
+pub use disk::OnDisk;
 pub use error::CasError;
 pub use memory::InMemory;
+pub use stream::{BlobReader, BlobWriter, Hashing};
 
 #[async_trait]
 pub trait ContentAddressableStorage: Clone + Send + Sync + 'static {
@@ -15,4 +23,30 @@ pub trait ContentAddressableStorage: Clone + Send + Sync + 'static {
     async fn read_blob(&self, digest: Digest) -> Result<Vec<u8>, CasError>;
 
     async fn has_blob(&self, digest: &Digest) -> Result<bool, CasError>;
+
+    /// Start writing a blob, to be stored once it is committed.
+    async fn blob_writer(&self) -> Result<Box<dyn BlobWriter>, CasError> {
+        Ok(Box::new(stream::Buffered::new(self.clone())))
+    }
+
+    /// Start reading a blob from `offset`.
+    async fn blob_reader(&self, digest: Digest, offset: u64) -> Result<BlobReader, CasError> {
+        let mut reader = std::io::Cursor::new(self.read_blob(digest).await?);
+        reader.set_position(offset);
+        Ok(Box::new(reader))
+    }
+}
+
+/// Compute the SHA-256 digest of a blob.
+pub fn digest_of(data: &[u8]) -> Digest {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    digest_from(hasher, data.len() as u64)
+}
+
+/// The digest of the `len` bytes fed to `hasher`.
+fn digest_from(hasher: Sha256, len: u64) -> Digest {
+    let hash_buf = hasher.finalize();
+    let hex_hash = base16ct::lower::encode_string(&hash_buf);
+    Digest::from_str(&format!("{}:{}", hex_hash, len)).expect("oh no")
 }
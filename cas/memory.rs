@@ -1,17 +1,67 @@
 use crate::error::CasError;
+use crate::stream::Hashing;
 use crate::ContentAddressableStorage;
+use crate::{BlobReader, BlobWriter};
 use async_trait::async_trait;
 use common::Digest;
-use sha2::{Digest as _, Sha256};
 use std::collections::HashMap;
+use std::io;
 use std::path::Path;
-use std::str::FromStr;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::AsyncWrite;
 use tokio::{fs::File, io::AsyncReadExt, sync::Mutex};
 
 #[derive(Default, Debug, Clone)]
 pub struct InMemory {
-    cas: Arc<Mutex<HashMap<Digest, Vec<u8>>>>,
+    cas: Arc<Mutex<HashMap<Digest, Arc<[u8]>>>>,
+}
+
+impl InMemory {
+    async fn insert(&self, digest: Digest, data: Arc<[u8]>) {
+        let mut cas = self.cas.lock().await;
+        cas.insert(digest, data);
+    }
+}
+
+/// A blob hashed as it is collected, to be stored once it is committed.
+struct MemoryWriter {
+    cas: InMemory,
+    data: Hashing<Vec<u8>>,
+}
+
+impl AsyncWrite for MemoryWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.data).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.data).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.data).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl BlobWriter for MemoryWriter {
+    async fn commit(self: Box<Self>, expected: Option<Digest>) -> Result<Digest, CasError> {
+        let actual_digest = self.data.digest();
+        if let Some(expected_digest) = expected {
+            if actual_digest != expected_digest {
+                return Err(CasError::InvalidDigest(actual_digest, expected_digest));
+            }
+        }
+        let data = self.data.into_inner();
+        self.cas.insert(actual_digest.clone(), data.into()).await;
+        Ok(actual_digest)
+    }
 }
 
 #[async_trait]
@@ -21,20 +71,14 @@ impl crate::ContentAddressableStorage for InMemory {
         data: &[u8],
         expected_digest: Option<Digest>,
     ) -> Result<Digest, CasError> {
-        let mut hasher = Sha256::new();
-        hasher.update(&data);
-        let hash_buf = hasher.finalize();
-        let hex_hash = base16ct::lower::encode_string(&hash_buf);
-        let actual_digest =
-            Digest::from_str(&format!("{}:{}", hex_hash, data.len())).expect("oh no");
+        let actual_digest = crate::digest_of(data);
         if let Some(expected_digest) = expected_digest {
             if actual_digest != expected_digest {
                 return Err(CasError::InvalidDigest(actual_digest, expected_digest));
             }
         }
 
-        let mut cas = self.cas.lock().await;
-        cas.insert(actual_digest.clone(), data.to_vec());
+        self.insert(actual_digest.clone(), data.into()).await;
         Ok(actual_digest)
     }
 
@@ -51,4 +95,22 @@ impl crate::ContentAddressableStorage for InMemory {
         let r = cas.contains_key(digest);
         Ok(r)
     }
+
+    async fn blob_writer(&self) -> Result<Box<dyn BlobWriter>, CasError> {
+        Ok(Box::new(MemoryWriter {
+            cas: self.clone(),
+            data: Hashing::new(vec![]),
+        }))
+    }
+
+    /// Blobs are shared with their readers rather than copied.
+    async fn blob_reader(&self, digest: Digest, offset: u64) -> Result<BlobReader, CasError> {
+        let cas = self.cas.lock().await;
+        let data = cas
+            .get(&digest)
+            .ok_or_else(|| CasError::BlobNotFound(digest.clone()))?;
+        let mut reader = io::Cursor::new(data.clone());
+        reader.set_position(offset);
+        Ok(Box::new(reader))
+    }
 }
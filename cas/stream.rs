@@ -0,0 +1,117 @@
+//! Streaming access to blobs, so they don't have to be held in memory whole.
+
+use crate::{CasError, ContentAddressableStorage};
+use async_trait::async_trait;
+use common::Digest;
+use sha2::{Digest as _, Sha256};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A blob being read out of a `ContentAddressableStorage`.
+pub type BlobReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// A blob being written into a `ContentAddressableStorage`.
+///
+/// Nothing is stored until the blob is committed, dropping the writer abandons
+/// the blob.
+#[async_trait]
+pub trait BlobWriter: AsyncWrite + Send + Unpin {
+    /// Store what was written, after checking it has the `expected` digest.
+    async fn commit(self: Box<Self>, expected: Option<Digest>) -> Result<Digest, CasError>;
+}
+
+/// Hashes everything written through it to `inner`.
+#[derive(Debug)]
+pub struct Hashing<W> {
+    inner: W,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<W> Hashing<W> {
+    pub fn new(inner: W) -> Self {
+        Hashing {
+            inner,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// The digest of what was written so far.
+    pub fn digest(&self) -> Digest {
+        crate::digest_from(self.hasher.clone(), self.len)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Hashing<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.hasher.update(&buf[..written]);
+        self.len += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Collects a blob in memory to store with `write_blob`, for stores that can't
+/// stream writes.
+pub(crate) struct Buffered<C> {
+    cas: C,
+    data: Vec<u8>,
+}
+
+impl<C> Buffered<C> {
+    pub(crate) fn new(cas: C) -> Self {
+        Buffered { cas, data: vec![] }
+    }
+}
+
+// The store is never pinned, only written data is touched through a pin.
+impl<C> Unpin for Buffered<C> {}
+
+impl<C> AsyncWrite for Buffered<C> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.data.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait]
+impl<C: ContentAddressableStorage> BlobWriter for Buffered<C> {
+    async fn commit(self: Box<Self>, expected: Option<Digest>) -> Result<Digest, CasError> {
+        self.cas.write_blob(&self.data, expected).await
+    }
+}
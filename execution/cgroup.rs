@@ -0,0 +1,249 @@
+//! Limits on, and accounting of, what actions use, by running each in a cgroup
+//! v2 leaf of its own.
+
+use crate::local::cstring;
+use crate::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::str::FromStr;
+use tracing::{event, Level};
+
+/// Platform properties overriding the limits configured for a single action.
+pub const MEMORY_LIMIT_PROPERTY: &str = "memory_limit_bytes";
+pub const CPU_LIMIT_PROPERTY: &str = "cpu_limit";
+pub const PIDS_LIMIT_PROPERTY: &str = "pids_limit";
+
+/// Controllers enabled for the cgroups actions run in, where available.
+const CONTROLLERS: [&str; 4] = ["cpu", "io", "memory", "pids"];
+
+/// Period, in microseconds, CPU limits are enforced over.
+const CPU_PERIOD: u64 = 100_000;
+
+/// Where actions' cgroups go, and what they're allowed, set from the
+/// `[execution.cgroup]` table of the node config.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CgroupConfig {
+    /// Cgroup each action gets a leaf in, such as `/sys/fs/cgroup/oryx`. It must
+    /// be delegated to whoever runs the node, and hold no processes of its own.
+    /// Actions aren't limited or accounted for without one.
+    pub parent: Option<PathBuf>,
+    /// Most memory an action may use, in bytes, before it is killed.
+    pub memory_limit_bytes: Option<u64>,
+    /// CPUs an action may keep busy, such as 1.5.
+    pub cpu_limit: Option<f64>,
+    /// Most processes and threads an action may have at once.
+    pub pids_limit: Option<u64>,
+}
+
+/// What an action used while running.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResourceUsage {
+    /// Most memory used at once, in bytes, if the kernel tracks it.
+    pub peak_memory_bytes: Option<u64>,
+    pub user_cpu_time: Duration,
+    pub system_cpu_time: Duration,
+    /// Bytes read from and written to block devices.
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+}
+
+/// The cgroup a single action runs in, removed when dropped.
+pub(crate) struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Create the cgroup for the execution `id`, with the limits `platform`
+    /// asks for or else those configured, if cgroups are configured at all.
+    pub(crate) fn create(
+        config: &CgroupConfig,
+        id: Uuid,
+        platform: &Platform,
+    ) -> Result<Option<Self>, ExecuteError> {
+        let Some(parent) = &config.parent else {
+            return Ok(None);
+        };
+        let memory = limit(platform, MEMORY_LIMIT_PROPERTY, config.memory_limit_bytes)?;
+        let cpu = limit(platform, CPU_LIMIT_PROPERTY, config.cpu_limit)?;
+        let pids = limit(platform, PIDS_LIMIT_PROPERTY, config.pids_limit)?;
+        if matches!(cpu, Some(cpu) if cpu <= 0.0) {
+            return Err(ExecuteError::InvalidArgument(String::from(
+                "The CPU limit must be positive",
+            )));
+        }
+
+        enable_controllers(parent)?;
+        std::fs::create_dir(parent.join(id.to_string()))?;
+        let cgroup = Cgroup {
+            path: parent.join(id.to_string()),
+        };
+        if let Some(memory) = memory {
+            cgroup.write("memory.max", memory)?;
+            // Kill the whole action when it runs out, rather than leave it
+            // running with some of its processes gone.
+            cgroup.write("memory.oom.group", 1)?;
+        }
+        if let Some(cpu) = cpu {
+            let quota = (cpu * CPU_PERIOD as f64) as u64;
+            cgroup.write("cpu.max", format!("{quota} {CPU_PERIOD}"))?;
+        }
+        if let Some(pids) = pids {
+            cgroup.write("pids.max", pids)?;
+        }
+        Ok(Some(cgroup))
+    }
+
+    /// The file a process writes itself to, to join the cgroup.
+    pub(crate) fn procs(&self) -> io::Result<CString> {
+        cstring(self.path.join("cgroup.procs"))
+    }
+
+    /// What the processes that ran in the cgroup used, as far as the kernel
+    /// tracks it.
+    pub(crate) fn usage(&self) -> ResourceUsage {
+        let cpu = self.read_stats("cpu.stat");
+        let usec = |name| Duration::from_micros(cpu.get(name).copied().unwrap_or_default());
+        let mut usage = ResourceUsage {
+            peak_memory_bytes: self
+                .read("memory.peak")
+                .and_then(|peak| peak.trim().parse().ok()),
+            user_cpu_time: usec("user_usec"),
+            system_cpu_time: usec("system_usec"),
+            ..Default::default()
+        };
+        // A line for each device, e.g. "8:0 rbytes=90112 wbytes=0 rios=3 ...".
+        for line in self.read("io.stat").unwrap_or_default().lines() {
+            for (name, value) in line.split_whitespace().filter_map(|s| s.split_once('=')) {
+                let value: u64 = value.parse().unwrap_or_default();
+                match name {
+                    "rbytes" => usage.read_bytes += value,
+                    "wbytes" => usage.write_bytes += value,
+                    _ => {}
+                }
+            }
+        }
+        usage
+    }
+
+    /// Whether the kernel killed anything in the cgroup for running out of memory.
+    pub(crate) fn oom_killed(&self) -> bool {
+        self.read_stats("memory.events")
+            .get("oom_kill")
+            .is_some_and(|kills| *kills > 0)
+    }
+
+    fn read(&self, file: &str) -> Option<String> {
+        std::fs::read_to_string(self.path.join(file)).ok()
+    }
+
+    /// Read a file of "name value" lines.
+    fn read_stats(&self, file: &str) -> HashMap<String, u64> {
+        self.read(file)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .filter_map(|(name, value)| Some((name.to_string(), value.parse().ok()?)))
+            .collect()
+    }
+
+    fn write(&self, file: &str, value: impl ToString) -> io::Result<()> {
+        std::fs::write(self.path.join(file), value.to_string())
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // Anything the action left running keeps the cgroup from being removed.
+        let _ = self.write("cgroup.kill", 1);
+        // Killed processes take a moment to leave it, which is waited out off
+        // the runtime's worker threads.
+        let path = std::mem::take(&mut self.path);
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(move || remove(&path));
+            }
+            Err(_) => remove(&path),
+        }
+    }
+}
+
+/// Remove the cgroup at `path` once the processes killed in it are gone.
+fn remove(path: &Path) {
+    for _ in 0..100 {
+        match std::fs::remove_dir(path) {
+            Ok(()) => return,
+            Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {
+                std::thread::sleep(Duration::from_millis(10))
+            }
+            Err(err) => {
+                event!(Level::WARN, %err, ?path, "failed to remove cgroup");
+                return;
+            }
+        }
+    }
+    event!(Level::WARN, ?path, "cgroup still in use, leaving it behind");
+}
+
+/// The limit `platform` sets with `property`, or else `default`.
+fn limit<T: FromStr>(
+    platform: &Platform,
+    property: &str,
+    default: Option<T>,
+) -> Result<Option<T>, ExecuteError> {
+    match platform.get(property) {
+        Some(value) => value.parse().map(Some).map_err(|_| {
+            ExecuteError::InvalidArgument(format!(
+                "Invalid value for the {property} platform property: {value}"
+            ))
+        }),
+        None => Ok(default),
+    }
+}
+
+/// Make controllers available to the children of `parent`, which is only
+/// possible if it holds no processes.
+fn enable_controllers(parent: &Path) -> io::Result<()> {
+    let available = std::fs::read_to_string(parent.join("cgroup.controllers"))?;
+    let enabled = std::fs::read_to_string(parent.join("cgroup.subtree_control"))?;
+    let missing = CONTROLLERS
+        .into_iter()
+        .filter(|c| available.split_whitespace().any(|a| a == *c))
+        .filter(|c| !enabled.split_whitespace().any(|e| e == *c))
+        .map(|c| format!("+{c}"))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        std::fs::write(parent.join("cgroup.subtree_control"), missing.join(" "))?;
+    }
+    Ok(())
+}
+
+impl From<&ResourceUsage> for protos::execution::ResourceUsage {
+    fn from(usage: &ResourceUsage) -> Self {
+        protos::execution::ResourceUsage {
+            peak_memory_bytes: usage.peak_memory_bytes,
+            user_cpu_time: prost_types::Duration::try_from(usage.user_cpu_time).ok(),
+            system_cpu_time: prost_types::Duration::try_from(usage.system_cpu_time).ok(),
+            read_bytes: usage.read_bytes,
+            write_bytes: usage.write_bytes,
+        }
+    }
+}
+
+impl From<protos::execution::ResourceUsage> for ResourceUsage {
+    fn from(usage: protos::execution::ResourceUsage) -> Self {
+        let duration = |d: Option<prost_types::Duration>| {
+            d.and_then(|d| Duration::try_from(d).ok())
+                .unwrap_or_default()
+        };
+        ResourceUsage {
+            peak_memory_bytes: usage.peak_memory_bytes,
+            user_cpu_time: duration(usage.user_cpu_time),
+            system_cpu_time: duration(usage.system_cpu_time),
+            read_bytes: usage.read_bytes,
+            write_bytes: usage.write_bytes,
+        }
+    }
+}
@@ -1,15 +1,80 @@
 use async_trait::async_trait;
-use cas::ContentAddressableStorage;
+use cas::ActionCache;
 use common::Digest;
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::future::Future;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 use tokio::sync::mpsc;
-use tracing::{event, span, Instrument, Level};
+use tracing::{event, field, span, Instrument, Level};
 use uuid::Uuid;
 
-use crate::{Command, DirectoryLayout, ExecuteError, ExecuteResponse, ExecutionBackend};
+use crate::cgroup::CgroupConfig;
+use crate::logs::LiveLogs;
+use crate::sandbox::SandboxConfig;
+use crate::scheduler::Scheduler;
+use crate::{
+    Action, Command, Entry, ExecuteError, ExecuteResponse, ExecutionBackend, ExecutionMetadata,
+    OutputKind, Platform,
+};
+
+/// Tunables for the execution engine, set from the `[execution]` table of the
+/// node config.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct EngineConfig {
+    /// Seconds an action may run for when it doesn't ask for a timeout.
+    pub default_timeout_secs: u64,
+    /// Longest timeout, in seconds, an action may ask for.
+    pub max_timeout_secs: u64,
+    /// Most actions run at once, the rest wait in a queue for a slot to free up.
+    pub max_concurrent_actions: usize,
+    /// Most important priority an action may ask for, smaller numbers run first.
+    pub min_priority: i32,
+    /// Least important priority an action may ask for.
+    pub max_priority: i32,
+    /// Seconds an action waits in the queue for its priority to be raised by one.
+    pub priority_aging_secs: u64,
+    /// Properties of this machine, for backends that run actions on the node.
+    pub platform: Platform,
+    /// Properties of the workers expected to connect, for the remote backend.
+    /// Actions none of them, nor any connected worker, could run are rejected.
+    /// Without any, actions wait for a worker able to run them to connect.
+    pub worker_platforms: Vec<Platform>,
+    /// Secret workers must present to register with the remote backend, which
+    /// won't start without one as workers are trusted with actions and results.
+    pub worker_token: Option<String>,
+    /// What actions can see of this machine with the sandbox backend.
+    pub sandbox: SandboxConfig,
+    /// Limits on what actions run on this machine use.
+    pub cgroup: CgroupConfig,
+    /// Stdout and stderr longer than this are stored in the CAS and returned by
+    /// digest, keeping responses within gRPC message size limits.
+    pub max_inline_output_bytes: u64,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            default_timeout_secs: 60 * 60,
+            max_timeout_secs: 4 * 60 * 60,
+            max_concurrent_actions: std::thread::available_parallelism()
+                .map_or(1, |parallelism| parallelism.get()),
+            min_priority: -100,
+            max_priority: 100,
+            priority_aging_secs: 1,
+            platform: Platform::default(),
+            worker_platforms: vec![],
+            worker_token: None,
+            sandbox: SandboxConfig::default(),
+            cgroup: CgroupConfig::default(),
+            max_inline_output_bytes: 1024 * 1024,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct ExecuteStatus {
@@ -26,25 +91,77 @@ pub enum ExecuteStage {
     Error(ExecuteError),
 }
 
-pub struct ExecutionEngine<B> {
+impl EngineConfig {
+    /// How long an action may run for, given the timeout it asked for.
+    fn timeout(&self, requested: Option<Duration>) -> Result<Duration, ExecuteError> {
+        let max = Duration::from_secs(self.max_timeout_secs);
+        match requested {
+            Some(requested) if requested > max => Err(ExecuteError::InvalidArgument(format!(
+                "Timeout of {}s is longer than the maximum of {}s",
+                requested.as_secs(),
+                max.as_secs()
+            ))),
+            Some(requested) if !requested.is_zero() => Ok(requested),
+            _ => Ok(Duration::from_secs(self.default_timeout_secs).min(max)),
+        }
+    }
+
+    /// The priority an action is queued with, given the one it asked for.
+    fn priority(&self, requested: i32) -> i32 {
+        requested.clamp(self.min_priority, self.max_priority.max(self.min_priority))
+    }
+}
+
+pub struct ExecutionEngine<B, A> {
     backend: B,
+    action_cache: A,
+    config: EngineConfig,
+    scheduler: Scheduler,
+    /// Reported as the worker of actions whose backend doesn't name one.
+    worker: String,
+    /// Output of the commands running now.
+    logs: LiveLogs,
 }
 
-impl<B: ExecutionBackend> ExecutionEngine<B> {
-    pub fn new(backend: B) -> Self {
-        ExecutionEngine { backend }
+impl<B: ExecutionBackend, A: ActionCache> ExecutionEngine<B, A> {
+    pub fn new(backend: B, action_cache: A, config: EngineConfig, logs: LiveLogs) -> Self {
+        let scheduler = Scheduler::new(
+            config.max_concurrent_actions,
+            Duration::from_secs(config.priority_aging_secs),
+        );
+        ExecutionEngine {
+            backend,
+            action_cache,
+            config,
+            scheduler,
+            worker: hostname(),
+            logs,
+        }
+    }
+
+    /// Look up the result of a previous execution of an action.
+    pub async fn cached_result(
+        &self,
+        action_digest: &Digest,
+    ) -> Result<Option<protos::re::ActionResult>, ExecuteError> {
+        Ok(self.action_cache.get_action_result(action_digest).await?)
     }
 
     pub fn execute<Exec>(
         &self,
         setup_func: impl Fn() -> Exec + Send + Sync + 'static,
-    ) -> Result<mpsc::Receiver<ExecuteStatus>, ExecuteError>
+    ) -> Result<(Uuid, mpsc::Receiver<ExecuteStatus>), ExecuteError>
     where
-        Exec: Future<Output = Result<(Digest, Command, DirectoryLayout), ExecuteError>> + Send,
+        Exec: Future<Output = Result<Action, ExecuteError>> + Send,
     {
         let span = span!(Level::TRACE, "execute backend");
         let (tx, rx) = mpsc::channel(32);
         let backend = self.backend.clone();
+        let action_cache = self.action_cache.clone();
+        let config = self.config.clone();
+        let scheduler = self.scheduler.clone();
+        let worker = self.worker.clone();
+        let logs = self.logs.clone();
         let uuid = Uuid::new_v4();
 
         //
@@ -53,9 +170,34 @@ impl<B: ExecutionBackend> ExecutionEngine<B> {
         tokio::spawn(
             async move {
                 let setup_span = span!(Level::TRACE, "setup function");
+                let action = setup_func()
+                    .instrument(setup_span)
+                    .await
+                    .and_then(|action| {
+                        if !backend.supports(&action.command.platform) {
+                            return Err(ExecuteError::UnsupportedPlatform(action.command.platform));
+                        }
+                        let timeout = config.timeout(action.timeout)?;
+                        Ok(Action {
+                            command: Command {
+                                timeout: Some(timeout),
+                                max_inline_output_bytes: Some(config.max_inline_output_bytes),
+                                ..action.command
+                            },
+                            ..action
+                        })
+                    });
                 // Run the actual command using the backend.
-                match setup_func().instrument(setup_span).await {
-                    Ok((action_digest, cmd, layout)) => {
+                match action {
+                    Ok(Action {
+                        digest: action_digest,
+                        command: cmd,
+                        layout,
+                        do_not_cache,
+                        priority,
+                        ..
+                    }) => {
+                        let queued = SystemTime::now();
                         tx.send(ExecuteStatus {
                             uuid: uuid,
                             action_digest: Some(action_digest.clone()),
@@ -63,19 +205,79 @@ impl<B: ExecutionBackend> ExecutionEngine<B> {
                         })
                         .await?;
 
-                        // TODO scheduling logic here
-                        tx.send(ExecuteStatus {
+                        // Wait for a slot to run in, unless nobody wants the result any more.
+                        let priority = config.priority(priority);
+                        let queue_span =
+                            span!(Level::TRACE, "queued", priority, queue_depth = field::Empty);
+                        let _slot = tokio::select! {
+                            slot = scheduler.acquire(priority).instrument(queue_span) => slot,
+                            _ = tx.closed() => return anyhow::Ok(()),
+                        };
+
+                        // Output can be followed from when the action is reported running.
+                        let cmd = Command {
+                            output: logs.start(uuid),
+                            ..cmd
+                        };
+                        let running = tx.send(ExecuteStatus {
                             uuid: uuid,
                             action_digest: Some(action_digest.clone()),
                             stage: ExecuteStage::Running,
-                        })
-                        .await?;
+                        });
+                        if running.await.is_err() {
+                            logs.finish(uuid);
+                            return anyhow::Ok(());
+                        }
 
                         // Run the actual command using the backend.
+                        let working_directory = cmd.working_directory.clone();
+                        let output_kinds = layout.output_kinds.clone();
                         let run_span = span!(Level::TRACE, "run command");
-                        match backend.run_command(cmd, layout).instrument(run_span).await {
+                        let run = backend.run_command(uuid, cmd, layout).instrument(run_span);
+                        tokio::pin!(run);
+                        let mut result = tokio::select! {
+                            biased;
+                            result = &mut run => result,
+                            // Nobody is left to receive the result, stop the command.
+                            _ = tx.closed() => {
+                                event!(Level::INFO, %uuid, "cancelling execution");
+                                if let Err(err) = backend.cancel(uuid).await {
+                                    event!(Level::WARN, %err, "failed to cancel execution");
+                                }
+                                let _ = run.await;
+                                logs.finish(uuid);
+                                return anyhow::Ok(());
+                            }
+                        };
+                        logs.finish(uuid);
+                        if let Ok(resp) = &result {
+                            if let Err(err) = check_output_kinds(&resp.output_paths, &output_kinds)
+                            {
+                                result = Err(err);
+                            }
+                        }
+                        let resp = match &mut result {
+                            Ok(resp) => Some(resp),
+                            Err(err) => err.response_mut(),
+                        };
+                        if let Some(resp) = resp {
+                            stamp(&mut resp.metadata, queued, &worker);
+                            relative_outputs(&mut resp.output_paths, &working_directory);
+                        }
+                        match result {
                             Ok(resp) => {
                                 event!(Level::TRACE, exit_status = resp.exit_status, "result");
+                                // Only successful results are cached, so a flaky failure
+                                // doesn't stick around for every later build.
+                                if resp.exit_status == 0 && !do_not_cache {
+                                    let result = protos::re::ActionResult::from(&resp);
+                                    if let Err(err) = action_cache
+                                        .update_action_result(action_digest.clone(), result)
+                                        .await
+                                    {
+                                        event!(Level::WARN, %err, "failed to cache action result");
+                                    }
+                                }
                                 tx.send(ExecuteStatus {
                                     uuid: uuid,
                                     action_digest: Some(action_digest.clone()),
@@ -84,6 +286,10 @@ impl<B: ExecutionBackend> ExecutionEngine<B> {
                                         output_paths: resp.output_paths,
                                         stderr: resp.stderr,
                                         stdout: resp.stdout,
+                                        stderr_digest: resp.stderr_digest,
+                                        stdout_digest: resp.stdout_digest,
+                                        usage: resp.usage,
+                                        metadata: resp.metadata,
                                     }),
                                 })
                                 .await?;
@@ -107,12 +313,65 @@ impl<B: ExecutionBackend> ExecutionEngine<B> {
                         .await?;
                     }
                 }
-                // TODO caching logic here
                 anyhow::Ok(())
             }
             .instrument(span),
         );
 
-        Ok(rx)
+        Ok((uuid, rx))
+    }
+}
+
+/// Fill in what the backend couldn't know about an action's execution: when it
+/// was queued, and, unless it ran elsewhere, that it ran on this machine.
+fn stamp(metadata: &mut ExecutionMetadata, queued: SystemTime, worker: &str) {
+    metadata.queued = Some(queued);
+    if metadata.worker.is_empty() {
+        metadata.worker = worker.to_string();
     }
 }
+
+/// Make sure the outputs at paths the command declared as files are files, and
+/// those it declared as directories are directories.
+fn check_output_kinds(
+    outputs: &[Entry],
+    kinds: &HashMap<PathBuf, OutputKind>,
+) -> Result<(), ExecuteError> {
+    for output in outputs {
+        let (path, kind) = match output {
+            Entry::File { path, .. } => (path, Some(OutputKind::File)),
+            Entry::Directory { path, .. } => (path, Some(OutputKind::Directory)),
+            // Symlinks stand in for what they point to.
+            Entry::Symlink {
+                link, points_to, ..
+            } => (link, *points_to),
+        };
+        if matches!(kinds.get(path), Some(expected) if Some(*expected) != kind) {
+            return Err(ExecuteError::UnexpectedOutputKind(path.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Make the paths of outputs, which backends collect relative to the input root,
+/// relative to the working directory they were requested relative to.
+fn relative_outputs(outputs: &mut [Entry], working_directory: &Path) {
+    let relative = |path: &mut PathBuf| {
+        if let Ok(relative) = path.strip_prefix(working_directory) {
+            *path = relative.to_path_buf();
+        }
+    };
+    for output in outputs {
+        match output {
+            Entry::File { path, .. } | Entry::Directory { path, .. } => relative(path),
+            Entry::Symlink { link, .. } => relative(link),
+        }
+    }
+}
+
+/// Name of this machine, or nothing if it can't be told.
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_default()
+}
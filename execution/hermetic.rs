@@ -1,74 +1,465 @@
+use crate::cgroup::{Cgroup, CgroupConfig};
+use crate::local::*;
 use crate::*;
-use cas::ContentAddressableStorage;
-use futures::future::{BoxFuture, FutureExt};
-use prost::Message;
-use tokio::io::AsyncReadExt;
-use tokio::{fs::File, io::AsyncWriteExt, process};
-
+use cas::{CasError, ContentAddressableStorage};
 use fuser::{
-    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
-    Request,
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request, TimeOrNow,
 };
-use libc::*;
-use std::env;
-use std::time::{Duration, UNIX_EPOCH};
-
-const TTL: Duration = Duration::from_secs(1); // 1 second
-
-const HELLO_DIR_ATTR: FileAttr = FileAttr {
-    ino: 1,
-    size: 0,
-    blocks: 0,
-    atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-    mtime: UNIX_EPOCH,
-    ctime: UNIX_EPOCH,
-    crtime: UNIX_EPOCH,
-    kind: FileType::Directory,
-    perm: 0o755,
-    nlink: 2,
-    uid: 501,
-    gid: 20,
-    rdev: 0,
-    flags: 0,
-    blksize: 512,
+use libc::{
+    c_int, EEXIST, EINVAL, EIO, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY, O_ACCMODE, O_RDONLY, O_TRUNC,
 };
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs::OpenOptions;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path};
+use std::time::SystemTime;
+use tempdir::TempDir;
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+use tracing::{event, span, Instrument, Level};
 
-const HELLO_TXT_CONTENT: &str = "Hello World!\n";
-
-const HELLO_TXT_ATTR: FileAttr = FileAttr {
-    ino: 2,
-    size: 13,
-    blocks: 1,
-    atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-    mtime: UNIX_EPOCH,
-    ctime: UNIX_EPOCH,
-    crtime: UNIX_EPOCH,
-    kind: FileType::RegularFile,
-    perm: 0o644,
-    nlink: 1,
-    uid: 501,
-    gid: 20,
-    rdev: 0,
-    flags: 0,
-    blksize: 512,
-};
+/// How long the kernel may cache what the filesystem tells it. Only the command
+/// changes the filesystem while it is mounted, and it does so through the kernel.
+const TTL: Duration = Duration::from_secs(1);
+
+const ROOT_INO: u64 = 1;
+
+/// Where the contents of a file are.
+enum Contents {
+    /// In the CAS, not yet fetched.
+    Blob(Digest),
+    /// In the overlay, either fetched from the CAS or written by the command.
+    Overlay(PathBuf),
+}
+
+enum Node {
+    Directory(BTreeMap<OsString, u64>),
+    File(Contents),
+    Symlink(PathBuf),
+}
+
+struct Inode {
+    parent: u64,
+    node: Node,
+    mtime: SystemTime,
+    /// Permission bits.
+    perm: u16,
+}
+
+/// The input root of an action.
+///
+/// Files are fetched from the CAS the first time they are opened, and anything
+/// the command writes is kept in an overlay directory on disk, so inputs that
+/// are never used are never copied.
+struct ActionFs<C> {
+    cas: C,
+    runtime: Handle,
+    overlay: PathBuf,
+    inodes: HashMap<u64, Inode>,
+    next_ino: u64,
+    uid: u32,
+    gid: u32,
+}
+
+impl<C: ContentAddressableStorage> ActionFs<C> {
+    fn new(cas: C, runtime: Handle, overlay: PathBuf) -> Self {
+        let root = Inode {
+            parent: ROOT_INO,
+            node: Node::Directory(BTreeMap::new()),
+            mtime: SystemTime::now(),
+            perm: 0o755,
+        };
+        ActionFs {
+            cas,
+            runtime,
+            overlay,
+            inodes: HashMap::from([(ROOT_INO, root)]),
+            next_ino: ROOT_INO + 1,
+            // SAFETY: neither call can fail or has any preconditions.
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        }
+    }
+
+    fn children(&self, ino: u64) -> Result<&BTreeMap<OsString, u64>, c_int> {
+        match self.inodes.get(&ino).map(|inode| &inode.node) {
+            Some(Node::Directory(children)) => Ok(children),
+            Some(_) => Err(ENOTDIR),
+            None => Err(ENOENT),
+        }
+    }
+
+    fn children_mut(&mut self, ino: u64) -> Result<&mut BTreeMap<OsString, u64>, c_int> {
+        match self.inodes.get_mut(&ino).map(|inode| &mut inode.node) {
+            Some(Node::Directory(children)) => Ok(children),
+            Some(_) => Err(ENOTDIR),
+            None => Err(ENOENT),
+        }
+    }
+
+    fn child(&self, parent: u64, name: &OsStr) -> Result<u64, c_int> {
+        self.children(parent)?.get(name).copied().ok_or(ENOENT)
+    }
+
+    /// Add a node named `name` with permissions `perm` to the directory `parent`,
+    /// which must not have anything by that name already.
+    fn insert(&mut self, parent: u64, name: &OsStr, node: Node, perm: u16) -> Result<u64, c_int> {
+        let ino = self.next_ino;
+        let children = self.children_mut(parent)?;
+        if children.contains_key(name) {
+            return Err(EEXIST);
+        }
+        children.insert(name.to_os_string(), ino);
+        self.next_ino += 1;
+        let inode = Inode {
+            parent,
+            node,
+            mtime: SystemTime::now(),
+            perm,
+        };
+        self.inodes.insert(ino, inode);
+        Ok(ino)
+    }
+
+    /// Create the directory at `path`, along with any missing parents.
+    fn make_dirs(&mut self, path: &Path) -> Result<u64, ExecuteError> {
+        // Nothing is created for paths that turn out to be invalid part way.
+        let names = path
+            .components()
+            .map(|component| match component {
+                Component::Normal(name) => Ok(name),
+                _ => Err(ExecuteError::InvalidArgument(format!(
+                    "Input path {} is not a plain relative path",
+                    path.display()
+                ))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut ino = ROOT_INO;
+        for name in names {
+            ino = match self.child(ino, name) {
+                Ok(child) => child,
+                Err(_) => self
+                    .insert(ino, name, Node::Directory(BTreeMap::new()), 0o755)
+                    .map_err(|_| {
+                        ExecuteError::InvalidArgument(format!(
+                            "Input path {} passes through a file",
+                            path.display()
+                        ))
+                    })?,
+            };
+        }
+        Ok(ino)
+    }
+
+    /// Add a node at `path`, creating the directories leading up to it.
+    fn add(&mut self, path: &Path, node: Node, perm: u16) -> Result<u64, ExecuteError> {
+        let invalid =
+            || ExecuteError::InvalidArgument(format!("Invalid input path {}", path.display()));
+        let name = path.file_name().ok_or_else(invalid)?;
+        let parent = self.make_dirs(path.parent().ok_or_else(invalid)?)?;
+        self.insert(parent, name, node, perm).map_err(|_| invalid())
+    }
+
+    /// Give the node `ino` the `properties` of an input.
+    fn set_properties(&mut self, ino: u64, properties: NodeProperties) {
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            if let Some(mtime) = properties.mtime {
+                inode.mtime = mtime;
+            }
+            if let Some(mode) = properties.unix_mode {
+                inode.perm = (mode & 0o7777) as u16;
+            }
+        }
+    }
+
+    /// Make sure the contents of a file are in the overlay, fetching them from
+    /// the CAS if need be, and return where they are.
+    fn materialize(&mut self, ino: u64) -> Result<PathBuf, c_int> {
+        let path = self.overlay.join(ino.to_string());
+        let contents = match self.inodes.get_mut(&ino).map(|inode| &mut inode.node) {
+            Some(Node::File(contents)) => contents,
+            Some(Node::Directory(_)) => return Err(EISDIR),
+            Some(Node::Symlink(_)) => return Err(EINVAL),
+            None => return Err(ENOENT),
+        };
+        if let Contents::Blob(digest) = contents {
+            let cas = &self.cas;
+            let fetch = async {
+                let mut blob = cas.blob_reader(digest.clone(), 0).await?;
+                let mut file = tokio::fs::File::create(&path).await?;
+                tokio::io::copy(&mut blob, &mut file).await?;
+                Ok::<(), CasError>(())
+            };
+            self.runtime.block_on(fetch).map_err(|err| {
+                event!(Level::WARN, %err, "failed to fetch input");
+                EIO
+            })?;
+            *contents = Contents::Overlay(path.clone());
+        }
+        Ok(path)
+    }
+
+    fn attr(&self, ino: u64) -> Result<FileAttr, c_int> {
+        let inode = self.inodes.get(&ino).ok_or(ENOENT)?;
+        let (kind, size, nlink) = match &inode.node {
+            Node::Directory(_) => (FileType::Directory, 0, 2),
+            Node::File(contents) => {
+                let size = match contents {
+                    Contents::Blob(digest) => digest.size_bytes() as u64,
+                    Contents::Overlay(path) => std::fs::metadata(path).map_err(|_| EIO)?.len(),
+                };
+                (FileType::RegularFile, size, 1)
+            }
+            Node::Symlink(target) => {
+                let size = target.as_os_str().len() as u64;
+                (FileType::Symlink, size, 1)
+            }
+        };
+        Ok(FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: inode.mtime,
+            mtime: inode.mtime,
+            ctime: inode.mtime,
+            crtime: inode.mtime,
+            kind,
+            perm: inode.perm,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+            blksize: 512,
+        })
+    }
+
+    fn touch(&mut self, ino: u64) {
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.mtime = SystemTime::now();
+        }
+    }
+
+    fn is_dir(&self, ino: u64) -> bool {
+        self.children(ino).is_ok()
+    }
 
-struct HermeticFS;
+    /// Take the node named `name` out of the directory `parent`.
+    ///
+    /// The inode itself is kept, so a command can keep using files it has open.
+    fn unlink_node(&mut self, parent: u64, name: &OsStr, dir: bool) -> Result<(), c_int> {
+        let ino = self.child(parent, name)?;
+        match (dir, self.children(ino)) {
+            (true, Ok(children)) if !children.is_empty() => return Err(ENOTEMPTY),
+            (true, Err(_)) => return Err(ENOTDIR),
+            (false, Ok(_)) => return Err(EISDIR),
+            _ => {}
+        }
+        self.children_mut(parent)?.remove(name);
+        self.touch(parent);
+        Ok(())
+    }
 
-impl Filesystem for HermeticFS {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &std::ffi::OsStr, reply: ReplyEntry) {
-        if parent == 1 && name.to_str() == Some("hello.txt") {
-            reply.entry(&TTL, &HELLO_TXT_ATTR, 0);
-        } else {
-            reply.error(ENOENT);
+    fn rename_node(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> Result<(), c_int> {
+        if flags & !libc::RENAME_NOREPLACE != 0 {
+            return Err(EINVAL);
+        }
+        let ino = self.child(parent, name)?;
+        if let Ok(existing) = self.child(new_parent, new_name) {
+            if existing == ino {
+                return Ok(());
+            }
+            if flags & libc::RENAME_NOREPLACE != 0 {
+                return Err(EEXIST);
+            }
+            self.unlink_node(new_parent, new_name, self.is_dir(ino))?;
+        }
+        self.children_mut(new_parent)?
+            .insert(new_name.to_os_string(), ino);
+        self.children_mut(parent)?.remove(name);
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.parent = new_parent;
+        }
+        self.touch(parent);
+        self.touch(new_parent);
+        Ok(())
+    }
+}
+
+/// Read up to `size` bytes at `offset`, fewer only at the end of the file.
+fn read_at(path: &Path, offset: u64, size: usize) -> std::io::Result<Vec<u8>> {
+    let file = std::fs::File::open(path)?;
+    let mut buf = vec![0; size];
+    let mut filled = 0;
+    while filled < size {
+        let read = file.read_at(&mut buf[filled..], offset + filled as u64)?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    buf.truncate(filled);
+    Ok(buf)
+}
+
+impl<C: ContentAddressableStorage> Filesystem for ActionFs<C> {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.child(parent, name).and_then(|ino| self.attr(ino)) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(err),
         }
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        match ino {
-            1 => reply.attr(&TTL, &HELLO_DIR_ATTR),
-            2 => reply.attr(&TTL, &HELLO_TXT_ATTR),
-            _ => reply.error(ENOENT),
+        match self.attr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let result = (|| {
+            if let Some(size) = size {
+                let path = self.materialize(ino)?;
+                let file = OpenOptions::new().write(true).open(path).map_err(|_| EIO)?;
+                file.set_len(size).map_err(|_| EIO)?;
+                self.touch(ino);
+            }
+            let inode = self.inodes.get_mut(&ino).ok_or(ENOENT)?;
+            if let Some(mode) = mode {
+                inode.perm = (mode & 0o7777) as u16;
+            }
+            match mtime {
+                Some(TimeOrNow::SpecificTime(time)) => inode.mtime = time,
+                Some(TimeOrNow::Now) => inode.mtime = SystemTime::now(),
+                None => {}
+            }
+            self.attr(ino)
+        })();
+        match result {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        match self.inodes.get(&ino).map(|inode| &inode.node) {
+            Some(Node::Symlink(target)) => reply.data(target.as_os_str().as_bytes()),
+            Some(_) => reply.error(EINVAL),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let perm = (mode & !umask & 0o7777) as u16;
+        let result = self
+            .insert(parent, name, Node::Directory(BTreeMap::new()), perm)
+            .and_then(|ino| self.attr(ino));
+        match result {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.unlink_node(parent, name, false) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.unlink_node(parent, name, true) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        let result = self
+            .insert(parent, name, Node::Symlink(link.to_path_buf()), 0o777)
+            .and_then(|ino| self.attr(ino));
+        match result {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        match self.rename_node(parent, name, newparent, newname, flags) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        let result = (|| {
+            // Inputs are fetched on the first read, unless they are about to be
+            // written to.
+            if flags & O_ACCMODE != O_RDONLY || flags & O_TRUNC != 0 {
+                let path = self.materialize(ino)?;
+                if flags & O_TRUNC != 0 {
+                    let file = OpenOptions::new().write(true).open(path).map_err(|_| EIO)?;
+                    file.set_len(0).map_err(|_| EIO)?;
+                    self.touch(ino);
+                }
+            }
+            Ok(())
+        })();
+        match result {
+            Ok(()) => reply.opened(0, 0),
+            Err(err) => reply.error(err),
         }
     }
 
@@ -78,36 +469,78 @@ impl Filesystem for HermeticFS {
         ino: u64,
         _fh: u64,
         offset: i64,
-        _size: u32,
+        size: u32,
         _flags: i32,
-        _lock: Option<u64>,
+        _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
-        if ino == 2 {
-            reply.data(&HELLO_TXT_CONTENT.as_bytes()[offset as usize..]);
-        } else {
-            reply.error(ENOENT);
+        let result = self
+            .materialize(ino)
+            .and_then(|path| read_at(&path, offset as u64, size as usize).map_err(|_| EIO));
+        match result {
+            Ok(data) => reply.data(&data),
+            Err(err) => reply.error(err),
         }
     }
 
-    fn readdir(
+    fn write(
         &mut self,
         _req: &Request,
         ino: u64,
         _fh: u64,
         offset: i64,
-        mut reply: ReplyDirectory,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
     ) {
-        if ino != 1 {
-            reply.error(ENOENT);
-            return;
+        let result = self.materialize(ino).and_then(|path| {
+            let file = OpenOptions::new().write(true).open(path).map_err(|_| EIO)?;
+            file.write_all_at(data, offset as u64).map_err(|_| EIO)
+        });
+        match result {
+            Ok(()) => {
+                self.touch(ino);
+                reply.written(data.len() as u32)
+            }
+            Err(err) => reply.error(err),
         }
+    }
 
-        let entries = vec![
-            (1, FileType::Directory, "."),
-            (1, FileType::Directory, ".."),
-            (2, FileType::RegularFile, "hello.txt"),
+    fn flush(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        reply.ok();
+    }
+
+    fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let children = match self.children(ino) {
+            Ok(children) => children,
+            Err(err) => return reply.error(err),
+        };
+        let parent = self.inodes[&ino].parent;
+        let mut entries = vec![
+            (ino, FileType::Directory, OsString::from(".")),
+            (parent, FileType::Directory, OsString::from("..")),
         ];
+        for (name, &child) in children {
+            let kind = match self.inodes[&child].node {
+                Node::Directory(_) => FileType::Directory,
+                Node::File(_) => FileType::RegularFile,
+                Node::Symlink(_) => FileType::Symlink,
+            };
+            entries.push((child, kind, name.clone()));
+        }
 
         for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
             // i + 1 means the index of the next entry
@@ -117,23 +550,176 @@ impl Filesystem for HermeticFS {
         }
         reply.ok();
     }
+
+    fn access(&mut self, _req: &Request, ino: u64, _mask: i32, reply: ReplyEmpty) {
+        match self.inodes.contains_key(&ino) {
+            true => reply.ok(),
+            false => reply.error(ENOENT),
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let path = self.overlay.join(self.next_ino.to_string());
+        let perm = (mode & !umask & 0o7777) as u16;
+        let result = std::fs::File::create(&path)
+            .map_err(|_| EIO)
+            .and_then(|_| self.insert(parent, name, Node::File(Contents::Overlay(path)), perm))
+            .and_then(|ino| self.attr(ino));
+        match result {
+            Ok(attr) => reply.created(&TTL, &attr, 0, 0, 0),
+            Err(err) => reply.error(err),
+        }
+    }
 }
 
+/// Runs commands in a FUSE filesystem mounted for each action, which serves the
+/// input root straight from the CAS.
 #[derive(Debug, Clone)]
 pub struct Hermetic<C> {
     cas: C,
+    /// Properties of the machine commands run on.
+    platform: Platform,
+    cgroups: CgroupConfig,
+    cancellations: Cancellations,
 }
 
 impl<C: ContentAddressableStorage> Hermetic<C> {
-    pub fn new(cas: C) -> Result<Self, ExecuteError> {
-        let mountpoint = "/tmp/mnt";
-        fuser::mount2(
-            HermeticFS,
-            mountpoint,
-            &[MountOption::NoDev, MountOption::AutoUnmount],
-        )
-        .unwrap();
-        Ok(Hermetic { cas })
+    pub fn new(cas: C, platform: Platform, cgroups: CgroupConfig) -> Result<Self, ExecuteError> {
+        Ok(Hermetic {
+            cas,
+            platform,
+            cgroups,
+            cancellations: Cancellations::default(),
+        })
+    }
+
+    async fn run(
+        &self,
+        id: Uuid,
+        command: Command,
+        dir: DirectoryLayout,
+        cancelled: oneshot::Receiver<()>,
+    ) -> Result<ExecuteResponse, ExecuteError> {
+        let span = span!(Level::TRACE, "hermetic");
+        let mut metadata = ExecutionMetadata {
+            worker_start: Some(SystemTime::now()),
+            ..Default::default()
+        };
+
+        // The filesystem is mounted on `root`, with what the command writes kept
+        // in `overlay`. Both go away with the temporary directory.
+        let tmp_dir = TempDir::new("oryx-hermetic")?;
+        let root_path = tmp_dir.path().join("root");
+        let overlay = tmp_dir.path().join("overlay");
+        std::fs::create_dir(&root_path)?;
+        std::fs::create_dir(&overlay)?;
+
+        // Inputs are only fetched as the command reads them, so fetching them
+        // amounts to mounting the filesystem.
+        let setup_span = span!(parent: &span, Level::TRACE, "setup");
+        metadata.input_fetch_start = Some(SystemTime::now());
+        let session = setup_span.in_scope(|| {
+            let mut fs = ActionFs::new(self.cas.clone(), Handle::current(), overlay);
+            for entry in dir.entries {
+                match entry {
+                    Entry::Symlink { original, link, .. } => {
+                        fs.add(&link, Node::Symlink(original), 0o777)?;
+                    }
+                    Entry::Directory {
+                        path, properties, ..
+                    } => {
+                        let ino = fs.make_dirs(&path)?;
+                        fs.set_properties(ino, properties);
+                    }
+                    Entry::File {
+                        path,
+                        digest,
+                        executable,
+                        properties,
+                    } => {
+                        let perm = if executable { 0o755 } else { 0o644 };
+                        let ino = fs.add(&path, Node::File(Contents::Blob(digest)), perm)?;
+                        fs.set_properties(ino, properties);
+                    }
+                }
+            }
+
+            // Directories leading up to the output paths are created by the worker prior
+            // to execution, even if they are not explicitly part of the input root.
+            for path in &dir.output_paths {
+                if let Some(prefix) = path.parent() {
+                    fs.make_dirs(prefix)?;
+                }
+            }
+
+            let options = [
+                MountOption::FSName(String::from("oryx")),
+                MountOption::NoDev,
+                MountOption::NoSuid,
+            ];
+            Ok::<_, ExecuteError>(fuser::spawn_mount2(fs, &root_path, &options)?)
+        })?;
+        metadata.input_fetch_completed = Some(SystemTime::now());
+
+        let cgroup = Cgroup::create(&self.cgroups, id, &command.platform);
+        let current_dir = root_path.join(&command.working_directory);
+        let finished = match cgroup {
+            Ok(cgroup) => {
+                run_process(
+                    &span,
+                    &self.cas,
+                    command,
+                    &current_dir,
+                    cgroup.as_ref(),
+                    |_| {},
+                    cancelled,
+                )
+                .await
+            }
+            Err(err) => Err(err),
+        };
+        let response = match finished {
+            Ok(finished) => {
+                let entries = match finished.exit {
+                    Exit::Exited(_) => {
+                        let finish_span = span!(parent: &span, Level::TRACE, "collect response");
+                        metadata.output_upload_start = Some(SystemTime::now());
+                        let entries = collect_outputs(
+                            &self.cas,
+                            &root_path,
+                            dir.output_paths,
+                            dir.output_properties,
+                        )
+                        .instrument(finish_span)
+                        .await;
+                        metadata.output_upload_completed = Some(SystemTime::now());
+                        entries
+                    }
+                    _ => Ok(vec![]),
+                };
+                entries.and_then(|entries| finished.into_result(entries, metadata))
+            }
+            Err(err) => Err(err),
+        };
+
+        // Nothing the command started is left to keep the filesystem busy:
+        // run_process kills its process group on the way out, and dropping the
+        // cgroup kills whatever escaped the group. Unmounting waits for the
+        // filesystem to finish serving requests, which may need this thread to
+        // fetch blobs.
+        if let Err(err) = tokio::task::spawn_blocking(move || session.join()).await {
+            event!(Level::WARN, %err, "failed to unmount action filesystem");
+        }
+        response
     }
 }
 
@@ -141,9 +727,145 @@ impl<C: ContentAddressableStorage> Hermetic<C> {
 impl<C: ContentAddressableStorage> ExecutionBackend for Hermetic<C> {
     async fn run_command(
         &self,
+        id: Uuid,
         command: Command,
         dir: DirectoryLayout,
     ) -> Result<ExecuteResponse, ExecuteError> {
-        todo!();
+        let cancelled = self.cancellations.start(id);
+        let result = self.run(id, command, dir, cancelled).await;
+        self.cancellations.finish(id);
+        result
+    }
+
+    async fn cancel(&self, id: Uuid) -> Result<(), ExecuteError> {
+        self.cancellations.cancel(id);
+        Ok(())
+    }
+
+    fn supports(&self, platform: &Platform) -> bool {
+        self.platform.satisfies(platform)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action_fs(overlay: &TempDir) -> ActionFs<cas::InMemory> {
+        let cas = cas::InMemory::default();
+        ActionFs::new(cas, Handle::current(), overlay.path().to_path_buf())
+    }
+
+    fn add_file(fs: &mut ActionFs<cas::InMemory>, path: &str, contents: &[u8]) -> u64 {
+        let node = Node::File(Contents::Blob(cas::digest_of(contents)));
+        fs.add(Path::new(path), node, 0o644).unwrap()
+    }
+
+    /// The inode at `path`, looked up a name at a time like the kernel does.
+    fn lookup(fs: &ActionFs<cas::InMemory>, path: &str) -> Result<u64, c_int> {
+        Path::new(path)
+            .iter()
+            .try_fold(ROOT_INO, |ino, name| fs.child(ino, name))
+    }
+
+    #[tokio::test]
+    async fn make_dirs_creates_missing_parents_once() {
+        let overlay = TempDir::new("oryx-hermetic-test").unwrap();
+        let mut fs = action_fs(&overlay);
+        let ino = fs.make_dirs(Path::new("a/b/c")).unwrap();
+        assert_eq!(lookup(&fs, "a/b/c"), Ok(ino));
+        assert_eq!(fs.make_dirs(Path::new("a/b/c")).unwrap(), ino);
+        assert_eq!(fs.inodes[&ino].parent, lookup(&fs, "a/b").unwrap());
+        assert!(fs.is_dir(lookup(&fs, "a").unwrap()));
+    }
+
+    #[tokio::test]
+    async fn make_dirs_rejects_paths_leaving_the_root() {
+        let overlay = TempDir::new("oryx-hermetic-test").unwrap();
+        let mut fs = action_fs(&overlay);
+        for path in ["../a", "/a", "a/../../b"] {
+            assert!(fs.make_dirs(Path::new(path)).is_err(), "{path}");
+        }
+        add_file(&mut fs, "a", b"etosha");
+        assert!(fs.make_dirs(Path::new("a/b")).is_err());
+    }
+
+    #[tokio::test]
+    async fn lookup_finds_inputs() {
+        let overlay = TempDir::new("oryx-hermetic-test").unwrap();
+        let mut fs = action_fs(&overlay);
+        let ino = add_file(&mut fs, "dir/in.txt", b"etosha");
+        assert_eq!(lookup(&fs, "dir/in.txt"), Ok(ino));
+        assert_eq!(lookup(&fs, "dir/missing.txt"), Err(ENOENT));
+        assert_eq!(lookup(&fs, "dir/in.txt/below"), Err(ENOTDIR));
+
+        let attr = fs.attr(ino).unwrap();
+        assert_eq!(attr.kind, FileType::RegularFile);
+        assert_eq!(attr.size, 6);
+        assert_eq!(attr.perm, 0o644);
+    }
+
+    #[tokio::test]
+    async fn unlink_node_checks_the_kind_of_node() {
+        let overlay = TempDir::new("oryx-hermetic-test").unwrap();
+        let mut fs = action_fs(&overlay);
+        let file = add_file(&mut fs, "dir/in.txt", b"etosha");
+        let dir = lookup(&fs, "dir").unwrap();
+
+        assert_eq!(
+            fs.unlink_node(ROOT_INO, OsStr::new("dir"), true),
+            Err(ENOTEMPTY)
+        );
+        assert_eq!(
+            fs.unlink_node(ROOT_INO, OsStr::new("dir"), false),
+            Err(EISDIR)
+        );
+        assert_eq!(
+            fs.unlink_node(dir, OsStr::new("in.txt"), true),
+            Err(ENOTDIR)
+        );
+        assert_eq!(
+            fs.unlink_node(dir, OsStr::new("missing"), false),
+            Err(ENOENT)
+        );
+
+        fs.unlink_node(dir, OsStr::new("in.txt"), false).unwrap();
+        assert_eq!(lookup(&fs, "dir/in.txt"), Err(ENOENT));
+        // Files still open can be used once unlinked.
+        assert!(fs.attr(file).is_ok());
+        fs.unlink_node(ROOT_INO, OsStr::new("dir"), true).unwrap();
+        assert_eq!(lookup(&fs, "dir"), Err(ENOENT));
+    }
+
+    #[tokio::test]
+    async fn rename_node_moves_and_replaces() {
+        let overlay = TempDir::new("oryx-hermetic-test").unwrap();
+        let mut fs = action_fs(&overlay);
+        let a = add_file(&mut fs, "a.txt", b"etosha");
+        let b = add_file(&mut fs, "b.txt", b"namib");
+        let dir = fs.make_dirs(Path::new("dir")).unwrap();
+
+        fs.rename_node(ROOT_INO, OsStr::new("a.txt"), dir, OsStr::new("c.txt"), 0)
+            .unwrap();
+        assert_eq!(lookup(&fs, "a.txt"), Err(ENOENT));
+        assert_eq!(lookup(&fs, "dir/c.txt"), Ok(a));
+        assert_eq!(fs.inodes[&a].parent, dir);
+
+        let noreplace = libc::RENAME_NOREPLACE;
+        let rename = fs.rename_node(
+            ROOT_INO,
+            OsStr::new("b.txt"),
+            dir,
+            OsStr::new("c.txt"),
+            noreplace,
+        );
+        assert_eq!(rename, Err(EEXIST));
+        fs.rename_node(ROOT_INO, OsStr::new("b.txt"), dir, OsStr::new("c.txt"), 0)
+            .unwrap();
+        assert_eq!(lookup(&fs, "dir/c.txt"), Ok(b));
+
+        // A file can't replace a directory.
+        let rename = fs.rename_node(dir, OsStr::new("c.txt"), ROOT_INO, OsStr::new("dir"), 0);
+        assert_eq!(rename, Err(EISDIR));
     }
 }
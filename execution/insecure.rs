@@ -1,229 +1,117 @@
+use crate::cgroup::{Cgroup, CgroupConfig};
+use crate::local::*;
 use crate::*;
 use cas::ContentAddressableStorage;
-use futures::future::{BoxFuture, FutureExt};
-use openat2::*;
-use prost::Message;
-use std::os::fd::RawFd;
-use std::os::unix::fs::PermissionsExt;
-use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use tempdir::TempDir;
-use tokio::io::AsyncReadExt;
-use tokio::{fs::File, io::AsyncWriteExt, process};
+use tokio::sync::oneshot;
 use tracing::{event, span, Instrument, Level};
 
 #[derive(Debug, Clone)]
 pub struct Insecure<C> {
     cas: C,
+    /// Properties of the machine commands run on.
+    platform: Platform,
+    cgroups: CgroupConfig,
+    cancellations: Cancellations,
 }
 
 impl<C: ContentAddressableStorage> Insecure<C> {
-    pub fn new(cas: C) -> Result<Self, ExecuteError> {
-        Ok(Insecure { cas })
-    }
-
-    async fn add_symlink(
-        &self,
-        root_path: &Path,
-        original: &Path,
-        link: &Path,
-    ) -> Result<Entry, ExecuteError> {
-        Ok(Entry::Symlink {
-            original: original.strip_prefix(&root_path).unwrap().to_path_buf(),
-            link: link.strip_prefix(&root_path).unwrap().to_path_buf(),
-        })
-    }
-
-    async fn add_file(&self, root_path: &Path, path: &Path) -> Result<Entry, ExecuteError> {
-        let mut file = tokio::fs::File::open(dbg!(&path)).await?;
-        let mut buf = vec![];
-        file.read_to_end(&mut buf).await?;
-        let digest = self.cas.write_blob(&buf, None).await?;
-        Ok(Entry::File {
-            path: path.strip_prefix(&root_path).unwrap().to_path_buf(),
-            digest,
-            executable: false,
-        })
-    }
-
-    fn add_dir<'a>(
-        &'a self,
-        root_path: &'a Path,
-        path: &'a Path,
-        children: &'a mut Vec<protos::re::Directory>,
-    ) -> BoxFuture<'a, Result<protos::re::Directory, ExecuteError>> {
-        Box::pin(async move {
-            let mut files = vec![];
-            let mut directories = vec![];
-
-            for entry in std::fs::read_dir(path)? {
-                let entry = entry?;
-                if entry.path().is_symlink() {
-                    todo!();
-                } else if entry.path().is_file() {
-                    let Entry::File{path, digest, executable} = self.add_file(root_path, &entry.path()).await? else { unreachable!() };
-                    files.push(protos::re::FileNode {
-                        name: entry.file_name().to_str().unwrap().to_string(),
-                        digest: Some(digest.into()),
-                        is_executable: executable,
-                        node_properties: None,
-                    })
-                } else if entry.path().is_dir() {
-                    let dir = self.add_dir(root_path, &entry.path(), children).await?;
-                    children.push(dir.clone());
-                    let proto_buf = dir.encode_to_vec();
-                    let digest = self.cas.write_blob(&proto_buf, None).await?;
-                    directories.push(protos::re::DirectoryNode {
-                        name: entry.file_name().to_str().unwrap().to_string(),
-                        digest: Some(digest.into()),
-                    })
-                } else {
-                    unreachable!();
-                }
-            }
-
-            Ok(protos::re::Directory {
-                files,
-                directories,
-                symlinks: vec![],
-                node_properties: None,
-            })
+    pub fn new(cas: C, platform: Platform, cgroups: CgroupConfig) -> Result<Self, ExecuteError> {
+        Ok(Insecure {
+            cas,
+            platform,
+            cgroups,
+            cancellations: Cancellations::default(),
         })
     }
 }
 
-fn get_root_relative(root_path: &Path, path: &Path) -> PathBuf {
-    let mut new_path: PathBuf = root_path.to_path_buf();
-    new_path.push(path);
-    new_path
-}
-
-#[async_trait]
-impl<C: ContentAddressableStorage> ExecutionBackend for Insecure<C> {
-    async fn run_command(
+impl<C: ContentAddressableStorage> Insecure<C> {
+    async fn run(
         &self,
+        id: Uuid,
         command: Command,
         dir: DirectoryLayout,
+        cancelled: oneshot::Receiver<()>,
     ) -> Result<ExecuteResponse, ExecuteError> {
         let span = span!(Level::TRACE, "insecure");
 
         let setup_span = span!(parent: &span, Level::TRACE, "setup");
+        let mut metadata = ExecutionMetadata {
+            worker_start: Some(SystemTime::now()),
+            ..Default::default()
+        };
 
         // Create a temporary directory and write all files from the cas there
         let tmp_dir = TempDir::new("oryx-insecure")?;
         let root_path = tmp_dir.path().to_path_buf();
-        // TODO Remove this!
-        // Only here so I can look in the insecure folders in /tmp during testing.
-        std::mem::forget(tmp_dir);
 
-        async {
-            for entry in dir.entries {
-                match entry {
-                    Entry::Symlink { original, link } => {
-                        let original = get_root_relative(&root_path, &original);
-                        if let Some(prefix) = original.parent() {
-                            std::fs::create_dir_all(prefix)?;
-                        }
-                        let link = get_root_relative(&root_path, &link);
-                        std::os::unix::fs::symlink(original, link)?;
-                    }
-                    Entry::Directory { path, .. } => {
-                        let path = get_root_relative(&root_path, &path);
-                        std::fs::create_dir_all(path)?;
-                    }
-                    Entry::File {
-                        digest,
-                        executable,
-                        path,
-                    } => {
-                        let path = dbg!(get_root_relative(&root_path, &path));
-                        if let Some(prefix) = path.parent() {
-                            std::fs::create_dir_all(prefix)?;
-                        }
-                        let mut file = File::create(&path).await?;
-                        let data = self.cas.read_blob(digest).await?;
-                        file.write_all(&data).await?;
-                        file.flush().await?;
-                        if executable {
-                            let metadata = file.metadata().await?;
-                            let mut permissions = metadata.permissions();
-                            permissions.set_mode(0o777);
-                            tokio::fs::set_permissions(&path, permissions).await?;
-                        }
-                    }
-                }
+        metadata.input_fetch_start = Some(SystemTime::now());
+        lay_out_inputs(&self.cas, &root_path, dir.entries, &dir.output_paths)
+            .instrument(setup_span)
+            .await?;
+        metadata.input_fetch_completed = Some(SystemTime::now());
+
+        let cgroup = Cgroup::create(&self.cgroups, id, &command.platform)?;
+        let current_dir = root_path.join(&command.working_directory);
+        let finished = run_process(
+            &span,
+            &self.cas,
+            command,
+            &current_dir,
+            cgroup.as_ref(),
+            |_| {},
+            cancelled,
+        )
+        .await?;
+        let entries = match finished.exit {
+            Exit::Exited(_) => {
+                let finish_span = span!(parent: &span, Level::TRACE, "collect response");
+                metadata.output_upload_start = Some(SystemTime::now());
+                let entries = collect_outputs(
+                    &self.cas,
+                    &root_path,
+                    dir.output_paths,
+                    dir.output_properties,
+                )
+                .instrument(finish_span)
+                .await?;
+                metadata.output_upload_completed = Some(SystemTime::now());
+                entries
             }
-
-            // Directories leading up to the output paths are created by the worker prior
-            // to execution, even if they are not explicitly part of the input root.
-            for path in &dir.output_paths {
-                let global_path = get_root_relative(&root_path, &path);
-                if let Some(prefix) = global_path.parent() {
-                    std::fs::create_dir_all(prefix)?;
-                }
+            _ => vec![],
+        };
+        // The outputs are in the CAS by now, so the directory can go.
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = tmp_dir.close() {
+                event!(Level::WARN, %err, "failed to clean up execution");
             }
-            Ok::<(), ExecuteError>(())
-        }
-        .instrument(setup_span)
-        .await;
+        });
+        finished.into_result(entries, metadata)
+    }
+}
 
-        let binary = &command.arguments[0];
-        let args = &command.arguments[1..];
-        let envs = command.env_vars;
-        let current_dir = root_path.clone();
-        let exec_span = span!(parent: &span, Level::TRACE, "execute",
-                binary=binary,
-                args=?args,
-                envs=?envs,
-                current_dir=?current_dir);
-        let output = process::Command::new(binary)
-            .current_dir(current_dir)
-            .args(args)
-            .envs(envs)
-            .output()
-            .instrument(exec_span)
-            .await?;
+#[async_trait]
+impl<C: ContentAddressableStorage> ExecutionBackend for Insecure<C> {
+    async fn run_command(
+        &self,
+        id: Uuid,
+        command: Command,
+        dir: DirectoryLayout,
+    ) -> Result<ExecuteResponse, ExecuteError> {
+        let cancelled = self.cancellations.start(id);
+        let result = self.run(id, command, dir, cancelled).await;
+        self.cancellations.finish(id);
+        result
+    }
 
-        let finish_span = span!(parent: &span, Level::TRACE, "collect response");
-        let entries = async {
-            // Verify outputs were created and get their hash
-            let mut entries = vec![];
-            for path in dir.output_paths {
-                let global_path = get_root_relative(&root_path, &path);
-                let mut children = vec![];
-                if global_path.is_symlink() {
-                    let symlink_path = global_path.read_link().expect("read_link call failed");
-                    let symlink_path = get_root_relative(&root_path, &symlink_path);
-                    entries.push(
-                        self.add_symlink(&root_path, &global_path, &symlink_path)
-                            .await?,
-                    );
-                } else if global_path.is_dir() {
-                    let root = self
-                        .add_dir(&root_path, &global_path, &mut children)
-                        .await?;
-                    let tree = protos::re::Tree {
-                        root: Some(root),
-                        children,
-                    };
-                    let proto_buf = tree.encode_to_vec();
-                    let digest = self.cas.write_blob(&proto_buf, None).await?;
-                    entries.push(Entry::Directory { path, digest });
-                } else if global_path.is_file() {
-                    entries.push(self.add_file(&root_path, &global_path).await?);
-                } else {
-                    panic!("path of unknown type");
-                }
-            }
-            Ok::<Vec<Entry>, ExecuteError>(entries)
-        }
-        .instrument(finish_span)
-        .await?;
+    async fn cancel(&self, id: Uuid) -> Result<(), ExecuteError> {
+        self.cancellations.cancel(id);
+        Ok(())
+    }
 
-        Ok(ExecuteResponse {
-            exit_status: output.status.code().unwrap(),
-            output_paths: entries,
-            stderr: output.stderr,
-            stdout: output.stdout,
-        })
+    fn supports(&self, platform: &Platform) -> bool {
+        self.platform.satisfies(platform)
     }
 }
@@ -1,23 +1,62 @@
 use async_trait::async_trait;
 use cas::ContentAddressableStorage;
 use common::Digest;
+use prost::Message;
+use std::collections::HashMap;
 use std::future::Future;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 use tokio::sync::mpsc;
 use uuid::Uuid;
 
+pub mod cgroup;
 mod engine;
 pub mod hermetic;
 pub mod insecure;
+mod local;
+pub mod logs;
+mod platform;
+pub mod remote;
+pub mod sandbox;
+mod scheduler;
 
-pub use engine::{ExecuteStage, ExecuteStatus, ExecutionEngine};
+pub use cgroup::ResourceUsage;
+pub use engine::{EngineConfig, ExecuteStage, ExecuteStatus, ExecutionEngine};
+pub use logs::{LiveLogs, LiveOutput};
+pub use platform::Platform;
 
-#[derive(Default, Debug)]
+#[derive(Clone, Default, Debug)]
 pub struct Command {
     pub arguments: Vec<String>,
     pub env_vars: Vec<(String, String)>,
+    /// Kill the command if it runs for longer than this.
+    pub timeout: Option<Duration>,
+    /// Properties the machine running the command must have.
+    pub platform: Platform,
+    /// Directory, relative to the input root, the command runs in. Empty for the
+    /// input root itself.
+    pub working_directory: PathBuf,
+    /// Stdout and stderr longer than this are written to the CAS, and returned
+    /// by digest rather than inline.
+    pub max_inline_output_bytes: Option<u64>,
+    /// Where stdout and stderr are also written as the command runs.
+    pub output: LiveOutput,
+}
+
+/// An action ready to be handed to the engine.
+#[derive(Debug)]
+pub struct Action {
+    pub digest: Digest,
+    pub command: Command,
+    pub layout: DirectoryLayout,
+    /// The result of this action must not be written to the action cache.
+    pub do_not_cache: bool,
+    /// How long the client is willing to let the action run for, if it said.
+    pub timeout: Option<Duration>,
+    /// How soon the action should run relative to others, smaller numbers first.
+    pub priority: i32,
 }
 
 /// Information on a digest reified into the filesystem.
@@ -27,24 +66,95 @@ pub enum Entry {
         path: PathBuf,
         digest: Digest,
         executable: bool,
+        properties: NodeProperties,
     },
     Directory {
         path: PathBuf,
         digest: Digest,
+        /// Those of an input directory. Those of an output directory are in its
+        /// tree.
+        properties: NodeProperties,
     },
+    /// A symlink at `link`, with `original` as its target, which is resolved
+    /// relative to the directory the symlink is in.
     Symlink {
         original: PathBuf,
         link: PathBuf,
+        /// What the target of an output symlink is, if it exists.
+        points_to: Option<OutputKind>,
     },
 }
 
 /// Description of how to layout execution directory structure
-#[derive(Default, Debug)]
+#[derive(Clone, Default, Debug)]
 pub struct DirectoryLayout {
+    /// Digest of the input root `Directory` the entries were collected from.
+    pub input_root_digest: Option<Digest>,
     /// Entries for laying out directory structure before execution.
     pub entries: Vec<Entry>,
-    /// Expected paths to be generated by execution.
+    /// Expected paths to be generated by execution, relative to the input root.
     pub output_paths: Vec<PathBuf>,
+    /// What the outputs at the paths of clients predating `output_paths` must be.
+    pub output_kinds: HashMap<PathBuf, OutputKind>,
+    /// Which properties of output files and directories to report.
+    pub output_properties: OutputProperties,
+}
+
+/// Properties of a file or directory other than its contents.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NodeProperties {
+    pub mtime: Option<SystemTime>,
+    /// Permission bits, e.g. 0o755.
+    pub unix_mode: Option<u32>,
+}
+
+/// The properties a client asked for in `Command.output_node_properties`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OutputProperties {
+    pub mtime: bool,
+    pub unix_mode: bool,
+}
+
+impl OutputProperties {
+    /// Names of the properties that can be asked for, as advertised in the
+    /// capabilities.
+    pub const SUPPORTED: [&'static str; 2] = ["mtime", "unix_mode"];
+
+    /// The properties named by `keys`, which must all be supported.
+    pub fn parse(keys: &[String]) -> Result<Self, ExecuteError> {
+        let mut properties = OutputProperties::default();
+        for key in keys {
+            match key.as_str() {
+                "mtime" => properties.mtime = true,
+                "unix_mode" => properties.unix_mode = true,
+                _ => {
+                    return Err(ExecuteError::InvalidArgument(format!(
+                        "Unsupported output node property {key:?}"
+                    )))
+                }
+            }
+        }
+        Ok(properties)
+    }
+
+    /// The names of the properties, the reverse of `parse`.
+    pub fn keys(&self) -> Vec<String> {
+        let wanted = [self.mtime, self.unix_mode];
+        Self::SUPPORTED
+            .iter()
+            .zip(wanted)
+            .filter(|(_, wanted)| *wanted)
+            .map(|(key, _)| key.to_string())
+            .collect()
+    }
+}
+
+/// The kind of output a client expects at a path, from `output_files` or
+/// `output_directories`, or that an output symlink points to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputKind {
+    File,
+    Directory,
 }
 
 #[derive(Debug, Error)]
@@ -59,14 +169,225 @@ pub enum ExecuteError {
     CasError(#[from] cas::CasError),
     #[error("An internal error occurred in the execution engine or the worker: {0}")]
     Internal(String),
+    #[error("The execution was cancelled before it completed.")]
+    Cancelled,
+    #[error("The action did not finish within its timeout.")]
+    DeadlineExceeded(Box<ExecuteResponse>),
+    #[error("The action was killed for using more memory than it is allowed.")]
+    OutOfMemory(Box<ExecuteResponse>),
+    #[error("No worker can run the action, none has the platform properties it requires: {0}")]
+    UnsupportedPlatform(Platform),
+    #[error("The output {} is not the kind of output the command declared it as.", .0.display())]
+    UnexpectedOutputKind(PathBuf),
+    #[error("The output symlink {} has an absolute target, which is not allowed.", .0.display())]
+    AbsoluteOutputSymlink(PathBuf),
+}
+
+impl ExecuteError {
+    /// The response of a command that ran, but was killed before it completed.
+    pub fn response_mut(&mut self) -> Option<&mut ExecuteResponse> {
+        match self {
+            ExecuteError::DeadlineExceeded(resp) | ExecuteError::OutOfMemory(resp) => Some(resp),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct ExecuteResponse {
     pub exit_status: i32,
     pub output_paths: Vec<Entry>,
+    /// Empty when written to the CAS, as `stderr_digest`.
     pub stderr: Vec<u8>,
     pub stdout: Vec<u8>,
+    pub stderr_digest: Option<Digest>,
+    pub stdout_digest: Option<Digest>,
+    /// What the command used, if the backend accounts for it.
+    pub usage: Option<ResourceUsage>,
+    pub metadata: ExecutionMetadata,
+}
+
+/// Where an action ran, and when each stage of running it started and completed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExecutionMetadata {
+    /// Name of the worker, or node, that ran the action.
+    pub worker: String,
+    /// When the action was queued to run.
+    pub queued: Option<SystemTime>,
+    pub worker_start: Option<SystemTime>,
+    pub worker_completed: Option<SystemTime>,
+    pub input_fetch_start: Option<SystemTime>,
+    pub input_fetch_completed: Option<SystemTime>,
+    pub execution_start: Option<SystemTime>,
+    pub execution_completed: Option<SystemTime>,
+    pub output_upload_start: Option<SystemTime>,
+    pub output_upload_completed: Option<SystemTime>,
+}
+
+/// Type of the `ResourceUsage` sent in `ExecutedActionMetadata.auxiliary_metadata`.
+pub static RESOURCE_USAGE: &str = "type.googleapis.com/oryx.execution.v1.ResourceUsage";
+
+impl From<&ExecuteResponse> for protos::re::ActionResult {
+    fn from(resp: &ExecuteResponse) -> Self {
+        let execution_metadata = protos::re::ExecutedActionMetadata {
+            auxiliary_metadata: resp
+                .usage
+                .iter()
+                .map(|usage| prost_types::Any {
+                    type_url: RESOURCE_USAGE.to_string(),
+                    value: protos::execution::ResourceUsage::from(usage).encode_to_vec(),
+                })
+                .collect(),
+            ..(&resp.metadata).into()
+        };
+
+        // Collect outputs from the finished execution
+        let mut output_files = vec![];
+        let mut output_directories = vec![];
+        let mut output_symlinks = vec![];
+        let mut output_file_symlinks = vec![];
+        let mut output_directory_symlinks = vec![];
+        for entry in &resp.output_paths {
+            match entry.clone() {
+                Entry::Symlink {
+                    original,
+                    link,
+                    points_to,
+                } => {
+                    let symlink = protos::re::OutputSymlink {
+                        path: link.display().to_string(),
+                        target: original.display().to_string(),
+                        node_properties: None,
+                    };
+                    // Servers that wish to be compatible with v2.0 API should still
+                    // populate the fields by target type in addition to `output_symlinks`.
+                    match points_to {
+                        Some(OutputKind::File) => output_file_symlinks.push(symlink.clone()),
+                        Some(OutputKind::Directory) => {
+                            output_directory_symlinks.push(symlink.clone())
+                        }
+                        None => {}
+                    }
+                    output_symlinks.push(symlink);
+                }
+                Entry::File {
+                    path,
+                    digest,
+                    executable,
+                    properties,
+                } => {
+                    output_files.push(protos::re::OutputFile {
+                        path: path.display().to_string(),
+                        digest: Some(digest.into()),
+                        is_executable: executable,
+                        // The contents of the file if inlining was requested. The server
+                        // SHOULD NOT inline file contents unless requested by the client in
+                        // the [GetActionResultRequest][build.bazel.remote.execution.v2.GetActionResultRequest]
+                        // message. The server MAY omit inlining, even if requested, and MUST do so if inlining
+                        // would cause the response to exceed message size limits.
+                        contents: vec![],
+                        node_properties: properties.into(),
+                    });
+                }
+                Entry::Directory { path, digest, .. } => {
+                    assert!(path.is_relative());
+                    output_directories.push(protos::re::OutputDirectory {
+                        path: path.display().to_string(),
+                        tree_digest: Some(digest.into()),
+                        is_topologically_sorted: false,
+                    });
+                }
+            }
+        }
+
+        // The files, directories and symlinks in the directory must each be sorted
+        // in lexicographical order by path. The path strings must be sorted by code
+        // point, equivalently, by UTF-8 bytes.
+        output_files.sort_by(|a, b| a.path.cmp(&b.path));
+        output_directories.sort_by(|a, b| a.path.cmp(&b.path));
+        output_symlinks.sort_by(|a, b| a.path.cmp(&b.path));
+        output_file_symlinks.sort_by(|a, b| a.path.cmp(&b.path));
+        output_directory_symlinks.sort_by(|a, b| a.path.cmp(&b.path));
+
+        protos::re::ActionResult {
+            output_files,
+            output_file_symlinks,
+            output_symlinks,
+            output_directories,
+            output_directory_symlinks,
+            exit_code: resp.exit_status,
+            execution_metadata: Some(execution_metadata),
+            stdout_digest: resp.stdout_digest.clone().map(Into::into),
+            stderr_digest: resp.stderr_digest.clone().map(Into::into),
+            stdout_raw: resp.stdout.clone(),
+            stderr_raw: resp.stderr.clone(),
+        }
+    }
+}
+
+impl From<NodeProperties> for Option<protos::re::NodeProperties> {
+    fn from(properties: NodeProperties) -> Self {
+        if properties == NodeProperties::default() {
+            return None;
+        }
+        Some(protos::re::NodeProperties {
+            properties: vec![],
+            mtime: properties.mtime.map(prost_types::Timestamp::from),
+            unix_mode: properties.unix_mode,
+        })
+    }
+}
+
+impl From<Option<protos::re::NodeProperties>> for NodeProperties {
+    fn from(properties: Option<protos::re::NodeProperties>) -> Self {
+        // String-based properties have no meaning here, and so are ignored.
+        let properties = properties.unwrap_or_default();
+        NodeProperties {
+            mtime: properties
+                .mtime
+                .and_then(|mtime| SystemTime::try_from(mtime).ok()),
+            unix_mode: properties.unix_mode,
+        }
+    }
+}
+
+impl From<&ExecutionMetadata> for protos::re::ExecutedActionMetadata {
+    fn from(metadata: &ExecutionMetadata) -> Self {
+        let timestamp = |time: Option<SystemTime>| time.map(prost_types::Timestamp::from);
+        protos::re::ExecutedActionMetadata {
+            worker: metadata.worker.clone(),
+            queued_timestamp: timestamp(metadata.queued),
+            worker_start_timestamp: timestamp(metadata.worker_start),
+            worker_completed_timestamp: timestamp(metadata.worker_completed),
+            input_fetch_start_timestamp: timestamp(metadata.input_fetch_start),
+            input_fetch_completed_timestamp: timestamp(metadata.input_fetch_completed),
+            execution_start_timestamp: timestamp(metadata.execution_start),
+            execution_completed_timestamp: timestamp(metadata.execution_completed),
+            output_upload_start_timestamp: timestamp(metadata.output_upload_start),
+            output_upload_completed_timestamp: timestamp(metadata.output_upload_completed),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<protos::re::ExecutedActionMetadata> for ExecutionMetadata {
+    fn from(metadata: protos::re::ExecutedActionMetadata) -> Self {
+        let time = |timestamp: Option<prost_types::Timestamp>| {
+            timestamp.and_then(|timestamp| SystemTime::try_from(timestamp).ok())
+        };
+        ExecutionMetadata {
+            worker: metadata.worker,
+            queued: time(metadata.queued_timestamp),
+            worker_start: time(metadata.worker_start_timestamp),
+            worker_completed: time(metadata.worker_completed_timestamp),
+            input_fetch_start: time(metadata.input_fetch_start_timestamp),
+            input_fetch_completed: time(metadata.input_fetch_completed_timestamp),
+            execution_start: time(metadata.execution_start_timestamp),
+            execution_completed: time(metadata.execution_completed_timestamp),
+            output_upload_start: time(metadata.output_upload_start_timestamp),
+            output_upload_completed: time(metadata.output_upload_completed_timestamp),
+        }
+    }
 }
 
 #[async_trait]
@@ -78,7 +399,19 @@ pub trait ExecutionBackend: Send + Sync + 'static + Clone {
     /// Returns an Execute Response.
     async fn run_command(
         &self,
+        id: Uuid,
         command: Command,
         dir: DirectoryLayout,
     ) -> Result<ExecuteResponse, ExecuteError>;
+
+    /// Stop the command started by `run_command` with the same `id`.
+    ///
+    /// Anything the command started must be killed and its working directory
+    /// cleaned up, with `run_command` returning `ExecuteError::Cancelled`.
+    /// Cancelling a command that already finished does nothing.
+    async fn cancel(&self, id: Uuid) -> Result<(), ExecuteError>;
+
+    /// Whether commands requiring `platform` can be run, now or once the
+    /// machines able to run them are available.
+    fn supports(&self, platform: &Platform) -> bool;
 }
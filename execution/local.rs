@@ -0,0 +1,608 @@
+//! Pieces shared by the backends that run commands on this machine.
+
+use crate::cgroup::Cgroup;
+use crate::logs::Log;
+use crate::*;
+use cas::BlobWriter;
+use futures::future::BoxFuture;
+use libc::c_int;
+use prost::Message;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process;
+use tokio::sync::{oneshot, watch};
+use tokio::time::sleep;
+use tracing::{event, span, Instrument, Level, Span};
+
+/// How long output is still read from a command that was killed, for anything
+/// that escaped its process group to let go of its stdout and stderr.
+const KILLED_OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+/// Signals to stop each running command, by execution id.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Cancellations {
+    running: Arc<Mutex<HashMap<Uuid, oneshot::Sender<()>>>>,
+}
+
+impl Cancellations {
+    /// Note a command is running, returning what fires if it is cancelled.
+    pub(crate) fn start(&self, id: Uuid) -> oneshot::Receiver<()> {
+        let (cancel, cancelled) = oneshot::channel();
+        self.running.lock().unwrap().insert(id, cancel);
+        cancelled
+    }
+
+    pub(crate) fn finish(&self, id: Uuid) {
+        self.running.lock().unwrap().remove(&id);
+    }
+
+    pub(crate) fn cancel(&self, id: Uuid) {
+        let cancel = self.running.lock().unwrap().remove(&id);
+        if let Some(cancel) = cancel {
+            // The command may finish before noticing, which is fine.
+            let _ = cancel.send(());
+        }
+    }
+}
+
+/// How a command started by `run_process` ended.
+pub(crate) enum Exit {
+    Exited(ExitStatus),
+    /// The command ran for longer than its timeout and was killed.
+    TimedOut(ExitStatus),
+    /// The command used more memory than its cgroup allows and was killed.
+    OutOfMemory(ExitStatus),
+    /// The command was killed because its execution was cancelled.
+    Cancelled,
+}
+
+/// What a command wrote to stdout or stderr, held inline if it fits within the
+/// command's `max_inline_output_bytes`, and otherwise stored in the CAS.
+#[derive(Default)]
+pub(crate) struct Captured {
+    pub inline: Vec<u8>,
+    pub digest: Option<Digest>,
+}
+
+/// A command that has stopped running, with everything it wrote to its output.
+pub(crate) struct Finished {
+    pub exit: Exit,
+    pub stdout: Captured,
+    pub stderr: Captured,
+    /// What the command used, if it ran in a cgroup.
+    pub usage: Option<ResourceUsage>,
+    /// When the command was started, and when it stopped.
+    pub started: SystemTime,
+    pub stopped: SystemTime,
+}
+
+impl Finished {
+    /// The response for a command that ran to completion, or the error for one
+    /// that didn't, with `metadata` on the stages before and after running it.
+    pub(crate) fn into_result(
+        self,
+        output_paths: Vec<Entry>,
+        metadata: ExecutionMetadata,
+    ) -> Result<ExecuteResponse, ExecuteError> {
+        let status = match self.exit {
+            Exit::Exited(status) | Exit::TimedOut(status) | Exit::OutOfMemory(status) => status,
+            Exit::Cancelled => return Err(ExecuteError::Cancelled),
+        };
+        let response = ExecuteResponse {
+            exit_status: exit_code(status),
+            output_paths,
+            stderr: self.stderr.inline,
+            stdout: self.stdout.inline,
+            stderr_digest: self.stderr.digest,
+            stdout_digest: self.stdout.digest,
+            usage: self.usage,
+            metadata: ExecutionMetadata {
+                execution_start: Some(self.started),
+                execution_completed: Some(self.stopped),
+                worker_completed: Some(SystemTime::now()),
+                ..metadata
+            },
+        };
+        match self.exit {
+            Exit::TimedOut(_) => Err(ExecuteError::DeadlineExceeded(Box::new(response))),
+            Exit::OutOfMemory(_) => Err(ExecuteError::OutOfMemory(Box::new(response))),
+            _ => Ok(response),
+        }
+    }
+}
+
+/// Run a command in `current_dir`, killing it, along with anything it started,
+/// if it times out or `cancelled` fires. Anything it leaves running in the
+/// background is killed once it exits, and the timeout also covers reading the
+/// last of its output.
+///
+/// The command runs in `cgroup`, if given, and `configure` may change how its
+/// process is spawned, e.g. to isolate it. Output too long to return inline is
+/// written to `cas` as the command runs.
+pub(crate) async fn run_process<C: ContentAddressableStorage>(
+    parent: &Span,
+    cas: &C,
+    command: Command,
+    current_dir: &Path,
+    cgroup: Option<&Cgroup>,
+    configure: impl FnOnce(&mut std::process::Command),
+    mut cancelled: oneshot::Receiver<()>,
+) -> Result<Finished, ExecuteError> {
+    let binary = &command.arguments[0];
+    let args = &command.arguments[1..];
+    let envs = command.env_vars;
+    let exec_span = span!(parent: parent, Level::TRACE, "execute",
+            binary=binary,
+            args=?args,
+            envs=?envs,
+            current_dir=?current_dir);
+    let mut child = std::process::Command::new(binary);
+    child
+        .current_dir(current_dir)
+        .args(args)
+        .envs(envs)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Lead a process group of its own, so everything the command started can
+        // be killed along with it.
+        .process_group(0);
+    if let Some(cgroup) = cgroup {
+        let procs = cgroup.procs()?;
+        // SAFETY: joining the cgroup only writes to a file, which is safe between
+        // fork and exec. It comes first, so the process is accounted for from the
+        // start and still has permission to.
+        unsafe { child.pre_exec(move || write_file(procs.as_bytes_with_nul(), b"0")) };
+    }
+    configure(&mut child);
+    let started = SystemTime::now();
+    let mut child = process::Command::from(child).kill_on_drop(true).spawn()?;
+    let mut group = ProcessGroup(child.id());
+    // Drain output while the command runs, so it's kept even if the command is killed.
+    let limit = command.max_inline_output_bytes;
+    let (stop_reading, stopped_reading) = watch::channel(false);
+    let stdout = child.stdout.take();
+    let stdout = tokio::spawn(read_pipe(
+        stdout,
+        stopped_reading.clone(),
+        command.output.stdout,
+        cas.clone(),
+        limit,
+    ));
+    let stderr = child.stderr.take();
+    let stderr = tokio::spawn(read_pipe(
+        stderr,
+        stopped_reading,
+        command.output.stderr,
+        cas.clone(),
+        limit,
+    ));
+    let deadline = async {
+        match command.timeout {
+            Some(timeout) => sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(deadline);
+    let mut exit = tokio::select! {
+        status = child.wait().instrument(exec_span) => match cgroup {
+            Some(cgroup) if cgroup.oom_killed() => Exit::OutOfMemory(status?),
+            _ => Exit::Exited(status?),
+        },
+        _ = &mut deadline => {
+            group.kill();
+            Exit::TimedOut(child.wait().await?)
+        }
+        _ = &mut cancelled => {
+            group.kill();
+            Exit::Cancelled
+        }
+    };
+    let stopped = SystemTime::now();
+    // Whatever the command left running in the background would otherwise keep
+    // running, and keep its output open.
+    group.kill();
+
+    let read_limit = async {
+        match exit {
+            Exit::Exited(_) | Exit::OutOfMemory(_) => (&mut deadline).await,
+            Exit::TimedOut(_) | Exit::Cancelled => sleep(KILLED_OUTPUT_GRACE).await,
+        }
+    };
+    let outputs = async { (stdout.await, stderr.await) };
+    tokio::pin!(outputs);
+    let (stdout, stderr) = tokio::select! {
+        outputs = &mut outputs => outputs,
+        _ = read_limit => {
+            // Something escaped the process group and holds on to the output,
+            // which is left with what was read so far.
+            let _ = stop_reading.send(true);
+            if let Exit::Exited(status) = exit {
+                exit = Exit::TimedOut(status);
+            }
+            outputs.await
+        }
+    };
+    let joined = |err: tokio::task::JoinError| ExecuteError::Internal(err.to_string());
+    let stdout = stdout.map_err(joined)?;
+    let stderr = stderr.map_err(joined)?;
+    Ok(Finished {
+        exit,
+        stdout: stdout?,
+        stderr: stderr?,
+        usage: cgroup.map(Cgroup::usage),
+        started,
+        stopped,
+    })
+}
+
+/// Everything written to a pipe until it is closed, or `stop` is set, which is
+/// also appended to `log` as it comes. Once there is more than `limit`, it is
+/// streamed to `cas` rather than held in memory.
+async fn read_pipe<C: ContentAddressableStorage>(
+    pipe: Option<impl AsyncRead + Unpin>,
+    mut stop: watch::Receiver<bool>,
+    log: Log,
+    cas: C,
+    limit: Option<u64>,
+) -> Result<Captured, ExecuteError> {
+    let mut captured = Captured::default();
+    let Some(mut pipe) = pipe else {
+        return Ok(captured);
+    };
+    let mut writer: Option<Box<dyn BlobWriter>> = None;
+    let mut chunk = [0; 8192];
+    loop {
+        let read = tokio::select! {
+            biased;
+            _ = stop.changed() => break,
+            read = pipe.read(&mut chunk) => match read {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(err) => {
+                    event!(Level::WARN, %err, "failed to read command output");
+                    break;
+                }
+            },
+        };
+        log.append(&chunk[..read]);
+        match &mut writer {
+            Some(writer) => writer.write_all(&chunk[..read]).await?,
+            None => {
+                captured.inline.extend_from_slice(&chunk[..read]);
+                if limit.is_some_and(|limit| captured.inline.len() as u64 > limit) {
+                    let inline = std::mem::take(&mut captured.inline);
+                    let mut blob = cas.blob_writer().await?;
+                    blob.write_all(&inline).await?;
+                    writer = Some(blob);
+                }
+            }
+        }
+    }
+    if let Some(writer) = writer {
+        captured.digest = Some(writer.commit(None).await?);
+    }
+    Ok(captured)
+}
+
+/// The process group a command leads, killed along with anything the command
+/// started at the latest when dropped, so nothing is left running however
+/// running the command ends.
+struct ProcessGroup(Option<u32>);
+
+impl ProcessGroup {
+    /// Kill the group, once, as its id may be reused afterwards.
+    fn kill(&mut self) {
+        if let Some(pid) = self.0.take() {
+            // SAFETY: only signals the process group created for the command.
+            unsafe { libc::killpg(pid as libc::pid_t, libc::SIGKILL) };
+        }
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// The exit code of a command, following the shell convention of 128 plus the
+/// signal number for commands killed by a signal.
+pub(crate) fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(-1)
+}
+
+pub(crate) fn get_root_relative(root_path: &Path, path: &Path) -> PathBuf {
+    let mut new_path: PathBuf = root_path.to_path_buf();
+    new_path.push(path);
+    new_path
+}
+
+/// Write the inputs of a command under `root_path`, along with the directories
+/// its outputs go in.
+pub(crate) async fn lay_out_inputs<C: ContentAddressableStorage>(
+    cas: &C,
+    root_path: &Path,
+    entries: Vec<Entry>,
+    output_paths: &[PathBuf],
+) -> Result<(), ExecuteError> {
+    // Laying out what is in a directory changes its mtime, and its mode may not
+    // allow it, so the properties of directories are set last.
+    let mut directories = vec![];
+    for entry in entries {
+        match entry {
+            Entry::Symlink { original, link, .. } => {
+                let link = get_root_relative(root_path, &link);
+                if let Some(prefix) = link.parent() {
+                    std::fs::create_dir_all(prefix)?;
+                }
+                std::os::unix::fs::symlink(original, link)?;
+            }
+            Entry::Directory {
+                path, properties, ..
+            } => {
+                let path = get_root_relative(root_path, &path);
+                std::fs::create_dir_all(&path)?;
+                directories.push((path, properties));
+            }
+            Entry::File {
+                digest,
+                executable,
+                path,
+                properties,
+            } => {
+                let path = get_root_relative(root_path, &path);
+                if let Some(prefix) = path.parent() {
+                    std::fs::create_dir_all(prefix)?;
+                }
+                let mut file = File::create(&path).await?;
+                let mut blob = cas.blob_reader(digest, 0).await?;
+                tokio::io::copy(&mut blob, &mut file).await?;
+                file.flush().await?;
+                if executable {
+                    let metadata = file.metadata().await?;
+                    let mut permissions = metadata.permissions();
+                    permissions.set_mode(0o777);
+                    tokio::fs::set_permissions(&path, permissions).await?;
+                }
+                set_properties(&path, properties)?;
+            }
+        }
+    }
+
+    // Directories leading up to the output paths are created by the worker prior
+    // to execution, even if they are not explicitly part of the input root.
+    for path in output_paths {
+        let global_path = get_root_relative(root_path, path);
+        if let Some(prefix) = global_path.parent() {
+            std::fs::create_dir_all(prefix)?;
+        }
+    }
+
+    // Those within a directory come after it, and so are set first.
+    for (path, properties) in directories.into_iter().rev() {
+        set_properties(&path, properties)?;
+    }
+    Ok(())
+}
+
+/// Give the file or directory at `path` the `properties` of an input. A mode
+/// takes precedence over whether a file is executable.
+fn set_properties(path: &Path, properties: NodeProperties) -> io::Result<()> {
+    if let Some(mtime) = properties.mtime {
+        std::fs::File::open(path)?.set_modified(mtime)?;
+    }
+    if let Some(mode) = properties.unix_mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// The `wanted` properties of the output file or directory with `metadata`.
+fn get_properties(
+    metadata: &std::fs::Metadata,
+    wanted: OutputProperties,
+) -> io::Result<NodeProperties> {
+    Ok(NodeProperties {
+        mtime: wanted.mtime.then(|| metadata.modified()).transpose()?,
+        unix_mode: wanted
+            .unix_mode
+            .then(|| metadata.permissions().mode() & 0o7777),
+    })
+}
+
+/// Store the outputs a command left under `root_path` in the CAS.
+pub(crate) async fn collect_outputs<C: ContentAddressableStorage>(
+    cas: &C,
+    root_path: &Path,
+    output_paths: Vec<PathBuf>,
+    properties: OutputProperties,
+) -> Result<Vec<Entry>, ExecuteError> {
+    // Verify outputs were created and get their hash
+    let mut entries = vec![];
+    for path in output_paths {
+        let global_path = get_root_relative(root_path, &path);
+        let mut children = vec![];
+        if global_path.is_symlink() {
+            let points_to = if global_path.is_dir() {
+                Some(OutputKind::Directory)
+            } else if global_path.is_file() {
+                Some(OutputKind::File)
+            } else {
+                None
+            };
+            entries.push(Entry::Symlink {
+                original: read_link(&global_path)?,
+                link: path,
+                points_to,
+            });
+        } else if global_path.is_dir() {
+            let root = add_dir(cas, root_path, &global_path, properties, &mut children).await?;
+            let tree = protos::re::Tree {
+                root: Some(root),
+                children,
+            };
+            let proto_buf = tree.encode_to_vec();
+            let digest = cas.write_blob(&proto_buf, None).await?;
+            entries.push(Entry::Directory {
+                path,
+                digest,
+                properties: NodeProperties::default(),
+            });
+        } else if global_path.is_file() {
+            entries.push(add_file(cas, root_path, &global_path, properties).await?);
+        }
+        // It is not an error for the command not to create an output.
+    }
+    Ok(entries)
+}
+
+/// The target of the output symlink at `path`, which must be relative, as
+/// absolute targets aren't allowed.
+fn read_link(path: &Path) -> Result<PathBuf, ExecuteError> {
+    let target = std::fs::read_link(path)?;
+    if target.is_absolute() {
+        return Err(ExecuteError::AbsoluteOutputSymlink(path.to_path_buf()));
+    }
+    Ok(target)
+}
+
+/// The error for an output at `path` that can't be stored, as it `problem`.
+fn invalid_output(root_path: &Path, path: &Path, problem: &str) -> ExecuteError {
+    let path = path.strip_prefix(root_path).unwrap_or(path);
+    ExecuteError::InvalidArgument(format!("Output {} {problem}.", path.display()))
+}
+
+async fn add_file<C: ContentAddressableStorage>(
+    cas: &C,
+    root_path: &Path,
+    path: &Path,
+    properties: OutputProperties,
+) -> Result<Entry, ExecuteError> {
+    let mut file = tokio::fs::File::open(&path).await?;
+    let metadata = file.metadata().await?;
+    let mut blob = cas.blob_writer().await?;
+    tokio::io::copy(&mut file, &mut blob).await?;
+    let digest = blob.commit(None).await?;
+    Ok(Entry::File {
+        path: path.strip_prefix(root_path).unwrap().to_path_buf(),
+        digest,
+        executable: metadata.permissions().mode() & 0o111 != 0,
+        properties: get_properties(&metadata, properties)?,
+    })
+}
+
+fn add_dir<'a, C: ContentAddressableStorage>(
+    cas: &'a C,
+    root_path: &'a Path,
+    path: &'a Path,
+    properties: OutputProperties,
+    children: &'a mut Vec<protos::re::Directory>,
+) -> BoxFuture<'a, Result<protos::re::Directory, ExecuteError>> {
+    Box::pin(async move {
+        let mut files = vec![];
+        let mut directories = vec![];
+        let mut symlinks = vec![];
+
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            let entry_path = entry.path();
+            let name = entry.file_name().into_string().map_err(|_| {
+                invalid_output(root_path, &entry_path, "has a name that isn't UTF-8")
+            })?;
+            if entry_path.is_symlink() {
+                let target = read_link(&entry_path)?.into_os_string().into_string();
+                symlinks.push(protos::re::SymlinkNode {
+                    name,
+                    target: target.map_err(|_| {
+                        invalid_output(root_path, &entry_path, "points to a path that isn't UTF-8")
+                    })?,
+                    node_properties: None,
+                })
+            } else if entry_path.is_file() {
+                let Entry::File {
+                    digest,
+                    executable,
+                    properties,
+                    ..
+                } = add_file(cas, root_path, &entry_path, properties).await?
+                else {
+                    unreachable!()
+                };
+                files.push(protos::re::FileNode {
+                    name,
+                    digest: Some(digest.into()),
+                    is_executable: executable,
+                    node_properties: properties.into(),
+                })
+            } else if entry_path.is_dir() {
+                let dir = add_dir(cas, root_path, &entry_path, properties, children).await?;
+                children.push(dir.clone());
+                let proto_buf = dir.encode_to_vec();
+                let digest = cas.write_blob(&proto_buf, None).await?;
+                directories.push(protos::re::DirectoryNode {
+                    name,
+                    digest: Some(digest.into()),
+                })
+            } else {
+                // Fifos, sockets and devices have no place in a Directory.
+                return Err(invalid_output(
+                    root_path,
+                    &entry_path,
+                    "isn't a file, directory or symlink",
+                ));
+            }
+        }
+
+        // Each kind of node must be sorted by name, for the digest of the directory
+        // to only depend on its contents.
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        directories.sort_by(|a, b| a.name.cmp(&b.name));
+        symlinks.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(protos::re::Directory {
+            files,
+            directories,
+            symlinks,
+            node_properties: get_properties(&std::fs::metadata(path)?, properties)?.into(),
+        })
+    })
+}
+
+/// Write `contents` to the file at `path`, without allocating.
+///
+/// SAFETY: `path` must end with a nul byte.
+pub(crate) unsafe fn write_file(path: &[u8], contents: &[u8]) -> io::Result<()> {
+    let fd = check(libc::open(
+        path.as_ptr().cast(),
+        libc::O_WRONLY | libc::O_CLOEXEC,
+    ))?;
+    let written = libc::write(fd, contents.as_ptr().cast(), contents.len());
+    libc::close(fd);
+    match written {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// The result of a system call, with the error it set if it failed.
+pub(crate) fn check(result: c_int) -> io::Result<c_int> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        result => Ok(result),
+    }
+}
+
+pub(crate) fn cstring(path: impl AsRef<Path>) -> io::Result<CString> {
+    Ok(CString::new(path.as_ref().as_os_str().as_bytes())?)
+}
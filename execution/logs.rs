@@ -0,0 +1,186 @@
+//! Output of commands as they run, for clients to follow before they finish.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+/// How much of the end of a log is kept for readers to catch up on. Anything
+/// older is dropped, the whole output being stored with the command's result.
+const LOG_WINDOW: usize = 1024 * 1024;
+
+#[derive(Debug, Default)]
+struct LogState {
+    /// The end of the log, from offset `start` on.
+    data: Vec<u8>,
+    start: usize,
+    /// Nothing more will be written.
+    closed: bool,
+}
+
+impl LogState {
+    fn len(&self) -> usize {
+        self.start + self.data.len()
+    }
+}
+
+/// A reader fell so far behind that what it had yet to read was dropped.
+#[derive(Debug, Error)]
+#[error("Output before offset {0} is no longer available")]
+pub struct Lagged(pub usize);
+
+/// What a command wrote to stdout or stderr so far, which can be read while
+/// more is written. Only the last `LOG_WINDOW` bytes or so are held.
+#[derive(Clone, Debug)]
+pub struct Log {
+    state: Arc<watch::Sender<LogState>>,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Log {
+            state: Arc::new(watch::channel(LogState::default()).0),
+        }
+    }
+}
+
+impl Log {
+    pub fn append(&self, data: &[u8]) {
+        if !data.is_empty() {
+            self.state.send_modify(|state| {
+                state.data.extend_from_slice(data);
+                // Drop in bulk, rather than on every append.
+                if state.data.len() > 2 * LOG_WINDOW {
+                    let dropped = state.data.len() - LOG_WINDOW;
+                    state.data.drain(..dropped);
+                    state.start += dropped;
+                }
+            });
+        }
+    }
+
+    /// Mark the log as complete, ending reads once they have caught up.
+    pub fn close(&self) {
+        self.state.send_modify(|state| state.closed = true);
+    }
+
+    /// How much was written, once nothing more will be.
+    pub fn final_len(&self) -> Option<usize> {
+        let state = self.state.borrow();
+        state.closed.then_some(state.len())
+    }
+
+    /// Follow the log from `offset` on.
+    pub fn reader(&self, offset: usize) -> LogReader {
+        LogReader {
+            state: self.state.subscribe(),
+            offset,
+        }
+    }
+}
+
+/// Follows a `Log` as it is written.
+pub struct LogReader {
+    state: watch::Receiver<LogState>,
+    offset: usize,
+}
+
+impl LogReader {
+    /// What was written since the last call, waiting for more if there is
+    /// nothing new. `None` once the log is closed and has all been read.
+    pub async fn next(&mut self) -> Result<Option<Vec<u8>>, Lagged> {
+        loop {
+            {
+                let state = self.state.borrow_and_update();
+                if self.offset < state.start {
+                    return Err(Lagged(self.offset));
+                }
+                if self.offset < state.len() {
+                    let data = state.data[self.offset - state.start..].to_vec();
+                    self.offset = state.len();
+                    return Ok(Some(data));
+                }
+                if state.closed {
+                    return Ok(None);
+                }
+            }
+            // Every writer went away without closing the log.
+            if self.state.changed().await.is_err() {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// The stdout and stderr of a command.
+#[derive(Clone, Debug, Default)]
+pub struct LiveOutput {
+    pub stdout: Log,
+    pub stderr: Log,
+}
+
+impl LiveOutput {
+    /// Mark both logs as complete.
+    pub fn close(&self) {
+        self.stdout.close();
+        self.stderr.close();
+    }
+}
+
+/// The output of the commands running now, by execution id.
+///
+/// Once a command finishes its output is only available from its result,
+/// though clients already following it can read it to the end.
+#[derive(Clone, Debug, Default)]
+pub struct LiveLogs {
+    running: Arc<Mutex<HashMap<Uuid, LiveOutput>>>,
+}
+
+impl LiveLogs {
+    pub fn new() -> Self {
+        LiveLogs::default()
+    }
+
+    /// Open the output of the command about to run as execution `id`.
+    pub(crate) fn start(&self, id: Uuid) -> LiveOutput {
+        let output = LiveOutput::default();
+        self.running.lock().unwrap().insert(id, output.clone());
+        output
+    }
+
+    /// Close the output of execution `id`, whose command finished.
+    pub(crate) fn finish(&self, id: Uuid) {
+        if let Some(output) = self.running.lock().unwrap().remove(&id) {
+            output.close();
+        }
+    }
+
+    /// The output of execution `id`, if it is still running.
+    pub fn get(&self, id: Uuid) -> Option<LiveOutput> {
+        self.running.lock().unwrap().get(&id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn readers_falling_behind_the_window_are_told() {
+        let log = Log::default();
+        let mut behind = log.reader(0);
+        let chunk = vec![b'x'; LOG_WINDOW];
+        for _ in 0..3 {
+            log.append(&chunk);
+        }
+        log.close();
+
+        // Only the end of the log is held, though its length is known.
+        assert!(matches!(behind.next().await, Err(Lagged(0))));
+        assert_eq!(log.final_len(), Some(3 * LOG_WINDOW));
+        let mut tail = log.reader(3 * LOG_WINDOW - 10);
+        assert_eq!(tail.next().await.unwrap(), Some(vec![b'x'; 10]));
+        assert_eq!(tail.next().await.unwrap(), None);
+    }
+}
@@ -0,0 +1,121 @@
+use crate::cgroup::{CPU_LIMIT_PROPERTY, MEMORY_LIMIT_PROPERTY, PIDS_LIMIT_PROPERTY};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Platform properties, such as `OSFamily` or `ISA`, as name and value pairs.
+///
+/// Backends and workers describe the machines they run commands on with these,
+/// and commands list the ones they require. A name may have several values.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(from = "BTreeMap<String, Values>")]
+pub struct Platform {
+    properties: BTreeSet<(String, String)>,
+}
+
+/// Properties setting limits on a command, rather than requirements of the
+/// machine running it.
+const LIMIT_PROPERTIES: [&str; 3] = [
+    MEMORY_LIMIT_PROPERTY,
+    CPU_LIMIT_PROPERTY,
+    PIDS_LIMIT_PROPERTY,
+];
+
+/// Values of a property in config, either a single one or a list.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Values {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<BTreeMap<String, Values>> for Platform {
+    fn from(map: BTreeMap<String, Values>) -> Self {
+        map.into_iter()
+            .flat_map(|(name, values)| {
+                let values = match values {
+                    Values::One(value) => vec![value],
+                    Values::Many(values) => values,
+                };
+                values.into_iter().map(move |value| (name.clone(), value))
+            })
+            .collect()
+    }
+}
+
+impl FromIterator<(String, String)> for Platform {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        Platform {
+            properties: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl Platform {
+    /// Whether a machine with this platform has every property `required` lists,
+    /// other than those limiting what the command may use, which aren't about the
+    /// machine.
+    pub fn satisfies(&self, required: &Platform) -> bool {
+        required
+            .properties
+            .iter()
+            .filter(|(name, _)| !LIMIT_PROPERTIES.contains(&name.as_str()))
+            .all(|property| self.properties.contains(property))
+    }
+
+    /// The value of the property `name`, the first if it has several.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.properties
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the property `name` has the value `value`.
+    pub fn contains(&self, name: &str, value: &str) -> bool {
+        self.properties
+            .contains(&(String::from(name), String::from(value)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.properties.is_empty()
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.properties.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+impl From<protos::re::Platform> for Platform {
+    fn from(platform: protos::re::Platform) -> Self {
+        platform
+            .properties
+            .into_iter()
+            .map(|property| (property.name, property.value))
+            .collect()
+    }
+}
+
+impl From<&Platform> for protos::re::Platform {
+    fn from(platform: &Platform) -> Self {
+        // Sorted by name, then value, as the REAPI requires.
+        protos::re::Platform {
+            properties: platform
+                .properties
+                .iter()
+                .map(|(name, value)| protos::re::platform::Property {
+                    name: name.clone(),
+                    value: value.clone(),
+                })
+                .collect(),
+        }
+    }
+}
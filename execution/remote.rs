@@ -0,0 +1,266 @@
+use crate::*;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+use tracing::{event, Level};
+
+/// What a worker says about itself when it connects.
+#[derive(Clone, Debug)]
+pub struct WorkerInfo {
+    /// Name of the worker, used to tell workers apart in logs.
+    pub name: String,
+    /// How many actions the worker can run at once.
+    pub capacity: usize,
+    /// Properties of the machine the worker runs actions on.
+    pub platform: Platform,
+}
+
+/// Work handed to a connected worker.
+#[derive(Debug)]
+pub enum WorkerTask {
+    /// Run a command, passing on its output with `RemoteWorker::output` as it
+    /// runs, and report back with `RemoteWorker::complete`.
+    Run {
+        id: Uuid,
+        command: Box<Command>,
+        layout: Box<DirectoryLayout>,
+    },
+    /// Stop running the command leased with the same `id`.
+    Cancel { id: Uuid },
+}
+
+type Done = oneshot::Sender<Result<ExecuteResponse, ExecuteError>>;
+
+/// A command waiting for, or leased to, a worker.
+struct Assignment {
+    command: Command,
+    layout: DirectoryLayout,
+    done: Done,
+}
+
+struct Worker {
+    info: WorkerInfo,
+    tasks: mpsc::UnboundedSender<WorkerTask>,
+    /// Commands leased to the worker, by execution id.
+    leased: HashMap<Uuid, Assignment>,
+}
+
+#[derive(Default)]
+struct State {
+    workers: HashMap<u64, Worker>,
+    next_worker: u64,
+    /// Commands no worker has had room for yet, oldest first.
+    pending: VecDeque<(Uuid, Assignment)>,
+}
+
+impl State {
+    /// Lease pending commands to the matching workers with the most room for
+    /// them. Commands no worker has room for stay pending, without holding up
+    /// the commands behind them that other workers could run.
+    fn dispatch(&mut self) {
+        let mut waiting = VecDeque::new();
+        while let Some((id, assignment)) = self.pending.pop_front() {
+            let worker = self
+                .workers
+                .values_mut()
+                .filter(|worker| worker.leased.len() < worker.info.capacity)
+                .filter(|worker| worker.info.platform.satisfies(&assignment.command.platform))
+                .max_by_key(|worker| worker.info.capacity - worker.leased.len());
+            let Some(worker) = worker else {
+                waiting.push_back((id, assignment));
+                continue;
+            };
+            event!(Level::INFO, %id, worker = worker.info.name, "leasing command");
+            // A worker that went away gives its leases back once its connection
+            // is dropped.
+            let _ = worker.tasks.send(WorkerTask::Run {
+                id,
+                command: Box::new(assignment.command.clone()),
+                layout: Box::new(assignment.layout.clone()),
+            });
+            worker.leased.insert(id, assignment);
+        }
+        self.pending = waiting;
+    }
+}
+
+/// Runs commands on workers connected to this node.
+///
+/// Commands are queued until a worker with the platform properties they require
+/// has room for them. Commands leased to a worker that disconnects before
+/// reporting back are leased again.
+#[derive(Clone, Default)]
+pub struct Remote {
+    state: Arc<Mutex<State>>,
+    /// Properties of the workers expected to connect.
+    expected: Arc<Vec<Platform>>,
+}
+
+impl Remote {
+    pub fn new(expected: Vec<Platform>) -> Self {
+        Remote {
+            state: Default::default(),
+            expected: Arc::new(expected),
+        }
+    }
+
+    /// Register a worker, returning a handle to report results with and the
+    /// tasks it should carry out.
+    ///
+    /// Dropping the handle disconnects the worker.
+    pub fn connect(&self, info: WorkerInfo) -> (RemoteWorker, mpsc::UnboundedReceiver<WorkerTask>) {
+        let (tasks, rx) = mpsc::unbounded_channel();
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_worker;
+        state.next_worker += 1;
+        event!(
+            Level::INFO,
+            worker = info.name,
+            capacity = info.capacity,
+            "worker connected"
+        );
+        state.workers.insert(
+            id,
+            Worker {
+                info,
+                tasks,
+                leased: HashMap::new(),
+            },
+        );
+        state.dispatch();
+        let worker = RemoteWorker {
+            remote: self.clone(),
+            id,
+        };
+        (worker, rx)
+    }
+}
+
+/// A worker connected to a `Remote` backend.
+pub struct RemoteWorker {
+    remote: Remote,
+    id: u64,
+}
+
+impl RemoteWorker {
+    /// Pass on what a command leased to this worker wrote as it runs.
+    pub fn output(&self, id: Uuid, stdout: &[u8], stderr: &[u8]) {
+        let state = self.remote.state.lock().unwrap();
+        let assignment = state
+            .workers
+            .get(&self.id)
+            .and_then(|worker| worker.leased.get(&id));
+        if let Some(assignment) = assignment {
+            assignment.command.output.stdout.append(stdout);
+            assignment.command.output.stderr.append(stderr);
+        }
+    }
+
+    /// Report the outcome of a command leased to this worker.
+    pub fn complete(&self, id: Uuid, mut result: Result<ExecuteResponse, ExecuteError>) {
+        let mut state = self.remote.state.lock().unwrap();
+        let worker = state
+            .workers
+            .get_mut(&self.id)
+            .expect("worker is connected");
+        let resp = match &mut result {
+            Ok(resp) => Some(resp),
+            Err(err) => err.response_mut(),
+        };
+        if let Some(resp) = resp {
+            resp.metadata.worker = worker.info.name.clone();
+        }
+        // Commands cancelled in the meantime have already been given up on.
+        if let Some(assignment) = worker.leased.remove(&id) {
+            let _ = assignment.done.send(result);
+        }
+        state.dispatch();
+    }
+}
+
+impl Drop for RemoteWorker {
+    fn drop(&mut self) {
+        let mut state = self.remote.state.lock().unwrap();
+        let Some(worker) = state.workers.remove(&self.id) else {
+            return;
+        };
+        event!(
+            Level::WARN,
+            worker = worker.info.name,
+            leased = worker.leased.len(),
+            "worker disconnected"
+        );
+        // Leased commands go ahead of those that have not been leased yet.
+        for lease in worker.leased {
+            state.pending.push_front(lease);
+        }
+        state.dispatch();
+    }
+}
+
+#[async_trait]
+impl ExecutionBackend for Remote {
+    async fn run_command(
+        &self,
+        id: Uuid,
+        command: Command,
+        dir: DirectoryLayout,
+    ) -> Result<ExecuteResponse, ExecuteError> {
+        let (done, result) = oneshot::channel();
+        {
+            let mut state = self.state.lock().unwrap();
+            state.pending.push_back((
+                id,
+                Assignment {
+                    command,
+                    layout: dir,
+                    done,
+                },
+            ));
+            state.dispatch();
+        }
+        result
+            .await
+            .map_err(|_| ExecuteError::Internal("the remote backend went away".to_string()))?
+    }
+
+    async fn cancel(&self, id: Uuid) -> Result<(), ExecuteError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(index) = state.pending.iter().position(|(pending, _)| *pending == id) {
+            let (_, assignment) = state.pending.remove(index).unwrap();
+            let _ = assignment.done.send(Err(ExecuteError::Cancelled));
+            return Ok(());
+        }
+        for worker in state.workers.values_mut() {
+            if let Some(assignment) = worker.leased.remove(&id) {
+                // Not waiting on the worker keeps a worker that stopped responding
+                // from holding the command up.
+                let _ = worker.tasks.send(WorkerTask::Cancel { id });
+                let _ = assignment.done.send(Err(ExecuteError::Cancelled));
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Commands wait for a worker able to run them to connect, if one is
+    /// expected to or is already connected. Without any expected workers every
+    /// command waits, as one might connect at any time.
+    fn supports(&self, platform: &Platform) -> bool {
+        if platform.is_empty() || self.expected.is_empty() {
+            return true;
+        }
+        if self
+            .expected
+            .iter()
+            .any(|expected| expected.satisfies(platform))
+        {
+            return true;
+        }
+        let state = self.state.lock().unwrap();
+        state
+            .workers
+            .values()
+            .any(|worker| worker.info.platform.satisfies(platform))
+    }
+}
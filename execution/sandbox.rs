@@ -0,0 +1,475 @@
+//! Runs commands in Linux namespaces of their own, where they see nothing of
+//! this machine but their input root and a few read-only system paths.
+
+use crate::cgroup::{Cgroup, CgroupConfig};
+use crate::local::*;
+use crate::*;
+use cas::ContentAddressableStorage;
+use libc::{c_int, c_uint, c_ulong, pid_t};
+use serde::Deserialize;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::ptr;
+use std::time::SystemTime;
+use tempdir::TempDir;
+use tokio::sync::oneshot;
+use tracing::{span, Instrument, Level};
+
+/// Platform property a command sets to [`NETWORK_ENABLED`] to be given network
+/// access. Machines that allow this list the property in their own platform.
+pub const NETWORK_PROPERTY: &str = "network";
+pub const NETWORK_ENABLED: &str = "enabled";
+
+/// Devices bound into every sandbox.
+const DEVICES: [&str; 5] = [
+    "/dev/full",
+    "/dev/null",
+    "/dev/random",
+    "/dev/urandom",
+    "/dev/zero",
+];
+
+/// Where, in the new root, the old one is put before being unmounted.
+const OLD_ROOT: &str = ".old-root";
+
+/// What commands can see of this machine, set from the `[execution.sandbox]`
+/// table of the node config.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SandboxConfig {
+    /// Paths commands can read, but not write, at the same place in the sandbox.
+    /// Those that don't exist on this machine are left out.
+    pub read_only_paths: Vec<PathBuf>,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        SandboxConfig {
+            read_only_paths: ["/bin", "/etc", "/lib", "/lib32", "/lib64", "/sbin", "/usr"]
+                .into_iter()
+                .map(PathBuf::from)
+                .collect(),
+        }
+    }
+}
+
+/// Runs commands in user, mount, PID, IPC and, unless they ask for network
+/// access, network namespaces. No privileges are needed.
+///
+/// Each command is the init process of its PID namespace, so anything it leaves
+/// running is killed along with it.
+#[derive(Debug, Clone)]
+pub struct Sandbox<C> {
+    cas: C,
+    /// Properties of the machine commands run on.
+    platform: Platform,
+    cgroups: CgroupConfig,
+    config: SandboxConfig,
+    cancellations: Cancellations,
+}
+
+impl<C: ContentAddressableStorage> Sandbox<C> {
+    pub fn new(
+        cas: C,
+        platform: Platform,
+        config: SandboxConfig,
+        cgroups: CgroupConfig,
+    ) -> Result<Self, ExecuteError> {
+        Ok(Sandbox {
+            cas,
+            platform,
+            cgroups,
+            config,
+            cancellations: Cancellations::default(),
+        })
+    }
+
+    async fn run(
+        &self,
+        id: Uuid,
+        command: Command,
+        dir: DirectoryLayout,
+        cancelled: oneshot::Receiver<()>,
+    ) -> Result<ExecuteResponse, ExecuteError> {
+        let span = span!(Level::TRACE, "sandbox");
+        let setup_span = span!(parent: &span, Level::TRACE, "setup");
+        let mut metadata = ExecutionMetadata {
+            worker_start: Some(SystemTime::now()),
+            ..Default::default()
+        };
+
+        // The input root is mounted at the same path in the sandbox, so outputs
+        // are collected from where they would be without one.
+        let tmp_dir = TempDir::new("oryx-sandbox")?;
+        let root_path = tmp_dir.path().join("root");
+        std::fs::create_dir(&root_path)?;
+        metadata.input_fetch_start = Some(SystemTime::now());
+        lay_out_inputs(&self.cas, &root_path, dir.entries, &dir.output_paths)
+            .instrument(setup_span.clone())
+            .await?;
+        metadata.input_fetch_completed = Some(SystemTime::now());
+        let network = command.platform.contains(NETWORK_PROPERTY, NETWORK_ENABLED);
+        let current_dir = root_path.join(&command.working_directory);
+        let namespaces = setup_span.in_scope(|| {
+            Namespaces::new(
+                &self.config,
+                tmp_dir.path(),
+                &root_path,
+                &current_dir,
+                network,
+            )
+        })?;
+        let cgroup = Cgroup::create(&self.cgroups, id, &command.platform)?;
+
+        let configure = |process: &mut std::process::Command| {
+            // SAFETY: `enter` only makes system calls, which is all that is safe
+            // between fork and exec.
+            unsafe { process.pre_exec(move || namespaces.enter()) };
+        };
+        let finished = run_process(
+            &span,
+            &self.cas,
+            command,
+            &current_dir,
+            cgroup.as_ref(),
+            configure,
+            cancelled,
+        )
+        .await?;
+        let entries = match finished.exit {
+            Exit::Exited(_) => {
+                let finish_span = span!(parent: &span, Level::TRACE, "collect response");
+                metadata.output_upload_start = Some(SystemTime::now());
+                let entries = collect_outputs(
+                    &self.cas,
+                    &root_path,
+                    dir.output_paths,
+                    dir.output_properties,
+                )
+                .instrument(finish_span)
+                .await?;
+                metadata.output_upload_completed = Some(SystemTime::now());
+                entries
+            }
+            _ => vec![],
+        };
+        finished.into_result(entries, metadata)
+    }
+}
+
+#[async_trait]
+impl<C: ContentAddressableStorage> ExecutionBackend for Sandbox<C> {
+    async fn run_command(
+        &self,
+        id: Uuid,
+        command: Command,
+        dir: DirectoryLayout,
+    ) -> Result<ExecuteResponse, ExecuteError> {
+        let cancelled = self.cancellations.start(id);
+        let result = self.run(id, command, dir, cancelled).await;
+        self.cancellations.finish(id);
+        result
+    }
+
+    async fn cancel(&self, id: Uuid) -> Result<(), ExecuteError> {
+        self.cancellations.cancel(id);
+        Ok(())
+    }
+
+    fn supports(&self, platform: &Platform) -> bool {
+        self.platform.satisfies(platform)
+    }
+}
+
+/// Everything needed to move a process into its sandbox, prepared before it is
+/// forked, after which it mustn't allocate.
+struct Namespaces {
+    network: bool,
+    uid_map: String,
+    gid_map: String,
+    /// Mounts making up the sandbox, in order, starting with its root.
+    mounts: Vec<Mount>,
+    new_root: CString,
+    old_root: CString,
+    /// Where the old root is once the new one is in place.
+    old_root_inside: CString,
+    /// Flags the new root is remounted read-only with.
+    root_flags: c_ulong,
+    current_dir: CString,
+}
+
+struct Mount {
+    source: CString,
+    target: CString,
+    fstype: Option<CString>,
+    flags: c_ulong,
+    /// Flags to remount with once mounted, e.g. to make a bind mount read-only.
+    remount: Option<c_ulong>,
+}
+
+impl Namespaces {
+    /// Lay out a new root in `tmp_dir`, with the input root at `root_path`
+    /// mounted at the same path in it, for a command started in `current_dir`.
+    fn new(
+        config: &SandboxConfig,
+        tmp_dir: &Path,
+        root_path: &Path,
+        current_dir: &Path,
+        network: bool,
+    ) -> io::Result<Self> {
+        let new_root = tmp_dir.join("sandbox");
+        let inside = |path: &Path| new_root.join(path.strip_prefix("/").unwrap_or(path));
+        std::fs::create_dir(&new_root)?;
+        std::fs::create_dir(inside(Path::new(OLD_ROOT)))?;
+        let mut mounts = vec![Mount::bind(&new_root, &new_root)?];
+
+        for path in &config.read_only_paths {
+            let Ok(metadata) = std::fs::symlink_metadata(path) else {
+                continue;
+            };
+            let target = inside(path);
+            if let Some(prefix) = target.parent() {
+                std::fs::create_dir_all(prefix)?;
+            }
+            if metadata.is_symlink() {
+                // E.g. /bin on systems that merged it into /usr.
+                std::os::unix::fs::symlink(std::fs::read_link(path)?, &target)?;
+                continue;
+            }
+            if metadata.is_dir() {
+                std::fs::create_dir_all(&target)?;
+            } else {
+                std::fs::File::create(&target)?;
+            }
+            mounts.push(Mount::read_only(path, &target)?);
+        }
+
+        // The rest of the new root is read-only, but /tmp is writable.
+        let tmp = inside(Path::new("/tmp"));
+        std::fs::create_dir_all(&tmp)?;
+        mounts.push(Mount::bind(&tmp, &tmp)?);
+
+        let exec_root = inside(root_path);
+        std::fs::create_dir_all(&exec_root)?;
+        mounts.push(Mount::bind(root_path, &exec_root)?);
+
+        // /proc shows only the processes in the new PID namespace.
+        let proc = inside(Path::new("/proc"));
+        std::fs::create_dir_all(&proc)?;
+        mounts.push(Mount {
+            source: cstring("proc")?,
+            target: cstring(&proc)?,
+            fstype: Some(cstring("proc")?),
+            flags: libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+            remount: None,
+        });
+
+        let dev = inside(Path::new("/dev"));
+        std::fs::create_dir_all(&dev)?;
+        for device in DEVICES.map(Path::new).into_iter().filter(|d| d.exists()) {
+            let target = inside(device);
+            std::fs::File::create(&target)?;
+            mounts.push(Mount::bind(device, &target)?);
+        }
+        std::os::unix::fs::symlink("/proc/self/fd", dev.join("fd"))?;
+        for (i, name) in ["stdin", "stdout", "stderr"].into_iter().enumerate() {
+            std::os::unix::fs::symlink(format!("/proc/self/fd/{i}"), dev.join(name))?;
+        }
+
+        // SAFETY: neither call can fail or has any preconditions.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        Ok(Namespaces {
+            network,
+            uid_map: format!("{uid} {uid} 1"),
+            gid_map: format!("{gid} {gid} 1"),
+            root_flags: libc::MS_BIND
+                | libc::MS_REMOUNT
+                | libc::MS_RDONLY
+                | mount_flags(&new_root)?,
+            old_root: cstring(inside(Path::new(OLD_ROOT)))?,
+            old_root_inside: cstring(Path::new("/").join(OLD_ROOT))?,
+            new_root: cstring(&new_root)?,
+            mounts,
+            current_dir: cstring(current_dir)?,
+        })
+    }
+
+    /// Move the calling process into the sandbox. Runs between fork and exec.
+    fn enter(&self) -> io::Result<()> {
+        // SAFETY: only makes system calls, with pointers to memory owned by self
+        // or to static strings.
+        unsafe {
+            let mut flags =
+                libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWPID | libc::CLONE_NEWIPC;
+            if !self.network {
+                flags |= libc::CLONE_NEWNET;
+            }
+            check(libc::unshare(flags))?;
+
+            // Keep the same user and group in the sandbox, so outputs are owned
+            // by whoever runs the node.
+            write_file(b"/proc/self/setgroups\0", b"deny")?;
+            write_file(b"/proc/self/uid_map\0", self.uid_map.as_bytes())?;
+            write_file(b"/proc/self/gid_map\0", self.gid_map.as_bytes())?;
+
+            // Only children join the new PID namespace, so the command runs in a
+            // child, which is its init, while this process waits to pass on how
+            // it exits.
+            let pid = check(libc::fork())?;
+            if pid != 0 {
+                wait_for_init(pid);
+            }
+            // Take the whole namespace down if the waiting process is killed.
+            check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+
+            if !self.network {
+                bring_up_loopback()?;
+            }
+
+            check(libc::mount(
+                ptr::null(),
+                c"/".as_ptr(),
+                ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+                ptr::null(),
+            ))?;
+            for mount in &self.mounts {
+                mount.mount()?;
+            }
+            check(libc::syscall(
+                libc::SYS_pivot_root,
+                self.new_root.as_ptr(),
+                self.old_root.as_ptr(),
+            ) as c_int)?;
+            check(libc::chdir(c"/".as_ptr()))?;
+            check(libc::umount2(
+                self.old_root_inside.as_ptr(),
+                libc::MNT_DETACH,
+            ))?;
+            check(libc::rmdir(self.old_root_inside.as_ptr()))?;
+            check(libc::mount(
+                ptr::null(),
+                c"/".as_ptr(),
+                ptr::null(),
+                self.root_flags,
+                ptr::null(),
+            ))?;
+            check(libc::chdir(self.current_dir.as_ptr()))?;
+        }
+        Ok(())
+    }
+}
+
+impl Mount {
+    fn bind(source: &Path, target: &Path) -> io::Result<Self> {
+        Ok(Mount {
+            source: cstring(source)?,
+            target: cstring(target)?,
+            fstype: None,
+            flags: libc::MS_BIND | libc::MS_REC,
+            remount: None,
+        })
+    }
+
+    fn read_only(source: &Path, target: &Path) -> io::Result<Self> {
+        // Flags of the original mount can't be cleared from inside a user
+        // namespace, so they are kept.
+        let flags = libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | mount_flags(source)?;
+        Ok(Mount {
+            remount: Some(flags),
+            ..Mount::bind(source, target)?
+        })
+    }
+
+    /// SAFETY: only to be called while entering the sandbox.
+    unsafe fn mount(&self) -> io::Result<()> {
+        let fstype = self.fstype.as_ref().map_or(ptr::null(), |f| f.as_ptr());
+        check(libc::mount(
+            self.source.as_ptr(),
+            self.target.as_ptr(),
+            fstype,
+            self.flags,
+            ptr::null(),
+        ))?;
+        if let Some(flags) = self.remount {
+            check(libc::mount(
+                ptr::null(),
+                self.target.as_ptr(),
+                ptr::null(),
+                flags,
+                ptr::null(),
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+/// The flags `path` is mounted with that a bind mount of it must keep.
+fn mount_flags(path: &Path) -> io::Result<c_ulong> {
+    let path = cstring(path)?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is a valid string, and `stat` is only read once written.
+    let stat = unsafe {
+        check(libc::statvfs(path.as_ptr(), stat.as_mut_ptr()))?;
+        stat.assume_init()
+    };
+    Ok([
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ]
+    .into_iter()
+    .filter(|(st, _)| stat.f_flag & st != 0)
+    .fold(0, |flags, (_, ms)| flags | ms))
+}
+
+/// Wait for the command to exit, then exit the same way.
+///
+/// SAFETY: only to be called while entering the sandbox.
+unsafe fn wait_for_init(pid: pid_t) -> ! {
+    // Hold nothing open the node waits on being closed, such as the pipe the
+    // command's failure to start is reported through.
+    if libc::syscall(libc::SYS_close_range, 3, c_uint::MAX, 0) == -1 {
+        for fd in 3..1024 {
+            libc::close(fd);
+        }
+    }
+    let mut status = 0;
+    while libc::waitpid(pid, &mut status, 0) == -1 {
+        if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            libc::_exit(1);
+        }
+    }
+    if libc::WIFSIGNALED(status) {
+        let signal = libc::WTERMSIG(status);
+        libc::signal(signal, libc::SIG_DFL);
+        libc::kill(libc::getpid(), signal);
+    }
+    libc::_exit(libc::WEXITSTATUS(status))
+}
+
+/// Bring up the loopback interface of a new network namespace, which starts
+/// out down.
+///
+/// SAFETY: only to be called while entering the sandbox.
+unsafe fn bring_up_loopback() -> io::Result<()> {
+    let socket = check(libc::socket(
+        libc::AF_INET,
+        libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+        0,
+    ))?;
+    let mut request: libc::ifreq = std::mem::zeroed();
+    for (c, b) in request.ifr_name.iter_mut().zip(b"lo") {
+        *c = *b as libc::c_char;
+    }
+    let result = check(libc::ioctl(socket, libc::SIOCGIFFLAGS, &mut request)).and_then(|_| {
+        request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+        check(libc::ioctl(socket, libc::SIOCSIFFLAGS, &request))
+    });
+    libc::close(socket);
+    result.map(drop)
+}
@@ -0,0 +1,152 @@
+//! Limits how many actions run at once, queueing the rest.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::{event, Level, Span};
+
+/// An action waiting for a slot.
+struct Waiter {
+    id: u64,
+    /// Smaller numbers are run first.
+    priority: i32,
+    queued_at: Instant,
+    wake: oneshot::Sender<()>,
+}
+
+struct State {
+    /// Slots not held by a running action.
+    free: usize,
+    /// Actions waiting for a slot, in the order they asked for one.
+    queue: VecDeque<Waiter>,
+    next_id: u64,
+}
+
+/// Hands out a fixed number of slots to run actions in, most important first.
+#[derive(Clone)]
+pub(crate) struct Scheduler {
+    state: Arc<Mutex<State>>,
+    /// Time spent waiting that raises an action's priority by one.
+    aging: Duration,
+}
+
+/// Permission to run an action, handed on to the next waiter when dropped.
+pub(crate) struct Slot {
+    scheduler: Scheduler,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.scheduler.release();
+    }
+}
+
+/// Takes an action back out of the queue if it stops waiting for a slot.
+struct Waiting<'a> {
+    scheduler: &'a Scheduler,
+    id: u64,
+    granted: bool,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if self.granted {
+            return;
+        }
+        let queued = {
+            let mut state = self.scheduler.state.lock().unwrap();
+            let position = state.queue.iter().position(|waiter| waiter.id == self.id);
+            position.and_then(|position| state.queue.remove(position))
+        };
+        // Already handed a slot that nobody is going to use.
+        if queued.is_none() {
+            self.scheduler.release();
+        }
+    }
+}
+
+impl Scheduler {
+    pub(crate) fn new(slots: usize, aging: Duration) -> Self {
+        Scheduler {
+            state: Arc::new(Mutex::new(State {
+                // With no slots at all nothing would ever run.
+                free: slots.max(1),
+                queue: VecDeque::new(),
+                next_id: 0,
+            })),
+            aging,
+        }
+    }
+
+    /// Wait for a free slot. Slots go to the action with the smallest `priority`,
+    /// less the time it has been waiting in units of the aging interval, so low
+    /// priority actions still get to run eventually. Ties go to whoever asked first.
+    ///
+    /// Records the depth of the queue on the current span's `queue_depth` field.
+    pub(crate) async fn acquire(&self, priority: i32) -> Slot {
+        let (id, woken) = {
+            let mut state = self.state.lock().unwrap();
+            if state.free > 0 && state.queue.is_empty() {
+                state.free -= 1;
+                return Slot {
+                    scheduler: self.clone(),
+                };
+            }
+            let id = state.next_id;
+            state.next_id += 1;
+            let (wake, woken) = oneshot::channel();
+            state.queue.push_back(Waiter {
+                id,
+                priority,
+                queued_at: Instant::now(),
+                wake,
+            });
+
+            let queue_depth = state.queue.len();
+            Span::current().record("queue_depth", queue_depth);
+            event!(Level::DEBUG, queue_depth, "action queued");
+            (id, woken)
+        };
+
+        let mut waiting = Waiting {
+            scheduler: self,
+            id,
+            granted: false,
+        };
+        // The waker is only dropped without sending once we stopped waiting.
+        let _ = woken.await;
+        waiting.granted = true;
+        Slot {
+            scheduler: self.clone(),
+        }
+    }
+
+    /// Priority of a waiting action once aged, smaller is more important.
+    fn effective_priority(&self, waiter: &Waiter) -> i64 {
+        let waited = waiter.queued_at.elapsed().as_millis();
+        let aged = waited / self.aging.as_millis().max(1);
+        i64::from(waiter.priority).saturating_sub(aged.try_into().unwrap_or(i64::MAX))
+    }
+
+    /// Give a slot to the most important action in the queue, or back to the pool.
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            // The queue is in arrival order, so the first minimum is the oldest.
+            let next = state
+                .queue
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, waiter)| self.effective_priority(waiter))
+                .map(|(position, _)| position);
+            let Some(waiter) = next.and_then(|position| state.queue.remove(position)) else {
+                break;
+            };
+            if waiter.wake.send(()).is_ok() {
+                return;
+            }
+        }
+        state.free += 1;
+    }
+}
@@ -30,7 +30,9 @@ fn setup_oryx_benchmark() -> (Runtime, Channel) {
             String::from(""),
             node_lib::Connection::Uds(stream),
             node_lib::StorageBackend::InMemory,
+            None,
             node_lib::ExecutionEngine::Insecure,
+            node_lib::EngineConfig::default(),
         )
         .await;
         assert!(result.is_ok());
@@ -25,7 +25,11 @@ pub struct NodeConfig {
     instance: String,
     address: std::net::SocketAddr,
     storage_backend: node_lib::StorageBackend,
+    /// Directory blobs are kept in when using the disk storage backend.
+    storage_path: Option<PathBuf>,
     execution_engine: node_lib::ExecutionEngine,
+    #[serde(default)]
+    execution: node_lib::EngineConfig,
     trace: bool,
 }
 
@@ -67,14 +71,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         config.instance,
         node_lib::Connection::Tcp(config.address),
         config.storage_backend,
+        config.storage_path,
         config.execution_engine,
+        config.execution,
     );
 
-    tokio::select! {
-        _ = signal::ctrl_c() => (),
-        _ = oryx_fut => unreachable!(),
-    }
+    // The node only stops on its own if it fails, e.g. for being misconfigured.
+    let result = tokio::select! {
+        _ = signal::ctrl_c() => Ok(()),
+        res = oryx_fut => res,
+    };
 
     global::shutdown_tracer_provider();
-    Ok(())
+    result
 }
@@ -4,8 +4,8 @@ use futures::future::BoxFuture;
 use prost::Message;
 use protos::{
     longrunning::operation::Result::Response,
-    re::batch_update_blobs_request::Request as BlobRequest, ContentAddressableStorageClient,
-    ExecutionClient,
+    re::batch_update_blobs_request::Request as BlobRequest, ByteStreamClient,
+    ContentAddressableStorageClient, ExecutionClient,
 };
 use sha2::{Digest as _, Sha256};
 use std::collections::VecDeque;
@@ -13,12 +13,17 @@ use std::{
     collections::HashMap,
     path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
 use tokio_stream::StreamExt;
-use tonic::{transport::Channel, Request};
+use tonic::{transport::Channel, Request, Streaming};
+use uuid::Uuid;
+
+/// Blobs bigger than this are streamed over ByteStream rather than batched.
+const MAX_BATCH_BLOB_SIZE: usize = 1024 * 1024;
 
 // Some light typesafety for the various digests.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ActionDigest(pub Digest);
 #[derive(Debug)]
 pub struct CommandDigest(pub Digest);
@@ -30,6 +35,7 @@ pub struct DirectoryDigest(pub Digest);
 pub struct Gemsbok {
     exec: ExecutionClient<Channel>,
     cas: ContentAddressableStorageClient<Channel>,
+    bytestream: ByteStreamClient<Channel>,
 }
 
 impl Gemsbok {
@@ -37,46 +43,60 @@ impl Gemsbok {
         Gemsbok {
             exec: ExecutionClient::new(channel.clone()),
             cas: ContentAddressableStorageClient::new(channel.clone()),
+            bytestream: ByteStreamClient::new(channel.clone()),
         }
     }
 
     /// Create a Directory message and upload to CAS returning the digest.
     pub async fn add_directory(&mut self, root: Directory) -> Result<DirectoryDigest, Error> {
-        let mut files = vec![];
-        let mut symlinks = vec![];
-        let mut directories = vec![];
+        Ok(DirectoryDigest(self.upload_directory(root).await?))
+    }
 
-        for dir in root.dirs {
-            todo!();
-        }
+    /// Upload a Directory message, after those of the directories in it.
+    fn upload_directory(&mut self, root: Directory) -> BoxFuture<'_, Result<Digest, Error>> {
+        Box::pin(async move {
+            let mut files = vec![];
+            let mut symlinks = vec![];
+            let mut directories = vec![];
+
+            let mut dirs = root.dirs.into_iter().collect::<Vec<_>>();
+            dirs.sort_by(|a, b| a.0.cmp(&b.0));
+            for (name, dir) in dirs {
+                let dir_digest = self.upload_directory(dir).await?;
+                directories.push(protos::re::DirectoryNode {
+                    name,
+                    digest: Some(dir_digest.into()),
+                });
+            }
 
-        for file in root.files {
-            let file_digest = self.upload_blob(&file.contents).await?;
-            let node = protos::re::FileNode {
-                name: file.name,
-                is_executable: file.executable,
-                digest: Some(file_digest.into()),
-                ..Default::default()
-            };
-            files.push(node);
-        }
+            for file in root.files {
+                let file_digest = self.upload_blob(&file.contents).await?;
+                let node = protos::re::FileNode {
+                    name: file.name,
+                    is_executable: file.executable,
+                    digest: Some(file_digest.into()),
+                    node_properties: file.properties,
+                };
+                files.push(node);
+            }
 
-        for symlink in root.symlinks {
-            let node = protos::re::SymlinkNode {
-                name: symlink.path.as_os_str().to_str().unwrap().to_string(),
-                target: symlink.target.as_os_str().to_str().unwrap().to_string(),
-                ..Default::default()
-            };
-            symlinks.push(node);
-        }
+            for symlink in root.symlinks {
+                let node = protos::re::SymlinkNode {
+                    name: symlink.path.as_os_str().to_str().unwrap().to_string(),
+                    target: symlink.target.as_os_str().to_str().unwrap().to_string(),
+                    ..Default::default()
+                };
+                symlinks.push(node);
+            }
 
-        let root = protos::re::Directory {
-            files,
-            directories,
-            symlinks,
-            node_properties: None,
-        };
-        Ok(DirectoryDigest(self.upload_proto(root).await?))
+            let root = protos::re::Directory {
+                files,
+                directories,
+                symlinks,
+                node_properties: None,
+            };
+            self.upload_proto(root).await
+        })
     }
 
     /// Create a Command message and upload to CAS returning the digest.
@@ -84,10 +104,58 @@ impl Gemsbok {
         &mut self,
         args: &[&str],
         out_paths: &[&str],
+    ) -> Result<CommandDigest, Error> {
+        self.add_command_in("", args, out_paths).await
+    }
+
+    /// Create a Command message listing its outputs the way clients predating
+    /// `output_paths` do, and upload to CAS returning the digest.
+    pub async fn add_legacy_command(
+        &mut self,
+        args: &[&str],
+        out_files: &[&str],
+        out_dirs: &[&str],
+    ) -> Result<CommandDigest, Error> {
+        let cmd = protos::re::Command {
+            arguments: args.iter().map(|a| String::from(*a)).collect(),
+            output_files: out_files.iter().map(|a| String::from(*a)).collect(),
+            output_directories: out_dirs.iter().map(|a| String::from(*a)).collect(),
+            ..Default::default()
+        };
+
+        Ok(CommandDigest(self.upload_proto(cmd).await?))
+    }
+
+    /// Create a Command message asking for the node properties named by
+    /// `properties` of its outputs, and upload to CAS returning the digest.
+    pub async fn add_command_with_properties(
+        &mut self,
+        args: &[&str],
+        out_paths: &[&str],
+        properties: &[&str],
     ) -> Result<CommandDigest, Error> {
         let cmd = protos::re::Command {
             arguments: args.iter().map(|a| String::from(*a)).collect(),
             output_paths: out_paths.iter().map(|a| String::from(*a)).collect(),
+            output_node_properties: properties.iter().map(|a| String::from(*a)).collect(),
+            ..Default::default()
+        };
+
+        Ok(CommandDigest(self.upload_proto(cmd).await?))
+    }
+
+    /// Create a Command message running in `working_directory`, which its output
+    /// paths are relative to, and upload to CAS returning the digest.
+    pub async fn add_command_in(
+        &mut self,
+        working_directory: &str,
+        args: &[&str],
+        out_paths: &[&str],
+    ) -> Result<CommandDigest, Error> {
+        let cmd = protos::re::Command {
+            arguments: args.iter().map(|a| String::from(*a)).collect(),
+            output_paths: out_paths.iter().map(|a| String::from(*a)).collect(),
+            working_directory: String::from(working_directory),
             ..Default::default()
         };
 
@@ -99,10 +167,81 @@ impl Gemsbok {
         &mut self,
         command_digest: CommandDigest,
         input_root_digest: DirectoryDigest,
+    ) -> Result<ActionDigest, Error> {
+        self.upload_action(command_digest, input_root_digest, false, None, None)
+            .await
+    }
+
+    /// Create a Action message that must finish within `timeout` and upload to CAS
+    /// returning the digest.
+    pub async fn add_action_with_timeout(
+        &mut self,
+        command_digest: CommandDigest,
+        input_root_digest: DirectoryDigest,
+        timeout: Duration,
+    ) -> Result<ActionDigest, Error> {
+        self.upload_action(
+            command_digest,
+            input_root_digest,
+            false,
+            Some(timeout),
+            None,
+        )
+        .await
+    }
+
+    /// Create a Action message whose result must never be cached and upload to CAS
+    /// returning the digest.
+    pub async fn add_uncacheable_action(
+        &mut self,
+        command_digest: CommandDigest,
+        input_root_digest: DirectoryDigest,
+    ) -> Result<ActionDigest, Error> {
+        self.upload_action(command_digest, input_root_digest, true, None, None)
+            .await
+    }
+
+    /// Create a Action message that must run on a machine with the given platform
+    /// properties and upload to CAS returning the digest.
+    pub async fn add_action_with_platform(
+        &mut self,
+        command_digest: CommandDigest,
+        input_root_digest: DirectoryDigest,
+        properties: &[(&str, &str)],
+    ) -> Result<ActionDigest, Error> {
+        let platform = protos::re::Platform {
+            properties: properties
+                .iter()
+                .map(|(name, value)| protos::re::platform::Property {
+                    name: String::from(*name),
+                    value: String::from(*value),
+                })
+                .collect(),
+        };
+        self.upload_action(
+            command_digest,
+            input_root_digest,
+            false,
+            None,
+            Some(platform),
+        )
+        .await
+    }
+
+    async fn upload_action(
+        &mut self,
+        command_digest: CommandDigest,
+        input_root_digest: DirectoryDigest,
+        do_not_cache: bool,
+        timeout: Option<Duration>,
+        platform: Option<protos::re::Platform>,
     ) -> Result<ActionDigest, Error> {
         let action = protos::re::Action {
             command_digest: Some(command_digest.0.into()),
             input_root_digest: Some(input_root_digest.0.into()),
+            do_not_cache,
+            timeout: timeout.map(prost_types::Duration::try_from).transpose()?,
+            platform,
             ..Default::default()
         };
 
@@ -111,6 +250,23 @@ impl Gemsbok {
 
     /// Execute a action.
     pub async fn execute(&mut self, action_digest: ActionDigest) -> Result<ActionResult, Error> {
+        self.execute_request(action_digest, false).await
+    }
+
+    /// Execute a action, ignoring any result the server has cached for it.
+    pub async fn execute_skip_cache(
+        &mut self,
+        action_digest: ActionDigest,
+    ) -> Result<ActionResult, Error> {
+        self.execute_request(action_digest, true).await
+    }
+
+    /// Execute a action, returning the server's final response whether or not the
+    /// action succeeded.
+    pub async fn execute_response(
+        &mut self,
+        action_digest: ActionDigest,
+    ) -> Result<protos::re::ExecuteResponse, Error> {
         let mut response = self
             .exec
             .execute(Request::new(protos::re::ExecuteRequest {
@@ -122,6 +278,96 @@ impl Gemsbok {
             }))
             .await?
             .into_inner();
+        while let Some(op) = response.next().await {
+            let op = op?;
+            if !op.done {
+                continue;
+            }
+            let Some(Response(result)) = op.result else {
+                return Err(anyhow::anyhow!("Operation finished without a response"));
+            };
+            return Ok(Message::decode(result.value.as_slice())?);
+        }
+        Err(anyhow::anyhow!("Gemsbok execute exited uncleanly!"))
+    }
+
+    /// Start executing a action, returning the rest of the operation's updates
+    /// once the server reports its command running, along with the metadata
+    /// naming the streams its output can be followed with.
+    pub async fn execute_until_running(
+        &mut self,
+        action_digest: ActionDigest,
+    ) -> Result<
+        (
+            Streaming<protos::longrunning::Operation>,
+            protos::re::ExecuteOperationMetadata,
+        ),
+        Error,
+    > {
+        let mut response = self
+            .exec
+            .execute(Request::new(protos::re::ExecuteRequest {
+                instance_name: "".to_string(),
+                action_digest: Some(action_digest.0.into()),
+                execution_policy: None,
+                results_cache_policy: None,
+                skip_cache_lookup: false,
+            }))
+            .await?
+            .into_inner();
+        while let Some(op) = response.next().await {
+            let op = op?;
+            if op.done {
+                break;
+            }
+            let Some(metadata) = op.metadata else {
+                continue;
+            };
+            let metadata: protos::re::ExecuteOperationMetadata =
+                Message::decode(metadata.value.as_slice())?;
+            if metadata.stage == protos::re::execution_stage::Value::Executing as i32 {
+                return Ok((response, metadata));
+            }
+        }
+        Err(anyhow::anyhow!("Operation finished without running"))
+    }
+
+    /// Start reading `limit` bytes of a ByteStream resource from `offset`, or
+    /// all of it if `limit` is zero.
+    pub async fn read_stream(
+        &mut self,
+        resource_name: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Streaming<protos::bytestream::ReadResponse>, tonic::Status> {
+        let request = protos::bytestream::ReadRequest {
+            resource_name: resource_name.to_string(),
+            read_offset: offset,
+            read_limit: limit,
+        };
+        Ok(self
+            .bytestream
+            .read(Request::new(request))
+            .await?
+            .into_inner())
+    }
+
+    async fn execute_request(
+        &mut self,
+        action_digest: ActionDigest,
+        skip_cache_lookup: bool,
+    ) -> Result<ActionResult, Error> {
+        let mut response = self
+            .exec
+            .execute(Request::new(protos::re::ExecuteRequest {
+                instance_name: "".to_string(),
+                action_digest: Some(action_digest.0.into()),
+                execution_policy: None,
+                results_cache_policy: None,
+                skip_cache_lookup,
+            }))
+            .await?
+            .into_inner();
         while let Some(op) = response.next().await {
             let op = op.unwrap();
             // If `done` == `false`, neither `error` nor `response` is set.
@@ -138,20 +384,24 @@ impl Gemsbok {
 
             // Should succeed
             assert_eq!(status.code, protos::rpc::Code::Ok.into());
+            let cached = resp.cached_result;
             let resp = resp.result.unwrap();
 
-            // TODO handle digests
-            assert!(resp.stderr_digest.is_none());
-            assert!(resp.stdout_digest.is_none());
-
-            let stderr = std::str::from_utf8(&resp.stderr_raw)?;
-            let stdout = std::str::from_utf8(&resp.stdout_raw)?;
+            // Logs too long to inline are in the CAS.
+            let stderr = match resp.stderr_digest {
+                Some(digest) => self.get_blob(digest.into()).await?,
+                None => resp.stderr_raw,
+            };
+            let stdout = match resp.stdout_digest {
+                Some(digest) => self.get_blob(digest.into()).await?,
+                None => resp.stdout_raw,
+            };
 
             let mut directory = Directory::root();
             for file in resp.output_files {
                 let path = PathBuf::from(file.path);
                 let contents = self.get_blob(file.digest.unwrap().into()).await?;
-                directory.add_path(&path, Some(&contents));
+                directory.add_file(&path, &contents, file.is_executable, file.node_properties);
             }
 
             for symlink in resp.output_symlinks {
@@ -173,8 +423,9 @@ impl Gemsbok {
 
             return Ok(ActionResult {
                 exit_code: resp.exit_code,
-                stderr: stderr.into(),
-                stdout: stdout.into(),
+                cached,
+                stderr,
+                stdout,
                 directory,
             });
         }
@@ -193,11 +444,18 @@ impl Gemsbok {
                 file_path.push(&file_node.name);
                 let digest = file_node.digest.clone().unwrap();
                 let contents = self.get_blob(digest.into()).await?;
-                dir.add_path(&file_path, Some(&contents));
+                dir.add_file(
+                    &file_path,
+                    &contents,
+                    file_node.is_executable,
+                    file_node.node_properties.clone(),
+                );
             }
 
             for symlink in &sub_dir.symlinks {
-                todo!();
+                let mut symlink_path = path.to_path_buf();
+                symlink_path.push(&symlink.name);
+                dir.add_symlink(&symlink_path, Path::new(&symlink.target));
             }
 
             for dir_node in &sub_dir.directories {
@@ -219,6 +477,9 @@ impl Gemsbok {
         let hash_buf = hasher.finalize();
         let hex_hash = base16ct::lower::encode_string(&hash_buf);
         let encoded_digest = Digest::from_str(&format!("{}:{}", hex_hash, encoded.len())).unwrap();
+        if encoded.len() > MAX_BATCH_BLOB_SIZE {
+            return self.write_stream(encoded, encoded_digest).await;
+        }
 
         let mut response_digests: Vec<(Digest, i32)> = self
             .cas
@@ -242,7 +503,46 @@ impl Gemsbok {
         Ok(response_digests[0].0.clone())
     }
 
+    /// Upload a blob too big to batch.
+    async fn write_stream(&mut self, data: Vec<u8>, digest: Digest) -> Result<Digest, Error> {
+        let resource_name = format!(
+            "uploads/{}/blobs/{}/{}",
+            Uuid::new_v4(),
+            digest.hash(),
+            digest.size_bytes()
+        );
+        let requests: Vec<_> = data
+            .chunks(64 * 1024)
+            .scan(0, |offset, chunk| {
+                let request = protos::bytestream::WriteRequest {
+                    resource_name: resource_name.clone(),
+                    write_offset: *offset,
+                    finish_write: *offset + chunk.len() as i64 == data.len() as i64,
+                    data: chunk.to_vec(),
+                };
+                *offset += chunk.len() as i64;
+                Some(request)
+            })
+            .collect();
+        let response = self
+            .bytestream
+            .write(tokio_stream::iter(requests))
+            .await?
+            .into_inner();
+        assert_eq!(response.committed_size, data.len() as i64);
+        Ok(digest)
+    }
+
     async fn get_blob(&mut self, digest: Digest) -> Result<Vec<u8>, Error> {
+        if digest.size_bytes() > MAX_BATCH_BLOB_SIZE as i64 {
+            let name = format!("blobs/{}/{}", digest.hash(), digest.size_bytes());
+            let mut stream = self.read_stream(&name, 0, 0).await?;
+            let mut data = vec![];
+            while let Some(response) = stream.next().await {
+                data.extend(response?.data);
+            }
+            return Ok(data);
+        }
         let mut responses: Vec<(Digest, Vec<u8>)> = self
             .cas
             .batch_read_blobs(Request::new(protos::re::BatchReadBlobsRequest {
@@ -279,6 +579,7 @@ impl Gemsbok {
 #[derive(Debug)]
 pub struct ActionResult {
     pub exit_code: i32,
+    pub cached: bool,
     pub stderr: Vec<u8>,
     pub stdout: Vec<u8>,
     pub directory: Directory,
@@ -289,6 +590,7 @@ pub struct File {
     name: String,
     contents: Vec<u8>,
     executable: bool,
+    properties: Option<protos::re::NodeProperties>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -313,19 +615,44 @@ impl Directory {
         }
     }
 
+    /// Add a symlink at `path` to `target`, which is relative to the directory
+    /// the symlink is in.
     pub fn add_symlink(&mut self, path: &Path, target: &Path) {
-        self.symlinks.push(Symlink {
-            path: path.to_path_buf(),
+        self.parent(path).symlinks.push(Symlink {
+            path: PathBuf::from(path.file_name().unwrap()),
             target: target.to_path_buf(),
         });
     }
 
     pub fn add_entry(&mut self, path: &Path, contents: Option<&[u8]>, executable: bool) {
+        if let Some(contents) = contents {
+            self.add_file(path, contents, executable, None);
+        }
+    }
+
+    /// Add a file at `path` with node properties, such as its mtime or mode.
+    pub fn add_file(
+        &mut self,
+        path: &Path,
+        contents: &[u8],
+        executable: bool,
+        properties: Option<protos::re::NodeProperties>,
+    ) {
+        self.parent(path).files.push(File {
+            name: path.file_name().unwrap().to_str().unwrap().to_string(),
+            contents: contents.to_vec(),
+            executable,
+            properties,
+        });
+    }
+
+    /// The directory `path` is in, created along with the directories above it if
+    /// need be.
+    fn parent(&mut self, path: &Path) -> &mut Directory {
         assert!(path.is_relative());
         let mut components = path.components().collect::<VecDeque<_>>();
 
-        let mut dirs = &mut self.dirs;
-        let mut files = &mut self.files;
+        let mut dir = self;
         while components.len() > 1 {
             let segment = components
                 .pop_front()
@@ -334,18 +661,9 @@ impl Directory {
                 .to_str()
                 .unwrap()
                 .to_string();
-            let dir = dirs.entry(segment.clone()).or_default();
-            dirs = &mut dir.dirs;
-            files = &mut dir.files;
-        }
-
-        if let Some(contents) = contents {
-            files.push(File {
-                name: path.file_name().unwrap().to_str().unwrap().to_string(),
-                contents: contents.to_vec(),
-                executable,
-            });
+            dir = dir.dirs.entry(segment).or_default();
         }
+        dir
     }
 
     pub fn add_exec(&mut self, path: &Path, contents: Option<&[u8]>) {
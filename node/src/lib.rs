@@ -1,17 +1,28 @@
 use opentelemetry::propagation::Extractor;
 use serde::Deserialize;
+use std::path::PathBuf;
 use tonic::transport::server::Router;
 use tonic::transport::Server;
 
+mod operations;
 mod services;
+pub mod worker;
 
+use execution_engine::LiveLogs;
+use operations::OperationRegistry;
 use protos::*;
 use services::*;
 
+pub use execution_engine::cgroup::CgroupConfig;
+pub use execution_engine::{EngineConfig, Platform};
+
 #[derive(Debug, Deserialize)]
 pub enum StorageBackend {
     #[serde(alias = "memory")]
     InMemory,
+    /// Blobs persisted under the configured storage path.
+    #[serde(alias = "disk")]
+    OnDisk,
 }
 
 #[derive(Debug, Deserialize)]
@@ -20,6 +31,12 @@ pub enum ExecutionEngine {
     Insecure,
     #[serde(rename = "hermetic")]
     Hermetic,
+    /// Actions run in Linux namespaces, isolated from the rest of the machine.
+    #[serde(rename = "sandbox")]
+    Sandbox,
+    /// Actions are leased to workers connected over the Workers service.
+    #[serde(rename = "remote")]
+    Remote,
 }
 
 pub enum Connection {
@@ -48,27 +65,96 @@ impl<'a> Extractor for MetadataMap<'a> {
     }
 }
 
-fn add_exec_service<C: cas::ContentAddressableStorage>(
+/// Everything the execution service shares with the other services of a node.
+struct ExecServiceState<C, A> {
+    instance: String,
+    cas: C,
+    action_cache: A,
+    registry: OperationRegistry,
+    logs: LiveLogs,
+}
+
+fn add_exec_service<C: cas::ContentAddressableStorage, A: cas::ActionCache>(
     s: Router,
-    instance: &str,
+    state: ExecServiceState<C, A>,
     execution_engine: ExecutionEngine,
-    cas: C,
+    engine_config: EngineConfig,
 ) -> Result<Router, Box<dyn std::error::Error>> {
+    let ExecServiceState {
+        instance,
+        cas,
+        action_cache,
+        registry,
+        logs,
+    } = state;
     Ok(match execution_engine {
         ExecutionEngine::Insecure => {
-            let backend = execution_engine::insecure::Insecure::new(cas.clone())?;
-            let execution_engine = execution_engine::ExecutionEngine::new(backend);
-            let server =
-                ExecutionServer::new(ExecutionService::new(&instance, cas, execution_engine));
+            let backend = execution_engine::insecure::Insecure::new(
+                cas.clone(),
+                engine_config.platform.clone(),
+                engine_config.cgroup.clone(),
+            )?;
+            let execution_engine =
+                execution_engine::ExecutionEngine::new(backend, action_cache, engine_config, logs);
+            let server = ExecutionServer::new(ExecutionService::new(
+                &instance,
+                cas,
+                execution_engine,
+                registry,
+            ));
             s.add_service(server)
         }
         ExecutionEngine::Hermetic => {
-            let backend = execution_engine::hermetic::Hermetic::new(cas.clone())?;
-            let execution_engine = execution_engine::ExecutionEngine::new(backend);
-            let server =
-                ExecutionServer::new(ExecutionService::new(&instance, cas, execution_engine));
+            let backend = execution_engine::hermetic::Hermetic::new(
+                cas.clone(),
+                engine_config.platform.clone(),
+                engine_config.cgroup.clone(),
+            )?;
+            let execution_engine =
+                execution_engine::ExecutionEngine::new(backend, action_cache, engine_config, logs);
+            let server = ExecutionServer::new(ExecutionService::new(
+                &instance,
+                cas,
+                execution_engine,
+                registry,
+            ));
+            s.add_service(server)
+        }
+        ExecutionEngine::Sandbox => {
+            let backend = execution_engine::sandbox::Sandbox::new(
+                cas.clone(),
+                engine_config.platform.clone(),
+                engine_config.sandbox.clone(),
+                engine_config.cgroup.clone(),
+            )?;
+            let execution_engine =
+                execution_engine::ExecutionEngine::new(backend, action_cache, engine_config, logs);
+            let server = ExecutionServer::new(ExecutionService::new(
+                &instance,
+                cas,
+                execution_engine,
+                registry,
+            ));
             s.add_service(server)
         }
+        ExecutionEngine::Remote => {
+            let token = engine_config
+                .worker_token
+                .clone()
+                .ok_or("worker_token is required by the remote execution engine")?;
+            let backend =
+                execution_engine::remote::Remote::new(engine_config.worker_platforms.clone());
+            let workers = WorkersServer::new(WorkersService::new(backend.clone(), token));
+            let execution_engine =
+                execution_engine::ExecutionEngine::new(backend, action_cache, engine_config, logs);
+            let server = ExecutionServer::new(ExecutionService::new(
+                &instance,
+                cas,
+                execution_engine,
+                registry,
+            ));
+            s.add_service(server).add_service(workers)
+        }
     })
 }
 
@@ -76,22 +162,79 @@ pub async fn start_oryx(
     instance: String,
     conn: Connection,
     storage_backend: StorageBackend,
+    storage_path: Option<PathBuf>,
     execution_engine: ExecutionEngine,
+    engine_config: EngineConfig,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let cas = match storage_backend {
-        StorageBackend::InMemory => cas::InMemory::default(),
-    };
+    match storage_backend {
+        StorageBackend::InMemory => {
+            let cas = cas::InMemory::default();
+            let action_cache = cas::InMemoryActionCache::default();
+            serve(
+                instance,
+                conn,
+                cas,
+                action_cache,
+                execution_engine,
+                engine_config,
+            )
+            .await
+        }
+        StorageBackend::OnDisk => {
+            let storage_path =
+                storage_path.ok_or("storage_path is required by the disk storage backend")?;
+            let cas = cas::OnDisk::new(&storage_path)?;
+            let action_cache = cas::OnDiskActionCache::new(storage_path.join("action_cache"))?;
+            serve(
+                instance,
+                conn,
+                cas,
+                action_cache,
+                execution_engine,
+                engine_config,
+            )
+            .await
+        }
+    }
+}
 
+async fn serve<C: cas::ContentAddressableStorage, A: cas::ActionCache>(
+    instance: String,
+    conn: Connection,
+    cas: C,
+    action_cache: A,
+    execution_engine: ExecutionEngine,
+    engine_config: EngineConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let registry = OperationRegistry::new();
+    let logs = LiveLogs::new();
     let server = Server::builder()
         .trace_fn(|event| tracing::info_span!("gRPC Request", api = event.uri().path()))
-        .add_service(ActionCacheServer::new(ActionCacheService::default()))
-        .add_service(ByteStreamServer::new(BytestreamService::new(cas.clone())))
-        .add_service(CapabilitiesServer::new(CapabilitiesService::default()))
+        .add_service(ActionCacheServer::new(ActionCacheService::new(
+            action_cache.clone(),
+            cas.clone(),
+        )))
+        .add_service(ByteStreamServer::new(BytestreamService::new(
+            cas.clone(),
+            logs.clone(),
+        )))
+        .add_service(CapabilitiesServer::new(CapabilitiesService::new(
+            engine_config.clone(),
+        )))
         .add_service(ContentAddressableStorageServer::new(
             ContentStorageService::new(cas.clone()),
         ))
-        .add_service(OperationsServer::new(OperationsService::new()));
-    let server = add_exec_service(server, &instance, execution_engine, cas)?;
+        .add_service(OperationsServer::new(OperationsService::new(
+            registry.clone(),
+        )));
+    let state = ExecServiceState {
+        instance,
+        cas,
+        action_cache,
+        registry,
+        logs,
+    };
+    let server = add_exec_service(server, state, execution_engine, engine_config)?;
 
     let conn = async {
         match conn {
@@ -0,0 +1,204 @@
+//! Registry of the long-running operations created by `Execute` calls.
+
+use common::Digest;
+use protos::longrunning::Operation;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::AbortHandle;
+
+/// How long a completed operation can still be queried before it is forgotten.
+const COMPLETED_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+struct Registered {
+    /// Latest state of the operation, broadcast to anyone waiting on it.
+    updates: watch::Sender<Operation>,
+    /// Action being executed, while the operation is still in flight.
+    action_digest: Option<Digest>,
+    /// Task driving the execution, while the operation is still in flight.
+    execution: Option<AbortHandle>,
+    completed_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Operations {
+    by_name: HashMap<String, Registered>,
+    /// Name of the in-flight operation executing each action.
+    in_flight: HashMap<Digest, String>,
+}
+
+impl Operations {
+    fn complete(&mut self, name: &str) {
+        if let Some(registered) = self.by_name.get_mut(name) {
+            registered.completed_at = Some(Instant::now());
+            if let Some(action_digest) = registered.action_digest.take() {
+                self.in_flight.remove(&action_digest);
+            }
+            // Stops the execution if the operation finished early, e.g. by being
+            // cancelled.
+            if let Some(execution) = registered.execution.take() {
+                execution.abort();
+            }
+        }
+    }
+
+    fn insert(
+        &mut self,
+        op: Operation,
+        action_digest: Option<Digest>,
+        execution: Option<AbortHandle>,
+    ) -> watch::Receiver<Operation> {
+        self.by_name
+            .retain(|_, registered| match registered.completed_at {
+                Some(completed_at) => completed_at.elapsed() < COMPLETED_RETENTION,
+                None => true,
+            });
+
+        let name = op.name.clone();
+        let done = op.done;
+        let (updates, receiver) = watch::channel(op);
+        if let Some(action_digest) = &action_digest {
+            self.in_flight.insert(action_digest.clone(), name.clone());
+        }
+        self.by_name.insert(
+            name.clone(),
+            Registered {
+                updates,
+                action_digest,
+                execution,
+                completed_at: None,
+            },
+        );
+        if done {
+            self.complete(&name);
+        }
+        receiver
+    }
+}
+
+/// What came of removing an operation.
+#[derive(Debug, PartialEq)]
+pub enum Removal {
+    Removed,
+    Unknown,
+    /// The operation isn't done, so it was kept.
+    InFlight,
+}
+
+/// Shared record of the operations a node knows about, keyed by the
+/// `operations/{uuid}` name handed out to clients.
+#[derive(Clone, Default)]
+pub struct OperationRegistry {
+    operations: Arc<Mutex<Operations>>,
+}
+
+impl OperationRegistry {
+    pub fn new() -> Self {
+        OperationRegistry::default()
+    }
+
+    /// Register a new operation.
+    pub fn insert(&self, op: Operation) -> watch::Receiver<Operation> {
+        let mut operations = self.operations.lock().unwrap();
+        operations.insert(op, None, None)
+    }
+
+    /// Follow the in-flight operation executing `action_digest`, or register the
+    /// operation returned by `start` if there is none.
+    ///
+    /// This lets identical actions requested at the same time share one execution.
+    /// `start` also returns the task driving the execution, which is aborted if
+    /// the operation finishes before it does.
+    pub fn join_or_start<E>(
+        &self,
+        action_digest: Option<Digest>,
+        start: impl FnOnce() -> Result<(Operation, AbortHandle), E>,
+    ) -> Result<watch::Receiver<Operation>, E> {
+        let mut operations = self.operations.lock().unwrap();
+        let in_flight = action_digest
+            .as_ref()
+            .and_then(|action_digest| operations.in_flight.get(action_digest))
+            .and_then(|name| operations.by_name.get(name));
+        if let Some(registered) = in_flight {
+            return Ok(registered.updates.subscribe());
+        }
+        let (op, execution) = start()?;
+        Ok(operations.insert(op, action_digest, Some(execution)))
+    }
+
+    /// Record the latest state of an operation.
+    ///
+    /// Returns the state clients should be shown. Once an operation is done
+    /// (for example because it was cancelled) later updates are ignored and the
+    /// final state is returned instead.
+    pub fn update(&self, op: Operation) -> Operation {
+        let mut operations = self.operations.lock().unwrap();
+        let Some(registered) = operations.by_name.get_mut(&op.name) else {
+            // The operation was deleted, nobody is left to tell.
+            return op;
+        };
+        if registered.completed_at.is_some() {
+            return registered.updates.borrow().clone();
+        }
+        registered.updates.send_replace(op.clone());
+        if op.done {
+            operations.complete(&op.name);
+        }
+        op
+    }
+
+    pub fn get(&self, name: &str) -> Option<Operation> {
+        let operations = self.operations.lock().unwrap();
+        operations
+            .by_name
+            .get(name)
+            .map(|registered| registered.updates.borrow().clone())
+    }
+
+    /// Every known operation, ordered by name.
+    pub fn list(&self) -> Vec<Operation> {
+        let operations = self.operations.lock().unwrap();
+        let mut list: Vec<_> = operations
+            .by_name
+            .values()
+            .map(|registered| registered.updates.borrow().clone())
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    /// Forget a completed operation.
+    ///
+    /// Operations still in flight are kept, as the clients following them are
+    /// owed their final state.
+    pub fn remove(&self, name: &str) -> Removal {
+        let mut operations = self.operations.lock().unwrap();
+        match operations.by_name.get(name) {
+            None => Removal::Unknown,
+            Some(registered) if registered.completed_at.is_none() => Removal::InFlight,
+            Some(_) => {
+                operations.by_name.remove(name);
+                Removal::Removed
+            }
+        }
+    }
+
+    /// Number of clients following the state of an operation.
+    pub fn followers(&self, name: &str) -> Option<usize> {
+        let operations = self.operations.lock().unwrap();
+        operations
+            .by_name
+            .get(name)
+            .map(|registered| registered.updates.receiver_count())
+    }
+
+    /// Follow the state of an operation as it changes.
+    pub fn subscribe(&self, name: &str) -> Option<watch::Receiver<Operation>> {
+        let operations = self.operations.lock().unwrap();
+        operations
+            .by_name
+            .get(name)
+            .map(|registered| registered.updates.subscribe())
+    }
+}
@@ -1,21 +1,97 @@
+use cas::{ActionCache, CasError, ContentAddressableStorage};
+use common::Digest;
 use tonic::{Request, Response, Status};
+use tracing::{event, Level};
 
-#[derive(Debug, Default)]
-pub struct ActionCacheService {}
+#[derive(Debug)]
+pub struct ActionCacheService<A, C> {
+    action_cache: A,
+    cas: C,
+}
+
+impl<A, C> ActionCacheService<A, C> {
+    pub fn new(action_cache: A, cas: C) -> Self {
+        ActionCacheService { action_cache, cas }
+    }
+}
+
+/// Every blob an `ActionResult` references must still be in the CAS for the
+/// result to be served, otherwise the client would be unable to fetch outputs.
+pub(crate) async fn outputs_available<C: ContentAddressableStorage>(
+    cas: &C,
+    result: &protos::re::ActionResult,
+) -> Result<bool, CasError> {
+    let digests = result
+        .output_files
+        .iter()
+        .filter_map(|file| file.digest.clone())
+        .chain(
+            result
+                .output_directories
+                .iter()
+                .filter_map(|dir| dir.tree_digest.clone()),
+        )
+        .chain(result.stdout_digest.clone())
+        .chain(result.stderr_digest.clone());
+    for digest in digests {
+        let digest: Digest = digest.into();
+        if !cas.has_blob(&digest).await? {
+            event!(Level::INFO, %digest, "cached output missing from CAS");
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
 
 #[tonic::async_trait]
-impl protos::ActionCache for ActionCacheService {
+impl<A: ActionCache, C: ContentAddressableStorage> protos::ActionCache
+    for ActionCacheService<A, C>
+{
     async fn get_action_result(
         &self,
-        _request: Request<protos::re::GetActionResultRequest>,
+        request: Request<protos::re::GetActionResultRequest>,
     ) -> Result<Response<protos::re::ActionResult>, Status> {
-        Err(Status::not_found("GetActionResult: Not yet implemented"))
+        let request = request.into_inner();
+        let action_digest: Digest = request
+            .action_digest
+            .ok_or_else(|| Status::invalid_argument("No action digest specified."))?
+            .into();
+
+        let result = self
+            .action_cache
+            .get_action_result(&action_digest)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or_else(|| Status::not_found(format!("No action result for {action_digest}")))?;
+
+        if !outputs_available(&self.cas, &result)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+        {
+            return Err(Status::not_found(format!(
+                "Outputs of {action_digest} are no longer available"
+            )));
+        }
+        Ok(Response::new(result))
     }
 
     async fn update_action_result(
         &self,
-        _request: Request<protos::re::UpdateActionResultRequest>,
+        request: Request<protos::re::UpdateActionResultRequest>,
     ) -> Result<Response<protos::re::ActionResult>, Status> {
-        Err(Status::not_found("UpdateActionResult: Not yet implemented"))
+        let request = request.into_inner();
+        let action_digest: Digest = request
+            .action_digest
+            .ok_or_else(|| Status::invalid_argument("No action digest specified."))?
+            .into();
+        let action_result = request
+            .action_result
+            .ok_or_else(|| Status::invalid_argument("No action result specified."))?;
+
+        self.action_cache
+            .update_action_result(action_digest, action_result.clone())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(action_result))
     }
 }
@@ -1,98 +1,485 @@
-use cas::ContentAddressableStorage;
+use cas::{BlobWriter, CasError, ContentAddressableStorage};
 use common::Digest;
-use std::str::FromStr;
-use tokio::sync::mpsc;
+use execution_engine::LiveLogs;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt;
 use tonic::{Request, Response, Status};
+use uuid::Uuid;
+
+/// Most data sent in one `ReadResponse`.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How long an unfinished upload is kept after its last write, for the client
+/// to resume it.
+const UPLOAD_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// How often uploads kept for longer than that are dropped.
+const UPLOAD_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+type ReadStream = ReceiverStream<Result<protos::bytestream::ReadResponse, Status>>;
+
+struct PartialUpload {
+    /// Where the data received is streamed to, once there is some.
+    writer: Option<Box<dyn BlobWriter>>,
+    committed: u64,
+    last_write: Instant,
+}
+
+impl std::fmt::Debug for PartialUpload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PartialUpload")
+            .field("committed", &self.committed)
+            .field("last_write", &self.last_write)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialUpload {
+    /// Pass on `data` written at `offset` of a blob of `size` bytes.
+    async fn append<C: ContentAddressableStorage>(
+        &mut self,
+        cas: &C,
+        size: u64,
+        offset: i64,
+        data: &[u8],
+    ) -> Result<(), Status> {
+        let committed = self.committed;
+        if offset != committed as i64 {
+            return Err(Status::invalid_argument(format!(
+                "Write offset {offset} does not match the {committed} bytes committed"
+            )));
+        }
+        if committed + data.len() as u64 > size {
+            return Err(Status::invalid_argument(format!(
+                "Write is longer than the {size} bytes of its blob"
+            )));
+        }
+        if self.writer.is_none() {
+            let writer = cas
+                .blob_writer()
+                .await
+                .map_err(|e| Status::internal(format!("Failed to start blob write: {e}")))?;
+            self.writer = Some(writer);
+        }
+        let writer = self.writer.as_mut().expect("the write was started");
+        writer
+            .write_all(data)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to write blob: {e}")))?;
+        self.committed += data.len() as u64;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+}
+
+/// An upload shared by the `Write` calls continuing it.
+type SharedUpload = Arc<AsyncMutex<PartialUpload>>;
+
+/// Uploads that have not finished, by upload id and blob.
+///
+/// They are only held in memory, so uploads can't be resumed once the node
+/// restarts.
+#[derive(Debug)]
+struct Uploads {
+    partial: Mutex<HashMap<(Uuid, Digest), SharedUpload>>,
+    /// How long an upload is kept after its last write.
+    retention: Duration,
+}
+
+impl Uploads {
+    fn new(retention: Duration) -> Self {
+        Uploads {
+            partial: Mutex::default(),
+            retention,
+        }
+    }
+
+    /// The upload of `key`, started if there is none yet.
+    fn get(&self, key: &(Uuid, Digest)) -> SharedUpload {
+        let mut partial = self.partial.lock().unwrap();
+        let upload = partial.entry(key.clone()).or_insert_with(|| {
+            Arc::new(AsyncMutex::new(PartialUpload {
+                writer: None,
+                committed: 0,
+                last_write: Instant::now(),
+            }))
+        });
+        upload.clone()
+    }
+
+    /// How much of an unfinished upload has been received, if any of it has.
+    async fn committed(&self, key: &(Uuid, Digest)) -> Option<u64> {
+        let upload = self.partial.lock().unwrap().get(key).cloned()?;
+        let upload = upload.lock().await;
+        upload.writer.is_some().then_some(upload.committed)
+    }
+
+    fn remove(&self, key: &(Uuid, Digest)) {
+        self.partial.lock().unwrap().remove(key);
+    }
+
+    /// Drop the uploads nothing was written to for longer than they are kept.
+    fn sweep(&self) {
+        // Uploads being written to are kept, however long that takes.
+        self.partial.lock().unwrap().retain(|_, upload| {
+            upload
+                .try_lock()
+                .map_or(true, |upload| upload.last_write.elapsed() < self.retention)
+        });
+    }
+}
+
+/// Sweep `uploads` every `interval`, until they are dropped along with their
+/// service.
+fn sweep_periodically(uploads: Weak<Uploads>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            match uploads.upgrade() {
+                Some(uploads) => uploads.sweep(),
+                None => return,
+            }
+        }
+    });
+}
 
 #[derive(Debug)]
 pub struct BytestreamService<T> {
     cas: T,
+    logs: LiveLogs,
+    uploads: Arc<Uploads>,
 }
 
 impl<T> BytestreamService<T> {
-    pub fn new(cas: T) -> Self {
-        BytestreamService { cas }
+    pub fn new(cas: T, logs: LiveLogs) -> Self {
+        let uploads = Arc::new(Uploads::new(UPLOAD_RETENTION));
+        sweep_periodically(Arc::downgrade(&uploads), UPLOAD_SWEEP_INTERVAL);
+        BytestreamService { cas, logs, uploads }
     }
 }
 
-#[tonic::async_trait]
-impl<T: ContentAddressableStorage> protos::ByteStream for BytestreamService<T> {
-    type ReadStream = ReceiverStream<Result<protos::bytestream::ReadResponse, Status>>;
+/// The resource name of the `stdout` or `stderr` of the running execution `id`,
+/// for clients to follow with `Read`.
+pub(crate) fn log_resource_name(id: Uuid, stream: &str) -> String {
+    format!("logs/{id}/{stream}")
+}
 
-    async fn read(
-        &self,
-        request: Request<protos::bytestream::ReadRequest>,
-    ) -> Result<Response<Self::ReadStream>, Status> {
-        let request = request.into_inner();
+/// The execution and stream a `[{instance}/]logs/{uuid}/{stream}` resource name
+/// refers to.
+fn parse_log_resource_name(name: &str) -> Option<(Uuid, &str)> {
+    let mut segments = name.rsplit('/');
+    let stream = segments.next()?;
+    let id = Uuid::parse_str(segments.next()?).ok()?;
+    (segments.next()? == "logs").then_some((id, stream))
+}
 
-        // TODO support other offsets
-        assert_eq!(request.read_offset, 0);
+/// The upload and blob an `[{instance}/]uploads/{uuid}/blobs/{hash}/{size}`
+/// resource name refers to. Anything after the size is ignored.
+fn parse_upload_resource_name(name: &str) -> Option<(Uuid, Digest)> {
+    let segments: Vec<_> = name.split('/').collect();
+    let id = segments
+        .windows(3)
+        .find(|window| window[0] == "uploads" && window[2] == "blobs")
+        .and_then(|window| Uuid::parse_str(window[1]).ok())?;
+    let digest = Digest::from_blob_str(name).ok()?;
+    (digest.size_bytes() >= 0).then_some((id, digest))
+}
 
-        let digest = Digest::from_blob_str(&request.resource_name)
-            .map_err(|e| tonic::Status::invalid_argument(format!("Invalid digest: {e:?}")))?;
+fn invalid_upload_name(name: &str) -> Status {
+    Status::invalid_argument(format!("Invalid upload resource name: {name}"))
+}
+
+/// Why a read can't be served.
+enum RangeError {
+    NegativeLimit(i64),
+    OffsetOutside(i64),
+}
+
+impl From<RangeError> for Status {
+    fn from(err: RangeError) -> Self {
+        match err {
+            RangeError::NegativeLimit(limit) => {
+                Status::invalid_argument(format!("Read limit {limit} is negative"))
+            }
+            RangeError::OffsetOutside(offset) => {
+                Status::out_of_range(format!("Read offset {offset} is outside the resource"))
+            }
+        }
+    }
+}
+
+/// Check the offset and limit of a read against the `size` of the resource, if
+/// it is known. A limit of zero reads to the end.
+fn read_range(
+    offset: i64,
+    limit: i64,
+    size: Option<u64>,
+) -> Result<(u64, Option<u64>), RangeError> {
+    if limit < 0 {
+        return Err(RangeError::NegativeLimit(limit));
+    }
+    if offset < 0 || size.is_some_and(|size| offset as u64 > size) {
+        return Err(RangeError::OffsetOutside(offset));
+    }
+    Ok((offset as u64, (limit > 0).then_some(limit as u64)))
+}
+
+impl<T: ContentAddressableStorage> BytestreamService<T> {
+    /// Send `limit` bytes of a blob from `offset`, reading it from the CAS a
+    /// chunk at a time.
+    async fn read_blob(
+        &self,
+        digest: Digest,
+        offset: i64,
+        limit: i64,
+    ) -> Result<ReadStream, Status> {
+        let size = u64::try_from(digest.size_bytes())
+            .map_err(|_| Status::invalid_argument(format!("Invalid digest: {digest}")))?;
+        let (offset, limit) = read_range(offset, limit, Some(size))?;
+        let end = match limit {
+            Some(limit) => size.min(offset.saturating_add(limit)),
+            None => size,
+        };
+        let mut reader =
+            self.cas
+                .blob_reader(digest.clone(), offset)
+                .await
+                .map_err(|e| match e {
+                    CasError::BlobNotFound(_) => {
+                        Status::not_found(format!("Blob {digest} not found"))
+                    }
+                    e => Status::internal(format!("Failed to read blob: {e}")),
+                })?;
 
         let (tx, rx) = mpsc::channel(32);
-        let cas = self.cas.clone();
+        tokio::spawn(async move {
+            let mut remaining = end - offset;
+            while remaining > 0 {
+                let mut data = Vec::with_capacity(READ_CHUNK_SIZE);
+                let response = match (&mut reader)
+                    .take(remaining.min(READ_CHUNK_SIZE as u64))
+                    .read_to_end(&mut data)
+                    .await
+                {
+                    Ok(0) => Err(Status::data_loss(format!(
+                        "Blob {digest} is shorter than its digest"
+                    ))),
+                    Ok(len) => {
+                        remaining -= len as u64;
+                        Ok(protos::bytestream::ReadResponse { data })
+                    }
+                    Err(e) => Err(Status::internal(format!("Failed to read blob: {e}"))),
+                };
+                let failed = response.is_err();
+                // The client went away, or was told why the read stopped.
+                if tx.send(response).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Ok(ReceiverStream::new(rx))
+    }
+
+    /// Follow the output of a running execution, sending `limit` bytes from
+    /// `offset`.
+    async fn read_log(
+        &self,
+        id: Uuid,
+        stream: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<ReadStream, Status> {
+        let output = self
+            .logs
+            .get(id)
+            .ok_or_else(|| Status::not_found(format!("Execution {id} is not running")))?;
+        let log = match stream {
+            "stdout" => output.stdout,
+            "stderr" => output.stderr,
+            _ => return Err(Status::not_found(format!("Unknown log {stream}"))),
+        };
+        // Output still being written may yet reach any offset.
+        let size = log.final_len().map(|len| len as u64);
+        let (offset, limit) = read_range(offset, limit, size)?;
 
+        let (tx, rx) = mpsc::channel(32);
+        let mut reader = log.reader(offset as usize);
+        let mut remaining = limit.map(|limit| limit as usize);
         tokio::spawn(async move {
-            // TODO Don't load whole blob into memory, stream from CAS.
-            match cas.read_blob(digest).await {
-                Ok(blob) => {
-                    for chunk in blob.chunks(1024) {
-                        tx.send(Ok(protos::bytestream::ReadResponse {
-                            data: chunk.to_vec(),
-                        }));
+            loop {
+                let data = match reader.next().await {
+                    Ok(Some(data)) => data,
+                    Ok(None) => return,
+                    // Only the end of a running command's output is held.
+                    Err(lagged) => {
+                        let _ = tx.send(Err(Status::out_of_range(lagged.to_string()))).await;
+                        return;
+                    }
+                };
+                let data = match remaining {
+                    Some(remaining) => &data[..data.len().min(remaining)],
+                    None => &data[..],
+                };
+                for chunk in data.chunks(READ_CHUNK_SIZE) {
+                    let response = protos::bytestream::ReadResponse {
+                        data: chunk.to_vec(),
+                    };
+                    // The client stopped following the log.
+                    if tx.send(Ok(response)).await.is_err() {
+                        return;
                     }
                 }
-                Err(_) => {
-                    tx.send(Err(tonic::Status::not_found(format!("Blob not found"))));
+                remaining = remaining.map(|remaining| remaining - data.len());
+                if remaining == Some(0) {
+                    return;
                 }
             }
         });
+        Ok(ReceiverStream::new(rx))
+    }
+}
 
-        Ok(Response::new(ReceiverStream::new(rx) as Self::ReadStream))
+#[tonic::async_trait]
+impl<T: ContentAddressableStorage> protos::ByteStream for BytestreamService<T> {
+    type ReadStream = ReadStream;
+
+    async fn read(
+        &self,
+        request: Request<protos::bytestream::ReadRequest>,
+    ) -> Result<Response<Self::ReadStream>, Status> {
+        let request = request.into_inner();
+        if let Some((id, stream)) = parse_log_resource_name(&request.resource_name) {
+            let stream = self
+                .read_log(id, stream, request.read_offset, request.read_limit)
+                .await?;
+            return Ok(Response::new(stream));
+        }
+
+        let digest = Digest::from_blob_str(&request.resource_name)
+            .map_err(|e| tonic::Status::invalid_argument(format!("Invalid digest: {e:?}")))?;
+        let stream = self
+            .read_blob(digest, request.read_offset, request.read_limit)
+            .await?;
+        Ok(Response::new(stream))
     }
 
+    /// Uploads are resumable: data is kept as it is received, so a client whose
+    /// `Write` was interrupted can ask `QueryWriteStatus` how much arrived and
+    /// carry on from there. Unfinished uploads are lost if the node restarts,
+    /// and are dropped after `UPLOAD_RETENTION` without writes.
     async fn write(
         &self,
         request: Request<tonic::Streaming<protos::bytestream::WriteRequest>>,
     ) -> Result<Response<protos::bytestream::WriteResponse>, Status> {
-        let mut blob = vec![];
         let mut stream = request.into_inner();
+        let Some(mut req) = stream.next().await.transpose()? else {
+            return Err(Status::invalid_argument("Write sent no data"));
+        };
+        let key = parse_upload_resource_name(&req.resource_name)
+            .ok_or_else(|| invalid_upload_name(&req.resource_name))?;
+        let size = key.1.size_bytes();
 
-        let mut digest = None;
-        while let Some(req) = stream.next().await {
-            let req = req?;
-            if digest.is_none() {
-                digest = Some(Digest::from_blob_str(&req.resource_name).map_err(|e| {
-                    tonic::Status::invalid_argument(format!("Invalid digest: {e:?}"))
-                })?);
-            }
-            assert_eq!(req.write_offset, blob.len() as i64);
-            blob.extend(req.data);
+        // Another client may have uploaded the blob already.
+        let stored = self
+            .cas
+            .has_blob(&key.1)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to look up blob: {e}")))?;
+        if stored {
+            self.uploads.remove(&key);
+            return Ok(Response::new(protos::bytestream::WriteResponse {
+                committed_size: size,
+            }));
+        }
 
+        let upload = self.uploads.get(&key);
+        loop {
+            let mut partial = upload.lock().await;
+            partial
+                .append(&self.cas, size as u64, req.write_offset, &req.data)
+                .await?;
             if req.finish_write {
-                assert!(digest.is_some());
-                self.cas
-                    .write_blob(&blob, digest)
-                    .await
-                    .map_err(|e| tonic::Status::internal(format!("Invalid blob write: {e:?}")))?;
+                let writer = partial.writer.take().expect("the write was started");
+                drop(partial);
+                self.uploads.remove(&key);
+                writer.commit(Some(key.1)).await.map_err(|e| match e {
+                    CasError::InvalidDigest(..) => Status::invalid_argument(e.to_string()),
+                    e => Status::internal(format!("Invalid blob write: {e:?}")),
+                })?;
                 return Ok(Response::new(protos::bytestream::WriteResponse {
-                    committed_size: blob.len() as i64,
+                    committed_size: size,
                 }));
             }
+            drop(partial);
+            req = match stream.next().await {
+                Some(req) => req?,
+                None => {
+                    return Err(Status::invalid_argument(
+                        "Write ended before it was finished",
+                    ))
+                }
+            };
         }
-        Err(tonic::Status::unknown(format!("Write did not succeed")))
     }
 
     async fn query_write_status(
         &self,
-        _request: Request<protos::bytestream::QueryWriteStatusRequest>,
+        request: Request<protos::bytestream::QueryWriteStatusRequest>,
     ) -> Result<Response<protos::bytestream::QueryWriteStatusResponse>, Status> {
-        Err(tonic::Status::unknown(format!(
-            "Not Supported at this time."
-        )))
+        let name = request.into_inner().resource_name;
+        let key = parse_upload_resource_name(&name).ok_or_else(|| invalid_upload_name(&name))?;
+        if let Some(committed) = self.uploads.committed(&key).await {
+            return Ok(Response::new(
+                protos::bytestream::QueryWriteStatusResponse {
+                    committed_size: committed as i64,
+                    complete: false,
+                },
+            ));
+        }
+        let stored = self
+            .cas
+            .has_blob(&key.1)
+            .await
+            .map_err(|e| Status::internal(format!("Failed to look up blob: {e}")))?;
+        if !stored {
+            return Err(Status::not_found("Nothing has been uploaded"));
+        }
+        Ok(Response::new(
+            protos::bytestream::QueryWriteStatusResponse {
+                committed_size: key.1.size_bytes(),
+                complete: true,
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn abandoned_uploads_are_swept() {
+        let uploads = Arc::new(Uploads::new(Duration::from_millis(200)));
+        sweep_periodically(Arc::downgrade(&uploads), Duration::from_millis(10));
+        let abandoned = (Uuid::new_v4(), cas::digest_of(b"etosha"));
+        let ongoing = (Uuid::new_v4(), cas::digest_of(b"okavango"));
+        uploads.get(&abandoned);
+        let upload = uploads.get(&ongoing);
+        let writing = upload.lock().await;
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(uploads.partial.lock().unwrap().contains_key(&abandoned));
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        // Uploads being written to are kept however long they take.
+        let partial = uploads.partial.lock().unwrap();
+        assert!(!partial.contains_key(&abandoned));
+        assert!(partial.contains_key(&ongoing));
+        drop(writing);
     }
 }
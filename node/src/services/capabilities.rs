@@ -1,7 +1,16 @@
+use execution_engine::{EngineConfig, OutputProperties};
 use tonic::{Request, Response, Status};
 
-#[derive(Debug, Default)]
-pub struct CapabilitiesService {}
+#[derive(Debug)]
+pub struct CapabilitiesService {
+    engine_config: EngineConfig,
+}
+
+impl CapabilitiesService {
+    pub fn new(engine_config: EngineConfig) -> Self {
+        CapabilitiesService { engine_config }
+    }
+}
 
 #[tonic::async_trait]
 impl protos::Capabilities for CapabilitiesService {
@@ -9,9 +18,9 @@ impl protos::Capabilities for CapabilitiesService {
         &self,
         request: Request<protos::re::GetCapabilitiesRequest>,
     ) -> Result<Response<protos::re::ServerCapabilities>, Status> {
-        let api_version = protos::semver::SemVer {
+        let api_version = |minor| protos::semver::SemVer {
             major: 2,
-            minor: 0,
+            minor,
             patch: 0,
             prerelease: String::default(),
         };
@@ -23,7 +32,9 @@ impl protos::Capabilities for CapabilitiesService {
             }),
             cache_priority_capabilities: None,
             max_batch_total_size_bytes: 0,
-            symlink_absolute_path_strategy: 0,
+            // Symlinks may not point outside of the input root.
+            symlink_absolute_path_strategy:
+                protos::re::symlink_absolute_path_strategy::Value::Disallowed.into(),
             supported_compressors: vec![],
             supported_batch_update_compressors: vec![],
         };
@@ -31,16 +42,25 @@ impl protos::Capabilities for CapabilitiesService {
         let exec_caps = protos::re::ExecutionCapabilities {
             digest_function: protos::re::digest_function::Value::Sha256.into(),
             exec_enabled: true,
-            execution_priority_capabilities: None,
-            supported_node_properties: vec![],
+            execution_priority_capabilities: Some(protos::re::PriorityCapabilities {
+                priorities: vec![protos::re::priority_capabilities::PriorityRange {
+                    min_priority: self.engine_config.min_priority,
+                    max_priority: self.engine_config.max_priority,
+                }],
+            }),
+            supported_node_properties: OutputProperties::SUPPORTED
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
         };
 
         let caps = protos::re::ServerCapabilities {
             cache_capabilities: Some(cache_capabilities),
             execution_capabilities: Some(exec_caps),
             deprecated_api_version: None,
-            low_api_version: Some(api_version.clone()),
-            high_api_version: Some(api_version.clone()),
+            // From output_files and output_directories, up to platforms on actions.
+            low_api_version: Some(api_version(0)),
+            high_api_version: Some(api_version(2)),
         };
         Ok(Response::new(caps))
     }
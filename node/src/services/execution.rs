@@ -1,20 +1,28 @@
 use anyhow::{anyhow, Error};
-use cas::ContentAddressableStorage;
+use cas::{ActionCache, ContentAddressableStorage};
 use execution_engine::{
-    Entry, ExecuteError, ExecuteStage, ExecuteStatus, ExecutionBackend, ExecutionEngine,
+    ExecuteError, ExecuteResponse, ExecuteStage, ExecuteStatus, ExecutionBackend, ExecutionEngine,
+    OutputKind, OutputProperties,
 };
 use futures::future::BoxFuture;
 use futures::StreamExt;
 use opentelemetry::global;
 use prost::Message;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
 use tokio::io::AsyncReadExt;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep, timeout};
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
 use tracing::{event, span, Instrument, Level};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
+use uuid::Uuid;
+
+use super::action_cache::outputs_available;
+use super::bytestream::log_resource_name;
+use crate::operations::OperationRegistry;
 
 pub static EXEC_OP_METADATA: &'static str =
     "type.googleapis.com/build.bazel.remote.execution.v2.ExecuteOperationMetadata";
@@ -23,18 +31,30 @@ pub static EXEC_RESP: &'static str =
 pub static PRECONDITION_FAILURE: &'static str =
     "type.googleapis.com/com.google.rpc.PreconditionFailure";
 
-pub struct ExecutionService<C, B> {
+/// How often an operation is resent to clients while it is not changing.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(30);
+/// How long an operation keeps running after its last client disconnected.
+const ABANDON_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+pub struct ExecutionService<C, B, A> {
     instance: String,
     cas: C,
-    engine: ExecutionEngine<B>,
+    engine: ExecutionEngine<B, A>,
+    registry: OperationRegistry,
 }
 
-impl<C: ContentAddressableStorage, B> ExecutionService<C, B> {
-    pub fn new(instance: &str, cas: C, engine: ExecutionEngine<B>) -> Self {
+impl<C: ContentAddressableStorage, B, A> ExecutionService<C, B, A> {
+    pub fn new(
+        instance: &str,
+        cas: C,
+        engine: ExecutionEngine<B, A>,
+        registry: OperationRegistry,
+    ) -> Self {
         ExecutionService {
             instance: instance.to_string(),
             cas,
             engine,
+            registry,
         }
     }
 }
@@ -60,19 +80,21 @@ fn create_mapping<'a, C: ContentAddressableStorage>(
 ) -> BoxFuture<'a, Result<(), ExecuteError>> {
     Box::pin(async move {
         for symlink in dir.symlinks {
+            check_name(&root, &symlink.name)?;
             let mut link_path = root.clone();
             link_path.push(&symlink.name);
+            check_symlink_target(&link_path, &symlink.target)?;
 
-            let mut original_path = root.clone();
-            original_path.push(&symlink.target);
-
+            // Targets are relative to the directory the symlink is in, like on disk.
             mapping.entries.push(execution_engine::Entry::Symlink {
-                original: original_path.into(),
-                link: link_path.into(),
+                original: symlink.target.into(),
+                link: link_path,
+                points_to: None,
             });
         }
 
         for file in dir.files {
+            check_name(&root, &file.name)?;
             let mut path = root.clone();
             path.push(&file.name);
             mapping.entries.push(execution_engine::Entry::File {
@@ -84,9 +106,11 @@ fn create_mapping<'a, C: ContentAddressableStorage>(
                     .into(),
                 path,
                 executable: file.is_executable,
+                properties: file.node_properties.into(),
             });
         }
         for directory_node in &dir.directories {
+            check_name(&root, &directory_node.name)?;
             let node_digest =
                 directory_node
                     .digest
@@ -95,18 +119,82 @@ fn create_mapping<'a, C: ContentAddressableStorage>(
                         "No digest in node for directory: {}",
                         root.display()
                     )))?;
-            let dir: protos::re::Directory = get_proto(cas.clone(), node_digest.into()).await?;
+            let dir: protos::re::Directory =
+                get_proto(cas.clone(), node_digest.clone().into()).await?;
             let mut new_root = root.clone();
             new_root.push(&directory_node.name);
+            // Directories are laid out even if empty, e.g. to run commands in.
+            mapping.entries.push(execution_engine::Entry::Directory {
+                path: new_root.clone(),
+                digest: node_digest.into(),
+                properties: dir.node_properties.clone().into(),
+            });
             create_mapping(mapping, dir, cas.clone(), new_root).await?;
         }
         Ok(())
     })
 }
 
+/// Make sure `name`, of an entry in the input directory at `root`, can't be
+/// used to lay it out anywhere else.
+fn check_name(root: &Path, name: &str) -> Result<(), ExecuteError> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) if !name.contains('/') => Ok(()),
+        _ => Err(ExecuteError::InvalidArgument(format!(
+            "Invalid input: {name:?} in {:?} is not a valid name",
+            root.display().to_string()
+        ))),
+    }
+}
+
+/// Make sure the input symlink at `link` can't be used to reach outside of the
+/// input root. Absolute targets are disallowed, as advertised in the
+/// capabilities.
+fn check_symlink_target(link: &Path, target: &str) -> Result<(), ExecuteError> {
+    let escapes = || {
+        ExecuteError::InvalidArgument(format!(
+            "Invalid input: symlink {} to {target} escapes the input root",
+            link.display()
+        ))
+    };
+    // How many directories below the input root the target has reached.
+    let mut depth = link.components().count() - 1;
+    for component in Path::new(target).components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir => depth = depth.checked_sub(1).ok_or_else(escapes)?,
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) => return Err(escapes()),
+        }
+    }
+    Ok(())
+}
+
+impl<C: ContentAddressableStorage, B: ExecutionBackend, A: ActionCache> ExecutionService<C, B, A> {
+    /// Find a cached result for the action whose outputs are all still in the CAS.
+    async fn cached_result(
+        &self,
+        action_digest: &common::Digest,
+    ) -> Result<Option<protos::re::ActionResult>, Status> {
+        let Some(result) = self
+            .engine
+            .cached_result(action_digest)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+        else {
+            return Ok(None);
+        };
+        let available = outputs_available(&self.cas, &result)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(available.then_some(result))
+    }
+}
+
 #[tonic::async_trait]
-impl<C: ContentAddressableStorage, B: ExecutionBackend> protos::Execution
-    for ExecutionService<C, B>
+impl<C: ContentAddressableStorage, B: ExecutionBackend, A: ActionCache> protos::Execution
+    for ExecutionService<C, B, A>
 {
     type ExecuteStream = ReceiverStream<Result<protos::longrunning::Operation, Status>>;
 
@@ -122,95 +210,56 @@ impl<C: ContentAddressableStorage, B: ExecutionBackend> protos::Execution
             ));
         }
 
-        let cas = self.cas.clone();
-        let cas2 = self.cas.clone();
-        let mut exec_events = self
-            .engine
-            .execute(move || {
-                let span = span.clone();
-                let request = request.clone();
-                let cas = cas.clone();
-                let span = span!(Level::TRACE, "setup function");
-                async move {
-                    let action_digest = request.action_digest.ok_or(
-                        ExecuteError::InvalidArgument(String::from("no action digest specified")),
-                    )?;
-                    let action: protos::re::Action =
-                        get_proto(cas.clone(), action_digest.clone().into()).await?;
-                    let command_digest =
-                        action.command_digest.ok_or(ExecuteError::InvalidArgument(
-                            String::from("Invalid Action: no command digest specified."),
-                        ))?;
-                    let command: protos::re::Command =
-                        get_proto(cas.clone(), command_digest.clone().into()).await?;
-
-                    let root_digest =
-                        action
-                            .input_root_digest
-                            .ok_or(ExecuteError::InvalidArgument(format!(
-                                "Invalid Action: no root digest specified."
-                            )))?;
-                    let root_directory: protos::re::Directory =
-                        get_proto(cas.clone(), root_digest.clone().into()).await?;
-
-                    // Collect a command for the execution engine
-                    let cmd = execution_engine::Command {
-                        arguments: command.arguments,
-                        env_vars: command
-                            .environment_variables
-                            .iter()
-                            .map(|ev| (ev.name.clone(), ev.value.clone()))
-                            .collect(),
-                    };
-                    // Collect the filesystem information for the execution engine
-                    let mut dir_layout = execution_engine::DirectoryLayout::default();
-                    create_mapping(
-                        &mut dir_layout,
-                        root_directory,
-                        cas.clone(),
-                        PathBuf::default(),
-                    )
-                    .await
-                    .map_err(|e| {
-                        ExecuteError::Internal(format!(
-                            "Failed to execute mapping collection: {e:?}"
-                        ))
-                    })?;
-
-                    assert!(command.working_directory.is_empty());
-
-                    if !command.output_directories.is_empty() || !command.output_files.is_empty() {
-                        return Err(ExecuteError::InvalidArgument(format!(
-                            "Only RBE v2.1+ Supported. Use output_paths"
-                        )));
-                    }
-
-                    // Collect output path information
-                    if command.output_paths.is_empty() {
-                        return Err(ExecuteError::InvalidArgument(format!(
-                            "No output_paths were specified."
-                        )));
-                    }
-                    for path in command.output_paths {
-                        dir_layout.output_paths.push(path.into());
-                    }
+        // Problems fetching the action are reported by the execution itself.
+        let action: Option<protos::re::Action> = match &request.action_digest {
+            Some(action_digest) => get_proto(self.cas.clone(), action_digest.clone().into())
+                .await
+                .ok(),
+            None => None,
+        };
+        let do_not_cache = action.as_ref().is_some_and(|action| action.do_not_cache);
 
-                    Ok((action_digest.into(), cmd, dir_layout))
+        // The results cache policy only carries an eviction priority, which
+        // neither action cache backend makes use of.
+        if !request.skip_cache_lookup && !do_not_cache {
+            if let Some(action_digest) = &request.action_digest {
+                let action_digest: common::Digest = action_digest.clone().into();
+                if let Some(result) = self.cached_result(&action_digest).await? {
+                    event!(Level::INFO, digest = %action_digest, "action cache hit");
+                    let updates = self.registry.insert(cached_op(action_digest, result));
+                    return Ok(Response::new(follow(self.registry.clone(), updates)));
                 }
-                .instrument(span)
-            })
-            .map_err(|e| Status::unknown(format!("Failed to execute: {e}")))?;
-
-        // TODO is there an easy way to map over this instead of making another channel?
-        let (tx, rx) = mpsc::channel(32);
-        while let Some(event) = exec_events.recv().await {
-            let op = convert_to_op(event);
-            tx.send(op)
-                .await
-                .map_err(|e| Status::unknown(format!("Failed to execute command: {e}")))?;
+            }
         }
-        let output_stream = ReceiverStream::new(rx);
-        Ok(Response::new(output_stream as Self::ExecuteStream))
+
+        let action_digest = request.action_digest.clone().map(common::Digest::from);
+        let cas = self.cas.clone();
+        let cas2 = self.cas.clone();
+        let registry = self.registry.clone();
+        // Failing to start is returned to the client as is, boxing the status
+        // would only move it to the heap on the way there.
+        #[allow(clippy::result_large_err)]
+        let start = || {
+            let (uuid, exec_events) = self
+                .engine
+                .execute(move || {
+                    let span = span!(Level::TRACE, "setup function");
+                    prepare_action(cas.clone(), request.clone()).instrument(span)
+                })
+                .map_err(|e| Status::unknown(format!("Failed to execute: {e}")))?;
+            let execution = tokio::spawn(publish(registry, uuid, exec_events));
+            let op = convert_to_op(ExecuteStatus {
+                uuid,
+                action_digest: action_digest.clone(),
+                stage: ExecuteStage::Queued,
+            })?;
+            Ok::<_, Status>((op, execution.abort_handle()))
+        };
+        // Identical actions already executing are joined rather than run again,
+        // unless the client asked for the action to always be run.
+        let joinable = action_digest.clone().filter(|_| !do_not_cache);
+        let updates = self.registry.join_or_start(joinable, start)?;
+        Ok(Response::new(follow(self.registry.clone(), updates)))
     }
 
     type WaitExecutionStream = ReceiverStream<Result<protos::longrunning::Operation, Status>>;
@@ -219,13 +268,260 @@ impl<C: ContentAddressableStorage, B: ExecutionBackend> protos::Execution
         &self,
         request: Request<protos::re::WaitExecutionRequest>,
     ) -> Result<Response<Self::WaitExecutionStream>, Status> {
-        Err(Status::not_found("WaitExecution: Not yet implemented"))
+        let name = request.into_inner().name;
+        let updates = self
+            .registry
+            .subscribe(&name)
+            .ok_or_else(|| Status::not_found(format!("Unknown operation: {name}")))?;
+        Ok(Response::new(follow(self.registry.clone(), updates)))
+    }
+}
+
+/// Gather everything the engine needs to run the action requested.
+async fn prepare_action<C: ContentAddressableStorage>(
+    cas: C,
+    request: protos::re::ExecuteRequest,
+) -> Result<execution_engine::Action, ExecuteError> {
+    let action_digest =
+        request
+            .action_digest
+            .ok_or(ExecuteError::InvalidArgument(String::from(
+                "no action digest specified",
+            )))?;
+    let action: protos::re::Action = get_proto(cas.clone(), action_digest.clone().into()).await?;
+    let command_digest =
+        action
+            .command_digest
+            .ok_or(ExecuteError::InvalidArgument(String::from(
+                "Invalid Action: no command digest specified.",
+            )))?;
+    let command: protos::re::Command =
+        get_proto(cas.clone(), command_digest.clone().into()).await?;
+
+    let root_digest = action
+        .input_root_digest
+        .ok_or(ExecuteError::InvalidArgument(format!(
+            "Invalid Action: no root digest specified."
+        )))?;
+
+    // Collect a command for the execution engine
+    let cmd = execution_engine::Command {
+        arguments: command.arguments,
+        env_vars: command
+            .environment_variables
+            .iter()
+            .map(|ev| (ev.name.clone(), ev.value.clone()))
+            .collect(),
+        // Decided by the engine from the action's timeout.
+        timeout: None,
+        // Older clients only set the platform on the command.
+        platform: action
+            .platform
+            .or(command.platform)
+            .unwrap_or_default()
+            .into(),
+        working_directory: working_directory(&command.working_directory)?,
+        // Decided by the engine from its config.
+        max_inline_output_bytes: None,
+        // Opened by the engine once the command runs.
+        output: Default::default(),
+    };
+    // Collect the filesystem information for the execution engine
+    let mut dir_layout = execution_engine::DirectoryLayout::default();
+    collect_inputs(cas.clone(), root_digest.into(), &mut dir_layout).await?;
+
+    let in_input_root = cmd.working_directory.as_os_str().is_empty()
+        || dir_layout.entries.iter().any(|entry| match entry {
+            execution_engine::Entry::Directory { path, .. } => *path == cmd.working_directory,
+            _ => false,
+        });
+    if !in_input_root {
+        return Err(ExecuteError::InvalidArgument(format!(
+            "Invalid Command: working directory {} is not a directory in the input root",
+            command.working_directory
+        )));
+    }
+
+    // Collect output path information. Clients predating v2.1 list output files
+    // and directories apart, which is ignored by those listing `output_paths`.
+    let outputs = if command.output_paths.is_empty() {
+        let files = command
+            .output_files
+            .into_iter()
+            .map(|path| (path, Some(OutputKind::File)));
+        let directories = command
+            .output_directories
+            .into_iter()
+            .map(|path| (path, Some(OutputKind::Directory)));
+        files.chain(directories).collect::<Vec<_>>()
+    } else {
+        command
+            .output_paths
+            .into_iter()
+            .map(|path| (path, None))
+            .collect()
+    };
+    if outputs.is_empty() {
+        return Err(ExecuteError::InvalidArgument(format!(
+            "No output_paths were specified."
+        )));
+    }
+    // Output paths are relative to the working directory, but the engine lays
+    // out and collects them relative to the input root.
+    for (path, kind) in outputs {
+        let path = cmd.working_directory.join(output_path(&path)?);
+        if let Some(kind) = kind {
+            dir_layout.output_kinds.insert(path.clone(), kind);
+        }
+        dir_layout.output_paths.push(path);
+    }
+    dir_layout.output_properties = OutputProperties::parse(&command.output_node_properties)?;
+
+    let timeout = action
+        .timeout
+        .map(Duration::try_from)
+        .transpose()
+        .map_err(|e| ExecuteError::InvalidArgument(format!("Invalid Action: bad timeout: {e}")))?;
+
+    Ok(execution_engine::Action {
+        digest: action_digest.into(),
+        command: cmd,
+        layout: dir_layout,
+        do_not_cache: action.do_not_cache,
+        timeout,
+        // Zero, the default, is what clients that don't care ask for.
+        priority: request.execution_policy.map_or(0, |policy| policy.priority),
+    })
+}
+
+/// Collect the entries of the input root `root_digest` into `layout`.
+pub(crate) async fn collect_inputs<C: ContentAddressableStorage>(
+    cas: C,
+    root_digest: common::Digest,
+    layout: &mut execution_engine::DirectoryLayout,
+) -> Result<(), ExecuteError> {
+    let root_directory: protos::re::Directory = get_proto(cas.clone(), root_digest.clone()).await?;
+    layout.input_root_digest = Some(root_digest);
+    create_mapping(layout, root_directory, cas, PathBuf::default()).await
+}
+
+/// The working directory of a command, which must be a relative path that stays
+/// inside the input root.
+fn working_directory(path: &str) -> Result<PathBuf, ExecuteError> {
+    relative_path(path, "working directory")
+}
+
+/// An output path of a command, which must be a relative path that stays inside
+/// the working directory.
+fn output_path(path: &str) -> Result<PathBuf, ExecuteError> {
+    relative_path(path, "output path")
+}
+
+fn relative_path(path: &str, what: &str) -> Result<PathBuf, ExecuteError> {
+    let mut relative = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) | Component::ParentDir => {
+                return Err(ExecuteError::InvalidArgument(format!(
+                    "Invalid Command: {what} {path} must be a relative path inside the input root"
+                )))
+            }
+        }
+    }
+    Ok(relative)
+}
+
+/// Record the engine's progress on an operation in the registry until it is done.
+async fn publish(
+    registry: OperationRegistry,
+    uuid: Uuid,
+    mut exec_events: mpsc::Receiver<ExecuteStatus>,
+) {
+    while let Some(event) = exec_events.recv().await {
+        if let Ok(op) = convert_to_op(event) {
+            // The operation may have been finished early, e.g. by being cancelled.
+            if registry.update(op).done {
+                return;
+            }
+        }
+    }
+
+    // The engine hung up without finishing, don't leave waiters hanging forever.
+    let lost = convert_to_op(ExecuteStatus {
+        uuid,
+        action_digest: None,
+        stage: ExecuteStage::Error(ExecuteError::Internal(String::from(
+            "Execution ended unexpectedly",
+        ))),
+    });
+    if let Ok(op) = lost {
+        registry.update(op);
+    }
+}
+
+/// Stream the state of an operation to a client as it changes, until it is done.
+fn follow(
+    registry: OperationRegistry,
+    mut updates: watch::Receiver<protos::longrunning::Operation>,
+) -> ReceiverStream<Result<protos::longrunning::Operation, Status>> {
+    let (tx, rx) = mpsc::channel(32);
+    tokio::spawn(async move {
+        loop {
+            let op = updates.borrow_and_update().clone();
+            let name = op.name.clone();
+            let done = op.done;
+            let sent = tx.send(Ok(op)).await.is_ok();
+            if done {
+                break;
+            }
+            // Resend the current state every so often so idle connections aren't
+            // timed out while a long action runs. Stop once the operation is
+            // forgotten by the registry.
+            let client_gone = !sent
+                || tokio::select! {
+                    changed = timeout(KEEP_ALIVE_INTERVAL, updates.changed()) => {
+                        if let Ok(Err(_)) = changed {
+                            break;
+                        }
+                        false
+                    }
+                    _ = tx.closed() => true,
+                };
+            if client_gone {
+                tokio::spawn(abandon(registry, name));
+                break;
+            }
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+/// Cancel an operation once nobody has followed it for `ABANDON_GRACE_PERIOD`,
+/// giving clients that lost their connection time to reconnect with `WaitExecution`.
+async fn abandon(registry: OperationRegistry, name: String) {
+    sleep(ABANDON_GRACE_PERIOD).await;
+    if registry.followers(&name) == Some(0) {
+        if let Some(op) = registry.get(&name) {
+            event!(Level::INFO, name, "cancelling abandoned operation");
+            registry.update(cancelled_op(op));
+        }
     }
 }
 
 fn convert_to_op(
     exec_status: ExecuteStatus,
 ) -> Result<protos::longrunning::Operation, tonic::Status> {
+    // Output can be followed over ByteStream while the command runs.
+    let (stdout_stream_name, stderr_stream_name) = match exec_status.stage {
+        ExecuteStage::Running => (
+            log_resource_name(exec_status.uuid, "stdout"),
+            log_resource_name(exec_status.uuid, "stderr"),
+        ),
+        _ => Default::default(),
+    };
+
     // Assemble the metadata
     let metadata = protos::re::ExecuteOperationMetadata {
         stage: match exec_status.stage {
@@ -236,14 +532,29 @@ fn convert_to_op(
         }
         .into(),
         action_digest: exec_status.action_digest.map(|ad| ad.into()),
-        // TODO stream metadata
-        stdout_stream_name: String::from(""),
-        stderr_stream_name: String::from(""),
+        stdout_stream_name,
+        stderr_stream_name,
     };
 
     let (done, result) = match exec_status.stage {
         ExecuteStage::Queued => (false, None),
         ExecuteStage::Running => (false, None),
+        ExecuteStage::Error(ExecuteError::DeadlineExceeded(resp)) => (
+            true,
+            Some(killed_response(
+                &resp,
+                protos::rpc::Code::DeadlineExceeded,
+                "Action timed out.",
+            )),
+        ),
+        ExecuteStage::Error(ExecuteError::OutOfMemory(resp)) => (
+            true,
+            Some(killed_response(
+                &resp,
+                protos::rpc::Code::ResourceExhausted,
+                "Action ran out of memory.",
+            )),
+        ),
         ExecuteStage::Error(status) => {
             let status = match status {
                 ExecuteError::InvalidArgument(info) => protos::rpc::Status {
@@ -290,6 +601,36 @@ fn convert_to_op(
                     message: format!("Internal Failure: {info}."),
                     ..Default::default()
                 },
+                ExecuteError::Cancelled => protos::rpc::Status {
+                    code: protos::rpc::Code::Cancelled.into(),
+                    message: String::from("Operation was cancelled."),
+                    ..Default::default()
+                },
+                ExecuteError::UnsupportedPlatform(platform) => protos::rpc::Status {
+                    code: protos::rpc::Code::FailedPrecondition.into(),
+                    message: format!("No worker matches the required platform: {platform}."),
+                    ..Default::default()
+                },
+                // If an output of the same name as listed in `output_files` was found, but
+                // was not a regular file, the server will return a FAILED_PRECONDITION.
+                err @ (ExecuteError::UnexpectedOutputKind(_)
+                | ExecuteError::AbsoluteOutputSymlink(_)) => protos::rpc::Status {
+                    code: protos::rpc::Code::FailedPrecondition.into(),
+                    message: err.to_string(),
+                    ..Default::default()
+                },
+                // Timeouts and running out of memory are reported above along with
+                // the output captured so far.
+                ExecuteError::DeadlineExceeded(_) => protos::rpc::Status {
+                    code: protos::rpc::Code::DeadlineExceeded.into(),
+                    message: String::from("Action timed out."),
+                    ..Default::default()
+                },
+                ExecuteError::OutOfMemory(_) => protos::rpc::Status {
+                    code: protos::rpc::Code::ResourceExhausted.into(),
+                    message: String::from("Action ran out of memory."),
+                    ..Default::default()
+                },
             };
 
             let response = protos::re::ExecuteResponse {
@@ -311,73 +652,8 @@ fn convert_to_op(
             (true, Some(response))
         }
         ExecuteStage::Done(resp) => {
-            let execution_metadata = protos::re::ExecutedActionMetadata {
-                ..Default::default()
-            };
-
-            // Collect outputs from the finished execution
-            let mut output_files = vec![];
-            let mut output_directories = vec![];
-            let mut output_symlinks = vec![];
-            for entry in resp.output_paths {
-                match entry {
-                    Entry::Symlink { original, link } => {
-                        output_symlinks.push(protos::re::OutputSymlink {
-                            path: original.display().to_string(),
-                            target: link.display().to_string(),
-                            node_properties: None,
-                        });
-                    }
-                    Entry::File {
-                        path,
-                        digest,
-                        executable,
-                    } => {
-                        output_files.push(protos::re::OutputFile {
-                            path: path.display().to_string(),
-                            digest: Some(digest.into()),
-                            is_executable: executable,
-                            // The contents of the file if inlining was requested. The server
-                            // SHOULD NOT inline file contents unless requested by the client in
-                            // the [GetActionResultRequest][build.bazel.remote.execution.v2.GetActionResultRequest]
-                            // message. The server MAY omit inlining, even if requested, and MUST do so if inlining
-                            // would cause the response to exceed message size limits.
-                            contents: vec![],
-                            node_properties: None,
-                        });
-                    }
-                    Entry::Directory { path, digest } => {
-                        assert!(path.is_relative());
-                        output_directories.push(protos::re::OutputDirectory {
-                            path: path.display().to_string(),
-                            tree_digest: Some(digest.into()),
-                            is_topologically_sorted: false,
-                        });
-                    }
-                }
-            }
-
-            // The files, directories and symlinks in the directory must each be sorted
-            // in lexicographical order by path. The path strings must be sorted by code
-            // point, equivalently, by UTF-8 bytes.
-            output_files.sort_by(|a, b| b.path.cmp(&a.path));
-            output_directories.sort_by(|a, b| b.path.cmp(&a.path));
-            output_symlinks.sort_by(|a, b| b.path.cmp(&a.path));
-
             let response = protos::re::ExecuteResponse {
-                result: Some(protos::re::ActionResult {
-                    output_files,
-                    output_file_symlinks: vec![],
-                    output_symlinks,
-                    output_directories,
-                    output_directory_symlinks: vec![],
-                    exit_code: resp.exit_status,
-                    execution_metadata: Some(execution_metadata),
-                    stdout_digest: None,
-                    stderr_digest: None,
-                    stdout_raw: resp.stdout,
-                    stderr_raw: resp.stderr,
-                }),
+                result: Some(protos::re::ActionResult::from(&resp)),
                 cached_result: false,
                 status: Some(protos::rpc::Status {
                     code: 0,
@@ -390,9 +666,97 @@ fn convert_to_op(
             (true, Some(response))
         }
     };
+    Ok(assemble_op(exec_status.uuid, done, metadata, result))
+}
+
+/// The response for an action that was killed before it finished, whose stdout
+/// and stderr are still worth showing.
+fn killed_response(
+    resp: &ExecuteResponse,
+    code: protos::rpc::Code,
+    message: &str,
+) -> protos::re::ExecuteResponse {
+    protos::re::ExecuteResponse {
+        result: Some(protos::re::ActionResult::from(resp)),
+        cached_result: false,
+        status: Some(protos::rpc::Status {
+            code: code.into(),
+            message: String::from(message),
+            ..Default::default()
+        }),
+        server_logs: HashMap::new(),
+        message: String::from(""),
+    }
+}
+
+/// The final state of an operation that was cancelled before completing.
+pub(crate) fn cancelled_op(op: protos::longrunning::Operation) -> protos::longrunning::Operation {
+    let metadata = op
+        .metadata
+        .as_ref()
+        .and_then(|any| protos::re::ExecuteOperationMetadata::decode(&*any.value).ok())
+        .unwrap_or_default();
+    let metadata = protos::re::ExecuteOperationMetadata {
+        stage: protos::re::execution_stage::Value::Completed.into(),
+        ..metadata
+    };
+    let response = protos::re::ExecuteResponse {
+        result: None,
+        cached_result: false,
+        status: Some(protos::rpc::Status {
+            code: protos::rpc::Code::Cancelled.into(),
+            message: String::from("Operation was cancelled."),
+            ..Default::default()
+        }),
+        server_logs: HashMap::new(),
+        message: String::from(""),
+    };
+    protos::longrunning::Operation {
+        name: op.name,
+        done: true,
+        metadata: Some(prost_types::Any {
+            type_url: EXEC_OP_METADATA.to_string(),
+            value: metadata.encode_to_vec(),
+        }),
+        result: Some(protos::longrunning::operation::Result::Response(
+            prost_types::Any {
+                type_url: EXEC_RESP.to_string(),
+                value: response.encode_to_vec(),
+            },
+        )),
+    }
+}
+
+/// A completed operation serving a result out of the action cache.
+fn cached_op(
+    action_digest: common::Digest,
+    result: protos::re::ActionResult,
+) -> protos::longrunning::Operation {
+    let metadata = protos::re::ExecuteOperationMetadata {
+        stage: protos::re::execution_stage::Value::Completed.into(),
+        action_digest: Some(action_digest.into()),
+        stdout_stream_name: String::from(""),
+        stderr_stream_name: String::from(""),
+    };
+    let response = protos::re::ExecuteResponse {
+        result: Some(result),
+        cached_result: true,
+        status: Some(protos::rpc::Status::default()),
+        server_logs: HashMap::new(),
+        message: String::from(""),
+    };
+    assemble_op(Uuid::new_v4(), true, metadata, Some(response))
+}
+
+fn assemble_op(
+    uuid: Uuid,
+    done: bool,
+    metadata: protos::re::ExecuteOperationMetadata,
+    result: Option<protos::re::ExecuteResponse>,
+) -> protos::longrunning::Operation {
     // Name pattern defined by longrunning proto & in remote_execution.proto under WaitExecution
-    let name = format!("operations/{}", exec_status.uuid);
-    Ok(protos::longrunning::Operation {
+    let name = format!("operations/{}", uuid);
+    protos::longrunning::Operation {
         name,
         done,
         metadata: Some(prost_types::Any {
@@ -412,5 +776,5 @@ fn convert_to_op(
                 value: result.encode_to_vec(),
             })
         }),
-    })
+    }
 }
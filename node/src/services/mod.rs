@@ -13,7 +13,11 @@ mod content_storage;
 pub use content_storage::ContentStorageService;
 
 mod execution;
+pub(crate) use execution::collect_inputs;
 pub use execution::ExecutionService;
 
 mod operations;
 pub use operations::OperationsService;
+
+mod workers;
+pub use workers::WorkersService;
@@ -1,9 +1,72 @@
+use crate::operations::{OperationRegistry, Removal};
+use prost::Message;
+use std::time::Duration;
 use tonic::{Request, Response, Status};
 
-pub struct OperationsService {}
+/// Number of operations returned by `ListOperations` when the client doesn't ask.
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+pub struct OperationsService {
+    registry: OperationRegistry,
+}
+
 impl OperationsService {
-    pub fn new() -> Self {
-        OperationsService {}
+    pub fn new(registry: OperationRegistry) -> Self {
+        OperationsService { registry }
+    }
+}
+
+/// A parsed `ListOperations` filter.
+///
+/// Filters are whitespace separated `key=value` terms that must all match,
+/// optionally joined by `AND`. Supported keys are `done` (`true` or `false`)
+/// and `action_digest` (`{hash}/{size}`).
+#[derive(Default)]
+struct Filter {
+    done: Option<bool>,
+    action_digest: Option<common::Digest>,
+}
+
+impl Filter {
+    /// Parse a filter, returning the first invalid term if there is one.
+    fn parse(filter: &str) -> Result<Self, String> {
+        let mut parsed = Filter::default();
+        for term in filter.split_whitespace().filter(|term| *term != "AND") {
+            let invalid = || format!("Invalid filter term: {term}");
+            let (key, value) = term.split_once('=').ok_or_else(invalid)?;
+            match key {
+                "done" => parsed.done = Some(value.parse().map_err(|_| invalid())?),
+                "action_digest" => {
+                    let digest = common::Digest::from_blob_str(&format!("blobs/{value}"))
+                        .map_err(|_| invalid())?;
+                    parsed.action_digest = Some(digest);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn matches(&self, op: &protos::longrunning::Operation) -> bool {
+        if let Some(done) = self.done {
+            if op.done != done {
+                return false;
+            }
+        }
+        if let Some(action_digest) = &self.action_digest {
+            let metadata = op
+                .metadata
+                .as_ref()
+                .and_then(|any| protos::re::ExecuteOperationMetadata::decode(&*any.value).ok());
+            let op_digest = metadata
+                .and_then(|metadata| metadata.action_digest)
+                .map(common::Digest::from);
+            if op_digest.as_ref() != Some(action_digest) {
+                return false;
+            }
+        }
+        true
     }
 }
 
@@ -13,34 +76,114 @@ impl protos::Operations for OperationsService {
         &self,
         request: Request<protos::longrunning::ListOperationsRequest>,
     ) -> Result<Response<protos::longrunning::ListOperationsResponse>, Status> {
-        todo!()
+        let request = request.into_inner();
+        if !request.name.is_empty() && request.name != "operations" {
+            return Err(Status::not_found(format!(
+                "Unknown operation collection: {}",
+                request.name
+            )));
+        }
+        let filter = Filter::parse(&request.filter).map_err(Status::invalid_argument)?;
+        let page_size = match request.page_size {
+            0 => DEFAULT_PAGE_SIZE,
+            size if size < 0 => {
+                return Err(Status::invalid_argument("page_size must not be negative"))
+            }
+            size => (size as usize).min(MAX_PAGE_SIZE),
+        };
+
+        // The page token is the name of the last operation of the previous page.
+        let mut operations: Vec<_> = self
+            .registry
+            .list()
+            .into_iter()
+            .filter(|op| op.name > request.page_token)
+            .filter(|op| filter.matches(op))
+            .take(page_size + 1)
+            .collect();
+        let next_page_token = if operations.len() > page_size {
+            operations.truncate(page_size);
+            operations
+                .last()
+                .map(|op| op.name.clone())
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        Ok(Response::new(protos::longrunning::ListOperationsResponse {
+            operations,
+            next_page_token,
+        }))
     }
 
     async fn get_operation(
         &self,
         request: Request<protos::longrunning::GetOperationRequest>,
     ) -> Result<Response<protos::longrunning::Operation>, Status> {
-        todo!()
+        let name = request.into_inner().name;
+        let op = self
+            .registry
+            .get(&name)
+            .ok_or_else(|| Status::not_found(format!("Unknown operation: {name}")))?;
+        Ok(Response::new(op))
     }
 
     async fn delete_operation(
         &self,
         request: Request<protos::longrunning::DeleteOperationRequest>,
     ) -> Result<Response<()>, Status> {
-        todo!()
+        let name = request.into_inner().name;
+        match self.registry.remove(&name) {
+            Removal::Removed => Ok(Response::new(())),
+            Removal::Unknown => Err(Status::not_found(format!("Unknown operation: {name}"))),
+            Removal::InFlight => Err(Status::failed_precondition(format!(
+                "Operation {name} is not done, cancel it first"
+            ))),
+        }
     }
 
     async fn cancel_operation(
         &self,
         request: Request<protos::longrunning::CancelOperationRequest>,
     ) -> Result<Response<()>, Status> {
-        todo!()
+        let name = request.into_inner().name;
+        let op = self
+            .registry
+            .get(&name)
+            .ok_or_else(|| Status::not_found(format!("Unknown operation: {name}")))?;
+        // Cancelling an operation that already finished is a no-op.
+        if !op.done {
+            self.registry.update(super::execution::cancelled_op(op));
+        }
+        Ok(Response::new(()))
     }
 
     async fn wait_operation(
         &self,
         request: Request<protos::longrunning::WaitOperationRequest>,
     ) -> Result<Response<protos::longrunning::Operation>, Status> {
-        todo!()
+        let request = request.into_inner();
+        let name = request.name;
+        let mut updates = self
+            .registry
+            .subscribe(&name)
+            .ok_or_else(|| Status::not_found(format!("Unknown operation: {name}")))?;
+        let timeout = request.timeout.map(|timeout| {
+            Duration::new(timeout.seconds.max(0) as u64, timeout.nanos.max(0) as u32)
+        });
+
+        let wait = updates.wait_for(|op| op.done);
+        // Whether the operation finished, timed out or was deleted, report its latest state.
+        match timeout {
+            Some(timeout) => {
+                let _ = tokio::time::timeout(timeout, wait).await;
+            }
+            None => {
+                let _ = wait.await;
+            }
+        }
+        let op = updates.borrow().clone();
+        Ok(Response::new(op))
     }
 }
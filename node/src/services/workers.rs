@@ -0,0 +1,116 @@
+use execution_engine::remote::{Remote, WorkerInfo, WorkerTask};
+use protos::worker::{node_message, worker_message, NodeMessage, WorkerMessage};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{event, Level};
+use uuid::Uuid;
+
+use crate::worker::protocol;
+
+/// Leases actions to workers connected to this node.
+pub struct WorkersService {
+    remote: Remote,
+    /// Secret shared with the workers, which they send as a bearer token.
+    token: String,
+}
+
+impl WorkersService {
+    pub fn new(remote: Remote, token: String) -> Self {
+        WorkersService { remote, token }
+    }
+
+    fn authenticated<T>(&self, request: &Request<T>) -> bool {
+        request
+            .metadata()
+            .get(protocol::AUTHORIZATION)
+            .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "))
+            .is_some_and(|token| constant_time_eq(token, self.token.as_bytes()))
+    }
+}
+
+/// Compare secrets without leaking where they differ through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[tonic::async_trait]
+impl protos::Workers for WorkersService {
+    type WorkStream = ReceiverStream<Result<NodeMessage, Status>>;
+
+    async fn work(
+        &self,
+        request: Request<Streaming<WorkerMessage>>,
+    ) -> Result<Response<Self::WorkStream>, Status> {
+        if !self.authenticated(&request) {
+            return Err(Status::unauthenticated("Invalid worker token."));
+        }
+        let mut messages = request.into_inner();
+        let Some(worker_message::Message::Register(register)) = messages
+            .message()
+            .await?
+            .and_then(|message| message.message)
+        else {
+            return Err(Status::invalid_argument("Workers must register first."));
+        };
+        if register.capacity == 0 {
+            return Err(Status::invalid_argument("Workers must have capacity."));
+        }
+        let (worker, mut tasks) = self.remote.connect(WorkerInfo {
+            name: register.name,
+            capacity: register.capacity as usize,
+            platform: register.platform.unwrap_or_default().into(),
+        });
+
+        // Hand tasks to the worker until it disconnects.
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(async move {
+            while let Some(task) = tasks.recv().await {
+                let message = match task {
+                    WorkerTask::Run {
+                        id,
+                        command,
+                        layout,
+                    } => node_message::Message::Lease(protocol::lease(id, *command, *layout)),
+                    WorkerTask::Cancel { id } => {
+                        node_message::Message::Cancel(protos::worker::Cancel { id: id.to_string() })
+                    }
+                };
+                let message = NodeMessage {
+                    message: Some(message),
+                };
+                if tx.send(Ok(message)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        // Pass on results until the worker disconnects, which drops `worker` and
+        // has what it was running leased to others.
+        tokio::spawn(async move {
+            while let Ok(Some(message)) = messages.message().await {
+                match message.message {
+                    Some(worker_message::Message::Result(result)) => {
+                        match protocol::parse_execution_result(result) {
+                            Ok((id, result)) => worker.complete(id, result),
+                            Err(err) => {
+                                event!(Level::WARN, %err, "ignoring invalid result from worker")
+                            }
+                        }
+                    }
+                    Some(worker_message::Message::Output(output)) => {
+                        match Uuid::parse_str(&output.id) {
+                            Ok(id) => worker.output(id, &output.stdout, &output.stderr),
+                            Err(err) => {
+                                event!(Level::WARN, %err, "ignoring output with invalid id")
+                            }
+                        }
+                    }
+                    _ => event!(Level::WARN, "ignoring unexpected message from worker"),
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
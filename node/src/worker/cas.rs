@@ -0,0 +1,338 @@
+use cas::{BlobReader, BlobWriter, CasError, ContentAddressableStorage, Hashing};
+use common::Digest;
+use protos::{ByteStreamClient, ContentAddressableStorageClient};
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tonic::transport::Channel;
+use tonic::Streaming;
+use uuid::Uuid;
+
+/// Blobs bigger than this are streamed over ByteStream, as batch requests must
+/// fit in a single gRPC message, which is limited to 4MiB by default.
+const MAX_BATCH_BLOB_SIZE: usize = 1024 * 1024;
+
+/// Most data sent in one `WriteRequest`.
+const WRITE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The ContentAddressableStorage of the node a worker runs actions for.
+#[derive(Clone, Debug)]
+pub struct NodeCas {
+    instance: String,
+    client: ContentAddressableStorageClient<Channel>,
+    bytestream: ByteStreamClient<Channel>,
+}
+
+impl NodeCas {
+    pub fn new(instance: String, channel: Channel) -> Self {
+        NodeCas {
+            instance,
+            client: ContentAddressableStorageClient::new(channel.clone()),
+            bytestream: ByteStreamClient::new(channel),
+        }
+    }
+
+    /// The ByteStream resource name of `path` in the node's instance.
+    fn resource_name(&self, path: String) -> String {
+        match self.instance.is_empty() {
+            true => path,
+            false => format!("{}/{path}", self.instance),
+        }
+    }
+
+    /// Upload a blob too big to batch over ByteStream, a chunk of `data` at a
+    /// time.
+    async fn write_stream(
+        &self,
+        mut data: impl AsyncRead + Send + Unpin + 'static,
+        digest: &Digest,
+    ) -> Result<(), CasError> {
+        let mut resource_name = self.resource_name(format!(
+            "uploads/{}/blobs/{}/{}",
+            Uuid::new_v4(),
+            digest.hash(),
+            digest.size_bytes()
+        ));
+        let size = digest.size_bytes();
+        let (tx, rx) = mpsc::channel(32);
+        let reading = tokio::spawn(async move {
+            let mut offset = 0;
+            while offset < size {
+                let mut chunk = Vec::with_capacity(WRITE_CHUNK_SIZE);
+                let read = (&mut data)
+                    .take(WRITE_CHUNK_SIZE as u64)
+                    .read_to_end(&mut chunk)
+                    .await?;
+                if read == 0 {
+                    return Err(CasError::Remote(String::from(
+                        "Blob is shorter than its digest",
+                    )));
+                }
+                let request = protos::bytestream::WriteRequest {
+                    // Only the first request needs to name the resource.
+                    resource_name: std::mem::take(&mut resource_name),
+                    write_offset: offset,
+                    finish_write: offset + read as i64 == size,
+                    data: chunk,
+                };
+                offset += read as i64;
+                // The node ended the write early, and says why in its response.
+                if tx.send(request).await.is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+        let written = self.bytestream.clone().write(ReceiverStream::new(rx)).await;
+        reading
+            .await
+            .map_err(|e| CasError::Remote(e.to_string()))??;
+        written.map_err(remote_error)?;
+        Ok(())
+    }
+
+    /// Download a blob too big to batch over ByteStream, from `offset` on.
+    async fn read_stream(&self, digest: &Digest, offset: u64) -> Result<BlobReader, CasError> {
+        let request = protos::bytestream::ReadRequest {
+            resource_name: self.resource_name(format!(
+                "blobs/{}/{}",
+                digest.hash(),
+                digest.size_bytes()
+            )),
+            read_offset: offset as i64,
+            read_limit: 0,
+        };
+        let stream = self
+            .bytestream
+            .clone()
+            .read(request)
+            .await
+            .map_err(|status| match status.code() {
+                tonic::Code::NotFound => CasError::BlobNotFound(digest.clone()),
+                _ => remote_error(status),
+            })?
+            .into_inner();
+        Ok(Box::new(ReadStream {
+            stream,
+            chunk: vec![],
+            read: 0,
+        }))
+    }
+}
+
+/// The data of a ByteStream `Read`, as it arrives.
+struct ReadStream {
+    stream: Streaming<protos::bytestream::ReadResponse>,
+    /// The last chunk received, of which `read` bytes were read so far.
+    chunk: Vec<u8>,
+    read: usize,
+}
+
+impl AsyncRead for ReadStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.read == self.chunk.len() {
+            match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Ready(Some(Ok(response))) => {
+                    self.chunk = response.data;
+                    self.read = 0;
+                }
+                Poll::Ready(Some(Err(status))) => {
+                    return Poll::Ready(Err(io::Error::other(status.message().to_string())))
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = buf.remaining().min(self.chunk.len() - self.read);
+        buf.put_slice(&self.chunk[self.read..self.read + len]);
+        self.read += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A blob written to the node's CAS. ByteStream uploads are named by the digest
+/// of the blob, so it is staged in a temporary file until it is committed and
+/// its digest known.
+struct NodeWriter {
+    cas: NodeCas,
+    tmp_path: PathBuf,
+    file: Hashing<fs::File>,
+}
+
+impl AsyncWrite for NodeWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.file).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_shutdown(cx)
+    }
+}
+
+#[tonic::async_trait]
+impl BlobWriter for NodeWriter {
+    async fn commit(mut self: Box<Self>, expected: Option<Digest>) -> Result<Digest, CasError> {
+        self.file.flush().await?;
+        let actual_digest = self.file.digest();
+        if let Some(expected_digest) = expected {
+            if actual_digest != expected_digest {
+                return Err(CasError::InvalidDigest(actual_digest, expected_digest));
+            }
+        }
+        if actual_digest.size_bytes() <= MAX_BATCH_BLOB_SIZE as i64 {
+            let data = fs::read(&self.tmp_path).await?;
+            return self.cas.write_blob(&data, Some(actual_digest)).await;
+        }
+        let file = fs::File::open(&self.tmp_path).await?;
+        self.cas.write_stream(file, &actual_digest).await?;
+        Ok(actual_digest)
+    }
+}
+
+impl Drop for NodeWriter {
+    fn drop(&mut self) {
+        let tmp_path = std::mem::take(&mut self.tmp_path);
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(move || std::fs::remove_file(tmp_path));
+            }
+            Err(_) => {
+                let _ = std::fs::remove_file(tmp_path);
+            }
+        }
+    }
+}
+
+fn remote_error(status: tonic::Status) -> CasError {
+    CasError::Remote(status.message().to_string())
+}
+
+#[tonic::async_trait]
+impl ContentAddressableStorage for NodeCas {
+    async fn write_blob(&self, data: &[u8], digest: Option<Digest>) -> Result<Digest, CasError> {
+        let actual_digest = cas::digest_of(data);
+        if let Some(expected_digest) = digest {
+            if expected_digest != actual_digest {
+                return Err(CasError::InvalidDigest(actual_digest, expected_digest));
+            }
+        }
+        if data.len() > MAX_BATCH_BLOB_SIZE {
+            let data = io::Cursor::new(data.to_vec());
+            self.write_stream(data, &actual_digest).await?;
+            return Ok(actual_digest);
+        }
+        let request = protos::re::BatchUpdateBlobsRequest {
+            instance_name: self.instance.clone(),
+            requests: vec![protos::re::batch_update_blobs_request::Request {
+                digest: Some(actual_digest.clone().into()),
+                data: data.to_vec(),
+                compressor: Default::default(),
+            }],
+        };
+        let response = self
+            .client
+            .clone()
+            .batch_update_blobs(request)
+            .await
+            .map_err(remote_error)?
+            .into_inner();
+        let status = response
+            .responses
+            .into_iter()
+            .next()
+            .and_then(|response| response.status)
+            .unwrap_or_default();
+        if status.code != protos::rpc::Code::Ok as i32 {
+            return Err(CasError::Remote(status.message));
+        }
+        Ok(actual_digest)
+    }
+
+    async fn read_blob(&self, digest: Digest) -> Result<Vec<u8>, CasError> {
+        if !self.has_blob(&digest).await? {
+            return Err(CasError::BlobNotFound(digest));
+        }
+        if digest.size_bytes() > MAX_BATCH_BLOB_SIZE as i64 {
+            let mut data = Vec::with_capacity(digest.size_bytes() as usize);
+            self.read_stream(&digest, 0)
+                .await?
+                .read_to_end(&mut data)
+                .await?;
+            return Ok(data);
+        }
+        let request = protos::re::BatchReadBlobsRequest {
+            instance_name: self.instance.clone(),
+            digests: vec![digest.clone().into()],
+            acceptable_compressors: vec![],
+        };
+        let response = self
+            .client
+            .clone()
+            .batch_read_blobs(request)
+            .await
+            .map_err(remote_error)?
+            .into_inner();
+        let response = response
+            .responses
+            .into_iter()
+            .next()
+            .ok_or(CasError::BlobNotFound(digest))?;
+        let status = response.status.unwrap_or_default();
+        if status.code != protos::rpc::Code::Ok as i32 {
+            return Err(CasError::Remote(status.message));
+        }
+        Ok(response.data)
+    }
+
+    async fn has_blob(&self, digest: &Digest) -> Result<bool, CasError> {
+        let request = protos::re::FindMissingBlobsRequest {
+            instance_name: self.instance.clone(),
+            blob_digests: vec![digest.clone().into()],
+        };
+        let response = self
+            .client
+            .clone()
+            .find_missing_blobs(request)
+            .await
+            .map_err(remote_error)?
+            .into_inner();
+        Ok(response.missing_blob_digests.is_empty())
+    }
+
+    async fn blob_writer(&self) -> Result<Box<dyn BlobWriter>, CasError> {
+        let tmp_path = std::env::temp_dir().join(format!("oryx-blob-{}", Uuid::new_v4()));
+        let file = fs::File::create(&tmp_path).await?;
+        Ok(Box::new(NodeWriter {
+            cas: self.clone(),
+            tmp_path,
+            file: Hashing::new(file),
+        }))
+    }
+
+    async fn blob_reader(&self, digest: Digest, offset: u64) -> Result<BlobReader, CasError> {
+        if digest.size_bytes() > MAX_BATCH_BLOB_SIZE as i64 {
+            return self.read_stream(&digest, offset).await;
+        }
+        let mut reader = io::Cursor::new(self.read_blob(digest).await?);
+        reader.set_position(offset);
+        Ok(Box::new(reader))
+    }
+}
@@ -0,0 +1,232 @@
+//! Run actions leased by a node on this machine.
+
+use execution_engine::cgroup::CgroupConfig;
+use execution_engine::logs::Log;
+use execution_engine::sandbox::SandboxConfig;
+use execution_engine::{Command, DirectoryLayout, ExecuteError, ExecutionBackend, Platform};
+use protos::worker::{node_message, worker_message, NodeMessage, WorkerMessage};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::Channel;
+use tonic::{Request, Streaming};
+use tracing::{event, Level};
+use uuid::Uuid;
+
+mod cas;
+pub(crate) mod protocol;
+
+pub use self::cas::NodeCas;
+use crate::services::collect_inputs;
+use crate::ExecutionEngine;
+
+#[derive(Debug, Deserialize)]
+pub struct WorkerConfig {
+    /// Instance of the node to work for.
+    pub instance: String,
+    /// Name the node knows this worker by.
+    pub name: String,
+    /// Secret shared with the node, its `execution.worker_token`.
+    pub token: String,
+    /// How many actions to run at once.
+    pub capacity: u32,
+    /// How actions are run on this worker.
+    pub execution_engine: ExecutionEngine,
+    /// Properties of this machine, e.g. `OSFamily`, `ISA` or a pool name.
+    #[serde(default)]
+    pub platform: Platform,
+    /// What actions can see of this machine with the sandbox engine.
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+    /// Limits on what actions run on this worker use.
+    #[serde(default)]
+    pub cgroup: CgroupConfig,
+}
+
+/// A worker registered with a node.
+pub struct Worker {
+    config: WorkerConfig,
+    channel: Channel,
+    results: mpsc::Sender<WorkerMessage>,
+    messages: Streaming<NodeMessage>,
+}
+
+impl Worker {
+    /// Register with the node at the other end of `channel`, which leases
+    /// actions to the worker from then on.
+    pub async fn register(
+        config: WorkerConfig,
+        channel: Channel,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let (results, rx) = mpsc::channel(32);
+        results
+            .send(WorkerMessage {
+                message: Some(worker_message::Message::Register(
+                    protos::worker::Register {
+                        name: config.name.clone(),
+                        capacity: config.capacity,
+                        platform: Some((&config.platform).into()),
+                    },
+                )),
+            })
+            .await?;
+        let mut request = Request::new(ReceiverStream::new(rx));
+        request.metadata_mut().insert(
+            protocol::AUTHORIZATION,
+            format!("Bearer {}", config.token).parse()?,
+        );
+        let mut client = protos::WorkersClient::new(channel.clone());
+        let messages = client.work(request).await?.into_inner();
+        event!(Level::INFO, name = config.name, "registered with node");
+        Ok(Worker {
+            config,
+            channel,
+            results,
+            messages,
+        })
+    }
+
+    /// Run the actions leased by the node until it hangs up.
+    pub async fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+        let cas = NodeCas::new(self.config.instance.clone(), self.channel.clone());
+        let platform = self.config.platform.clone();
+        let cgroups = self.config.cgroup.clone();
+        match self.config.execution_engine {
+            ExecutionEngine::Insecure => {
+                let backend =
+                    execution_engine::insecure::Insecure::new(cas.clone(), platform, cgroups)?;
+                self.work(cas, backend).await
+            }
+            ExecutionEngine::Hermetic => {
+                let backend =
+                    execution_engine::hermetic::Hermetic::new(cas.clone(), platform, cgroups)?;
+                self.work(cas, backend).await
+            }
+            ExecutionEngine::Sandbox => {
+                let backend = execution_engine::sandbox::Sandbox::new(
+                    cas.clone(),
+                    platform,
+                    self.config.sandbox.clone(),
+                    cgroups,
+                )?;
+                self.work(cas, backend).await
+            }
+            ExecutionEngine::Remote => Err("workers can't hand actions on to other workers".into()),
+        }
+    }
+
+    async fn work<B: ExecutionBackend>(
+        mut self,
+        cas: NodeCas,
+        backend: B,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        while let Some(NodeMessage { message }) = self.messages.message().await? {
+            match message {
+                Some(node_message::Message::Lease(lease)) => {
+                    let Ok(id) = Uuid::parse_str(&lease.id) else {
+                        event!(Level::WARN, id = lease.id, "ignoring lease with invalid id");
+                        continue;
+                    };
+                    let cas = cas.clone();
+                    let backend = backend.clone();
+                    let results = self.results.clone();
+                    tokio::spawn(async move {
+                        let result = match lay_out_lease(cas, lease).await {
+                            Ok((command, layout)) => {
+                                let output = command.output.clone();
+                                let forwarding = [
+                                    forward_output(id, output.stdout.clone(), true, &results),
+                                    forward_output(id, output.stderr.clone(), false, &results),
+                                ];
+                                let result = backend.run_command(id, command, layout).await;
+                                // The node gets all the output before the result.
+                                output.close();
+                                for forwarding in forwarding {
+                                    let _ = forwarding.await;
+                                }
+                                result
+                            }
+                            Err(err) => Err(err),
+                        };
+                        let result = protocol::execution_result(id, result);
+                        // The node re-leases the action elsewhere if it can't be told.
+                        let _ = results
+                            .send(WorkerMessage {
+                                message: Some(worker_message::Message::Result(result)),
+                            })
+                            .await;
+                    });
+                }
+                Some(node_message::Message::Cancel(cancel)) => {
+                    if let Ok(id) = Uuid::parse_str(&cancel.id) {
+                        backend.cancel(id).await?;
+                    }
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The command a worker was leased, with the entries of its input root
+/// collected from the node's CAS.
+async fn lay_out_lease(
+    cas: NodeCas,
+    lease: protos::worker::Lease,
+) -> Result<(Command, DirectoryLayout), ExecuteError> {
+    let (command, mut layout) = protocol::parse_lease(lease)?;
+    if let Some(root_digest) = layout.input_root_digest.clone() {
+        collect_inputs(cas, root_digest, &mut layout).await?;
+    }
+    Ok((command, layout))
+}
+
+/// Send the node what the command of execution `id` writes to `log`, its stdout
+/// or else its stderr, as it runs.
+fn forward_output(
+    id: Uuid,
+    log: Log,
+    stdout: bool,
+    results: &mpsc::Sender<WorkerMessage>,
+) -> JoinHandle<()> {
+    let results = results.clone();
+    let mut reader = log.reader(0);
+    tokio::spawn(async move {
+        loop {
+            let data = match reader.next().await {
+                Ok(Some(data)) => data,
+                Ok(None) => break,
+                // The whole output still reaches the node with the result.
+                Err(err) => {
+                    event!(Level::WARN, %err, %id, "stopped forwarding output");
+                    break;
+                }
+            };
+            let (stdout, stderr) = match stdout {
+                true => (data, vec![]),
+                false => (vec![], data),
+            };
+            let output = protos::worker::Output {
+                id: id.to_string(),
+                stdout,
+                stderr,
+            };
+            let message = WorkerMessage {
+                message: Some(worker_message::Message::Output(output)),
+            };
+            if results.send(message).await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+/// Run actions for the node at the other end of `channel` until it hangs up.
+pub async fn run_worker(
+    config: WorkerConfig,
+    channel: Channel,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Worker::register(config, channel).await?.run().await
+}
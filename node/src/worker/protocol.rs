@@ -0,0 +1,254 @@
+//! Translation between the engine's types and the messages of the worker protocol.
+
+use execution_engine::{
+    Command, DirectoryLayout, Entry, ExecuteError, ExecuteResponse, OutputKind, OutputProperties,
+};
+use protos::worker;
+use std::path::PathBuf;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Metadata carrying the token workers authenticate with, as `Bearer <token>`.
+pub(crate) const AUTHORIZATION: &str = "authorization";
+
+/// The lease handing a command to a worker.
+pub(crate) fn lease(id: Uuid, command: Command, layout: DirectoryLayout) -> worker::Lease {
+    worker::Lease {
+        id: id.to_string(),
+        arguments: command.arguments,
+        environment_variables: command
+            .env_vars
+            .into_iter()
+            .map(|(name, value)| protos::re::command::EnvironmentVariable { name, value })
+            .collect(),
+        timeout: command
+            .timeout
+            .and_then(|timeout| prost_types::Duration::try_from(timeout).ok()),
+        input_root_digest: layout.input_root_digest.map(Into::into),
+        output_node_properties: layout.output_properties.keys(),
+        output_paths: layout
+            .output_paths
+            .into_iter()
+            .map(|path| path.display().to_string())
+            .collect(),
+        platform: Some((&command.platform).into()),
+        working_directory: command.working_directory.display().to_string(),
+        max_inline_output_bytes: command.max_inline_output_bytes,
+    }
+}
+
+/// The command and inputs a worker was leased. The entries of the input root
+/// are left for the worker to collect.
+pub(crate) fn parse_lease(
+    lease: worker::Lease,
+) -> Result<(Command, DirectoryLayout), ExecuteError> {
+    let timeout = lease
+        .timeout
+        .map(Duration::try_from)
+        .transpose()
+        .map_err(|e| ExecuteError::InvalidArgument(format!("Invalid lease timeout: {e}")))?;
+    let command = Command {
+        arguments: lease.arguments,
+        env_vars: lease
+            .environment_variables
+            .into_iter()
+            .map(|ev| (ev.name, ev.value))
+            .collect(),
+        timeout,
+        platform: lease.platform.unwrap_or_default().into(),
+        working_directory: lease.working_directory.into(),
+        max_inline_output_bytes: lease.max_inline_output_bytes,
+        // Forwarded to the node by the worker as the command runs.
+        output: Default::default(),
+    };
+    let layout = DirectoryLayout {
+        input_root_digest: lease.input_root_digest.map(Into::into),
+        entries: vec![],
+        output_paths: lease.output_paths.into_iter().map(PathBuf::from).collect(),
+        // The node makes sure outputs are of the kind declared.
+        output_kinds: Default::default(),
+        output_properties: OutputProperties::parse(&lease.output_node_properties)?,
+    };
+    Ok((command, layout))
+}
+
+/// The report of how a leased command went.
+pub(crate) fn execution_result(
+    id: Uuid,
+    result: Result<ExecuteResponse, ExecuteError>,
+) -> worker::ExecutionResult {
+    let status = |code: protos::rpc::Code, message: String| {
+        Some(protos::rpc::Status {
+            code: code.into(),
+            message,
+            ..Default::default()
+        })
+    };
+    let (status, response, missing_blob) = match result {
+        Ok(response) => (status(protos::rpc::Code::Ok, String::new()), response, None),
+        Err(ExecuteError::DeadlineExceeded(response)) => (
+            status(protos::rpc::Code::DeadlineExceeded, String::new()),
+            *response,
+            None,
+        ),
+        Err(ExecuteError::OutOfMemory(response)) => (
+            status(protos::rpc::Code::ResourceExhausted, String::new()),
+            *response,
+            None,
+        ),
+        Err(err) => {
+            let code = match &err {
+                ExecuteError::InvalidArgument(_) => protos::rpc::Code::InvalidArgument,
+                ExecuteError::BlobNotFound(_) | ExecuteError::AbsoluteOutputSymlink(_) => {
+                    protos::rpc::Code::FailedPrecondition
+                }
+                ExecuteError::Cancelled => protos::rpc::Code::Cancelled,
+                _ => protos::rpc::Code::Internal,
+            };
+            // The message of a failed precondition other than a missing blob is the
+            // offending output symlink.
+            let message = match &err {
+                ExecuteError::AbsoluteOutputSymlink(path) => path.display().to_string(),
+                _ => err.to_string(),
+            };
+            let missing_blob = match &err {
+                ExecuteError::BlobNotFound(digest) => Some(digest.clone().into()),
+                _ => None,
+            };
+            let response = ExecuteResponse {
+                exit_status: 0,
+                output_paths: vec![],
+                stderr: vec![],
+                stdout: vec![],
+                stderr_digest: None,
+                stdout_digest: None,
+                usage: None,
+                metadata: Default::default(),
+            };
+            (status(code, message), response, missing_blob)
+        }
+    };
+    worker::ExecutionResult {
+        id: id.to_string(),
+        status,
+        exit_code: response.exit_status,
+        output_entries: response.output_paths.into_iter().map(entry).collect(),
+        stdout: response.stdout,
+        stderr: response.stderr,
+        stdout_digest: response.stdout_digest.map(Into::into),
+        stderr_digest: response.stderr_digest.map(Into::into),
+        missing_blob,
+        usage: response.usage.as_ref().map(Into::into),
+        metadata: Some((&response.metadata).into()),
+    }
+}
+
+/// The id of the execution a worker reported on, and how it went. Results that
+/// can't be parsed are only logged, so they fail with just a message.
+pub(crate) fn parse_execution_result(
+    result: worker::ExecutionResult,
+) -> Result<(Uuid, Result<ExecuteResponse, ExecuteError>), String> {
+    let id = Uuid::parse_str(&result.id).map_err(|e| format!("Invalid execution id: {e}"))?;
+    let status = result.status.unwrap_or_default();
+    let response = ExecuteResponse {
+        exit_status: result.exit_code,
+        output_paths: result
+            .output_entries
+            .into_iter()
+            .map(parse_entry)
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?,
+        stderr: result.stderr,
+        stdout: result.stdout,
+        stderr_digest: result.stderr_digest.map(Into::into),
+        stdout_digest: result.stdout_digest.map(Into::into),
+        usage: result.usage.map(Into::into),
+        metadata: result.metadata.unwrap_or_default().into(),
+    };
+    let outcome = match protos::rpc::Code::from_i32(status.code) {
+        Some(protos::rpc::Code::Ok) => Ok(response),
+        Some(protos::rpc::Code::DeadlineExceeded) => {
+            Err(ExecuteError::DeadlineExceeded(Box::new(response)))
+        }
+        Some(protos::rpc::Code::ResourceExhausted) => {
+            Err(ExecuteError::OutOfMemory(Box::new(response)))
+        }
+        Some(protos::rpc::Code::Cancelled) => Err(ExecuteError::Cancelled),
+        Some(protos::rpc::Code::InvalidArgument) => {
+            Err(ExecuteError::InvalidArgument(status.message))
+        }
+        Some(protos::rpc::Code::FailedPrecondition) => match result.missing_blob {
+            Some(digest) => Err(ExecuteError::BlobNotFound(digest.into())),
+            None => Err(ExecuteError::AbsoluteOutputSymlink(status.message.into())),
+        },
+        _ => Err(ExecuteError::Internal(status.message)),
+    };
+    Ok((id, outcome))
+}
+
+fn entry(entry: Entry) -> worker::Entry {
+    let entry = match entry {
+        Entry::File {
+            path,
+            digest,
+            executable,
+            properties,
+        } => worker::entry::Entry::File(worker::entry::File {
+            path: path.display().to_string(),
+            digest: Some(digest.into()),
+            is_executable: executable,
+            node_properties: properties.into(),
+        }),
+        Entry::Directory {
+            path,
+            digest,
+            properties,
+        } => worker::entry::Entry::Directory(worker::entry::Directory {
+            path: path.display().to_string(),
+            digest: Some(digest.into()),
+            node_properties: properties.into(),
+        }),
+        Entry::Symlink {
+            original,
+            link,
+            points_to,
+        } => worker::entry::Entry::Symlink(worker::entry::Symlink {
+            original: original.display().to_string(),
+            link: link.display().to_string(),
+            points_to: match points_to {
+                None => worker::entry::Kind::Unspecified,
+                Some(OutputKind::File) => worker::entry::Kind::File,
+                Some(OutputKind::Directory) => worker::entry::Kind::Directory,
+            }
+            .into(),
+        }),
+    };
+    worker::Entry { entry: Some(entry) }
+}
+
+fn parse_entry(entry: worker::Entry) -> Result<Entry, ExecuteError> {
+    let no_digest = || ExecuteError::InvalidArgument(String::from("no digest in entry"));
+    match entry.entry {
+        Some(worker::entry::Entry::File(file)) => Ok(Entry::File {
+            path: file.path.into(),
+            digest: file.digest.ok_or_else(no_digest)?.into(),
+            executable: file.is_executable,
+            properties: file.node_properties.into(),
+        }),
+        Some(worker::entry::Entry::Directory(directory)) => Ok(Entry::Directory {
+            path: directory.path.into(),
+            digest: directory.digest.ok_or_else(no_digest)?.into(),
+            properties: directory.node_properties.into(),
+        }),
+        Some(worker::entry::Entry::Symlink(symlink)) => Ok(Entry::Symlink {
+            points_to: match symlink.points_to() {
+                worker::entry::Kind::Unspecified => None,
+                worker::entry::Kind::File => Some(OutputKind::File),
+                worker::entry::Kind::Directory => Some(OutputKind::Directory),
+            },
+            original: symlink.original.into(),
+            link: symlink.link.into(),
+        }),
+        None => Err(ExecuteError::InvalidArgument(String::from("empty entry"))),
+    }
+}
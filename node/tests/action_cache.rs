@@ -0,0 +1,135 @@
+use crate::{oryx_test, oryx_test_with_storage};
+use common::Digest;
+use gemsbok::Gemsbok;
+use node_lib::StorageBackend;
+use std::str::FromStr;
+use tonic::{Code, Request};
+
+#[tokio::test]
+async fn missing_action_result() {
+    let action_digest: protos::re::Digest = Digest::from_str("aaaa:5").unwrap().into();
+
+    oryx_test(|channel| async move {
+        let mut client = protos::ActionCacheClient::new(channel);
+        let status = client
+            .get_action_result(Request::new(protos::re::GetActionResultRequest {
+                instance_name: "".to_string(),
+                action_digest: Some(action_digest),
+                ..Default::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn update_then_get_action_result() {
+    let action_digest: protos::re::Digest = Digest::from_str("aaaa:5").unwrap().into();
+
+    oryx_test(|channel| async move {
+        let mut gemsbok = Gemsbok::new(channel.clone());
+        let stdout_digest = gemsbok.upload_blob(b"etosha\n").await.unwrap();
+        let action_result = protos::re::ActionResult {
+            exit_code: 0,
+            stdout_digest: Some(stdout_digest.into()),
+            ..Default::default()
+        };
+
+        let mut client = protos::ActionCacheClient::new(channel);
+        let updated = client
+            .update_action_result(Request::new(protos::re::UpdateActionResultRequest {
+                instance_name: "".to_string(),
+                action_digest: Some(action_digest.clone()),
+                action_result: Some(action_result.clone()),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(updated, action_result);
+
+        let cached = client
+            .get_action_result(Request::new(protos::re::GetActionResultRequest {
+                instance_name: "".to_string(),
+                action_digest: Some(action_digest),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(cached, action_result);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn action_result_with_missing_outputs() {
+    let action_digest: protos::re::Digest = Digest::from_str("aaaa:5").unwrap().into();
+    let missing_digest: protos::re::Digest = Digest::from_str("bbbb:5").unwrap().into();
+
+    oryx_test(|channel| async move {
+        let mut client = protos::ActionCacheClient::new(channel);
+        client
+            .update_action_result(Request::new(protos::re::UpdateActionResultRequest {
+                instance_name: "".to_string(),
+                action_digest: Some(action_digest.clone()),
+                action_result: Some(protos::re::ActionResult {
+                    stdout_digest: Some(missing_digest),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+
+        // The result references a blob that isn't in the CAS, so it can't be served.
+        let status = client
+            .get_action_result(Request::new(protos::re::GetActionResultRequest {
+                instance_name: "".to_string(),
+                action_digest: Some(action_digest),
+                ..Default::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn action_results_are_keyed_by_hash_and_size() {
+    let storage = tempfile::tempdir().unwrap();
+    let backends = [
+        (StorageBackend::InMemory, None),
+        (StorageBackend::OnDisk, Some(storage.path().to_path_buf())),
+    ];
+    for (backend, storage_path) in backends {
+        oryx_test_with_storage(backend, storage_path, |channel| async move {
+            let action_digest: protos::re::Digest = Digest::from_str("aaaa:5").unwrap().into();
+            let other_size: protos::re::Digest = Digest::from_str("aaaa:6").unwrap().into();
+            let mut client = protos::ActionCacheClient::new(channel);
+            client
+                .update_action_result(Request::new(protos::re::UpdateActionResultRequest {
+                    instance_name: "".to_string(),
+                    action_digest: Some(action_digest),
+                    action_result: Some(protos::re::ActionResult::default()),
+                    ..Default::default()
+                }))
+                .await
+                .unwrap();
+
+            let status = client
+                .get_action_result(Request::new(protos::re::GetActionResultRequest {
+                    instance_name: "".to_string(),
+                    action_digest: Some(other_size),
+                    ..Default::default()
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), Code::NotFound);
+        })
+        .await;
+    }
+}
@@ -0,0 +1,259 @@
+use crate::{oryx_test, oryx_test_with_storage};
+use common::Digest;
+use gemsbok::*;
+use node_lib::StorageBackend;
+use protos::bytestream::{QueryWriteStatusRequest, WriteRequest};
+use sha2::{Digest as _, Sha256};
+use std::str::FromStr;
+use tokio_stream::StreamExt;
+use tonic::transport::Channel;
+use tonic::{Code, Status};
+
+fn blob_name(digest: &Digest) -> String {
+    format!("blobs/{}/{}", digest.hash(), digest.size_bytes())
+}
+
+fn upload_name(id: &str, digest: &Digest) -> String {
+    format!("uploads/{id}/{}", blob_name(digest))
+}
+
+/// Read a resource to the end, returning each response's data.
+async fn read(
+    client: &mut Gemsbok,
+    resource_name: &str,
+    offset: i64,
+    limit: i64,
+) -> Result<Vec<Vec<u8>>, Status> {
+    let mut stream = client.read_stream(resource_name, offset, limit).await?;
+    let mut responses = vec![];
+    while let Some(response) = stream.next().await {
+        responses.push(response?.data);
+    }
+    Ok(responses)
+}
+
+/// Send `data` in one `Write` starting at `offset`, returning the committed size.
+async fn write(
+    client: &mut protos::ByteStreamClient<Channel>,
+    resource_name: &str,
+    offset: i64,
+    data: &[u8],
+    finish_write: bool,
+) -> Result<i64, Status> {
+    let request = WriteRequest {
+        resource_name: resource_name.to_string(),
+        write_offset: offset,
+        finish_write,
+        data: data.to_vec(),
+    };
+    let response = client.write(tokio_stream::iter([request])).await?;
+    Ok(response.into_inner().committed_size)
+}
+
+async fn query_write_status(
+    client: &mut protos::ByteStreamClient<Channel>,
+    resource_name: &str,
+) -> Result<(i64, bool), Status> {
+    let request = QueryWriteStatusRequest {
+        resource_name: resource_name.to_string(),
+    };
+    let response = client.query_write_status(request).await?.into_inner();
+    Ok((response.committed_size, response.complete))
+}
+
+#[tokio::test]
+async fn blob_reads_honor_offset_and_limit() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let digest = client.upload_blob(b"swakopmund").await.unwrap();
+        let name = blob_name(&digest);
+
+        let reads = [
+            (0, 0, &b"swakopmund"[..]),
+            (4, 3, b"opm"),
+            (4, 100, b"opmund"),
+            (10, 0, b""),
+        ];
+        for (offset, limit, expected) in reads {
+            let data = read(&mut client, &name, offset, limit).await.unwrap();
+            assert_eq!(data.concat(), expected, "offset {offset} limit {limit}");
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn invalid_blob_reads_are_rejected() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let digest = client.upload_blob(b"swakopmund").await.unwrap();
+        let name = blob_name(&digest);
+
+        let reads = [
+            (11, 0, Code::OutOfRange),
+            (-1, 0, Code::OutOfRange),
+            (0, -1, Code::InvalidArgument),
+        ];
+        for (offset, limit, code) in reads {
+            let status = read(&mut client, &name, offset, limit).await.unwrap_err();
+            assert_eq!(status.code(), code, "offset {offset} limit {limit}");
+        }
+
+        let missing = Digest::from_str(&format!("{}:10", "a".repeat(64))).unwrap();
+        let status = read(&mut client, &blob_name(&missing), 0, 0)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn large_disk_blobs_are_read_in_chunks() {
+    let storage = tempfile::tempdir().unwrap();
+    let blob: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    oryx_test_with_storage(
+        StorageBackend::OnDisk,
+        Some(storage.path().to_path_buf()),
+        |channel| async move {
+            let mut client = Gemsbok::new(channel);
+            let digest = client.upload_blob(&blob).await.unwrap();
+
+            let data = read(&mut client, &blob_name(&digest), 1000, 150_000)
+                .await
+                .unwrap();
+            assert!(data.len() > 1);
+            assert_eq!(data.concat(), &blob[1000..151_000]);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn interrupted_uploads_can_be_resumed() {
+    let digest =
+        Digest::from_str("8aad87ae61d3df48ff6447ca5f5b8670b9d9d080dbbf735be109530a445330e3:10")
+            .unwrap();
+    let name = upload_name("0b7c6a52-6d8e-4a4e-9a43-5f1b8cb1c0d2", &digest);
+    oryx_test(|channel| async move {
+        let mut client = protos::ByteStreamClient::new(channel.clone());
+        let status = query_write_status(&mut client, &name).await.unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        // The first write is cut off before it finishes.
+        write(&mut client, &name, 0, b"swakop", false)
+            .await
+            .unwrap_err();
+        let status = query_write_status(&mut client, &name).await.unwrap();
+        assert_eq!(status, (6, false));
+
+        // Picking up anywhere but where the upload left off is rejected.
+        let status = write(&mut client, &name, 0, b"swakopmund", true)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let committed = write(&mut client, &name, 6, b"mund", true).await.unwrap();
+        assert_eq!(committed, 10);
+        let status = query_write_status(&mut client, &name).await.unwrap();
+        assert_eq!(status, (10, true));
+
+        let mut gemsbok = Gemsbok::new(channel);
+        let data = read(&mut gemsbok, &blob_name(&digest), 0, 0).await.unwrap();
+        assert_eq!(data.concat(), b"swakopmund");
+
+        // Uploading a blob that is already stored finishes straight away.
+        let name = upload_name("5d0b4f0e-2c55-4c3e-8f5e-0d3c8f3c6a7b", &digest);
+        let committed = write(&mut client, &name, 0, b"", false).await.unwrap();
+        assert_eq!(committed, 10);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn uploads_not_matching_their_digest_are_rejected() {
+    let digest =
+        Digest::from_str("8aad87ae61d3df48ff6447ca5f5b8670b9d9d080dbbf735be109530a445330e3:10")
+            .unwrap();
+    let name = upload_name("9f4e3a1c-2b7d-4e8f-a6c5-1d0e9b8a7c6f", &digest);
+    oryx_test(|channel| async move {
+        let mut client = protos::ByteStreamClient::new(channel);
+        let status = write(&mut client, &name, 0, b"walvisbaai", true)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let status = write(&mut client, &name, 0, b"swakopmund!", true)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        // Neither upload left anything behind.
+        let status = query_write_status(&mut client, &name).await.unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let status = write(&mut client, &blob_name(&digest), 0, b"swakopmund", true)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn disk_uploads_are_streamed_into_place() {
+    let storage = tempfile::tempdir().unwrap();
+    let staging = storage.path().join("tmp");
+    let blob: Vec<u8> = (0..200_000u32).map(|i| (i % 241) as u8).collect();
+    oryx_test_with_storage(
+        StorageBackend::OnDisk,
+        Some(storage.path().to_path_buf()),
+        |channel| async move {
+            let mut client = protos::ByteStreamClient::new(channel.clone());
+            let hash = base16ct::lower::encode_string(&Sha256::digest(&blob));
+            let digest = Digest::from_str(&format!("{hash}:{}", blob.len())).unwrap();
+            let name = upload_name("3e9d2c4b-8a71-4f06-b5d3-7c2e1a9f0b84", &digest);
+            let requests: Vec<_> = blob
+                .chunks(64 * 1024)
+                .scan(0, |offset, chunk| {
+                    let request = WriteRequest {
+                        resource_name: name.clone(),
+                        write_offset: *offset,
+                        finish_write: *offset + chunk.len() as i64 == blob.len() as i64,
+                        data: chunk.to_vec(),
+                    };
+                    *offset += chunk.len() as i64;
+                    Some(request)
+                })
+                .collect();
+            let response = client
+                .write(tokio_stream::iter(requests))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.committed_size, blob.len() as i64);
+
+            let mut gemsbok = Gemsbok::new(channel);
+            let data = read(&mut gemsbok, &blob_name(&digest), 0, 0).await.unwrap();
+            assert_eq!(data.concat(), blob);
+
+            // Writes that fail their digest check leave nothing staged behind.
+            let mut wrong = blob.clone();
+            wrong[0] ^= 1;
+            let wrong_digest = Digest::from_str(&format!("{}:200000", "a".repeat(64))).unwrap();
+            let name = upload_name("c1f7a0d2-5b3e-4e9a-8d6c-2f4b0e7a9c13", &wrong_digest);
+            let requests = [WriteRequest {
+                resource_name: name,
+                write_offset: 0,
+                finish_write: true,
+                data: wrong,
+            }];
+            let status = client
+                .write(tokio_stream::iter(requests))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+            assert_eq!(std::fs::read_dir(&staging).unwrap().count(), 0);
+        },
+    )
+    .await;
+}
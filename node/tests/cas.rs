@@ -1,6 +1,7 @@
-use crate::oryx_test;
+use crate::{oryx_test, oryx_test_with_storage};
 use common::Digest;
 use futures::Future;
+use node_lib::StorageBackend;
 use std::str::FromStr;
 use tonic::Request;
 
@@ -122,3 +123,69 @@ async fn bad_blob_gives_invalid_argument() {
     })
     .await;
 }
+
+#[tokio::test]
+async fn disk_blobs_survive_restart() {
+    use protos::re::batch_update_blobs_request::Request as BlobRequest;
+
+    let storage = tempfile::tempdir().unwrap();
+    let digest =
+        Digest::from_str("8aad87ae61d3df48ff6447ca5f5b8670b9d9d080dbbf735be109530a445330e3:10")
+            .unwrap();
+
+    let upload_digest = digest.clone();
+    oryx_test_with_storage(
+        StorageBackend::OnDisk,
+        Some(storage.path().to_path_buf()),
+        |channel| async move {
+            let mut client = protos::ContentAddressableStorageClient::new(channel);
+            let response = client
+                .batch_update_blobs(Request::new(protos::re::BatchUpdateBlobsRequest {
+                    requests: vec![BlobRequest {
+                        digest: Some(upload_digest.into()),
+                        data: b"swakopmund".to_vec(),
+                        compressor: Default::default(),
+                    }],
+                    instance_name: "".to_string(),
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(
+                response.responses[0].status.clone().unwrap().code,
+                protos::rpc::Code::Ok as i32
+            );
+        },
+    )
+    .await;
+
+    // A fresh node pointed at the same storage path should still have the blob.
+    oryx_test_with_storage(
+        StorageBackend::OnDisk,
+        Some(storage.path().to_path_buf()),
+        |channel| async move {
+            let mut client = protos::ContentAddressableStorageClient::new(channel);
+            let response = client
+                .find_missing_blobs(Request::new(protos::re::FindMissingBlobsRequest {
+                    blob_digests: vec![digest.clone().into()],
+                    instance_name: "".to_string(),
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.missing_blob_digests, vec![]);
+
+            let response = client
+                .batch_read_blobs(Request::new(protos::re::BatchReadBlobsRequest {
+                    instance_name: "".to_string(),
+                    acceptable_compressors: vec![],
+                    digests: vec![digest.into()],
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.responses[0].data, b"swakopmund".to_vec());
+        },
+    )
+    .await;
+}
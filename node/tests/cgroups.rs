@@ -0,0 +1,140 @@
+use crate::oryx_test_with_config;
+use gemsbok::*;
+use node_lib::{CgroupConfig, EngineConfig};
+use prost::Message;
+use protos::rpc::Code;
+use std::path::PathBuf;
+use tonic::transport::Channel;
+
+/// A cgroup for the actions of one test, removed once the test is done.
+struct TestCgroup {
+    path: PathBuf,
+}
+
+impl TestCgroup {
+    /// Create a cgroup named `name`, unless cgroup v2 isn't mounted or can't be
+    /// written to here, in which case the test is skipped.
+    fn new(name: &str) -> Option<Self> {
+        let mounts = std::fs::read_to_string("/proc/self/mounts").ok()?;
+        let mount = mounts
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>())
+            .find(|fields| fields.get(2) == Some(&"cgroup2"))?[1]
+            .to_string();
+        let path = PathBuf::from(mount).join(format!("oryx-{name}-{}", std::process::id()));
+        std::fs::create_dir(&path).ok()?;
+        Some(TestCgroup { path })
+    }
+
+    fn has_controller(&self, controller: &str) -> bool {
+        std::fs::read_to_string(self.path.join("cgroup.controllers"))
+            .unwrap_or_default()
+            .split_whitespace()
+            .any(|c| c == controller)
+    }
+
+    fn config(&self) -> EngineConfig {
+        EngineConfig {
+            cgroup: CgroupConfig {
+                parent: Some(self.path.clone()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+}
+
+impl Drop for TestCgroup {
+    fn drop(&mut self) {
+        // The leaves actions ran in are removed in the background, and have to
+        // be gone first.
+        for _ in 0..100 {
+            if std::fs::remove_dir(&self.path).is_ok() {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        std::fs::remove_dir(&self.path).unwrap();
+    }
+}
+
+/// Run a shell command requiring `platform`, which is to write `out.txt`,
+/// returning the server's response.
+async fn execute(
+    channel: Channel,
+    script: &str,
+    platform: &[(&str, &str)],
+) -> protos::re::ExecuteResponse {
+    let mut client = Gemsbok::new(channel);
+    let command_digest = client
+        .add_command(&["/bin/sh", "-c", script], &["out.txt"])
+        .await
+        .unwrap();
+    let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+    let action_digest = client
+        .add_action_with_platform(command_digest, root_dir_digest, platform)
+        .await
+        .unwrap();
+    client.execute_response(action_digest).await.unwrap()
+}
+
+#[tokio::test]
+async fn actions_run_in_cgroups_and_report_usage() {
+    let Some(cgroup) = TestCgroup::new("usage") else {
+        return;
+    };
+    oryx_test_with_config(cgroup.config(), |channel| async move {
+        let script = "cat /proc/self/cgroup; touch out.txt; i=0; while [ $i -lt 10000 ]; do i=$((i+1)); done";
+        let response = execute(channel, script, &[]).await;
+        assert_eq!(response.status.unwrap().code, Code::Ok as i32);
+
+        // The action ran in a cgroup of its own in the one configured.
+        let result = response.result.unwrap();
+        let stdout = String::from_utf8(result.stdout_raw).unwrap();
+        assert!(stdout.contains("/oryx-usage-"), "{stdout}");
+
+        let metadata = result.execution_metadata.unwrap();
+        assert_eq!(metadata.auxiliary_metadata.len(), 1);
+        let usage = &metadata.auxiliary_metadata[0];
+        assert_eq!(
+            usage.type_url,
+            "type.googleapis.com/oryx.execution.v1.ResourceUsage"
+        );
+        let usage = protos::execution::ResourceUsage::decode(&usage.value[..]).unwrap();
+        let user = usage.user_cpu_time.unwrap_or_default();
+        let system = usage.system_cpu_time.unwrap_or_default();
+        assert!(user.seconds + system.seconds > 0 || user.nanos + system.nanos > 0);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn actions_out_of_memory_are_reported() {
+    let Some(cgroup) = TestCgroup::new("oom").filter(|c| c.has_controller("memory")) else {
+        return;
+    };
+    oryx_test_with_config(cgroup.config(), |channel| async move {
+        // tail holds on to the whole of a line, which this never ends.
+        let script = "head -c 1G /dev/zero | tail > out.txt";
+        let limit = [("memory_limit_bytes", "33554432")];
+        let response = execute(channel, script, &limit).await;
+        assert_eq!(
+            response.status.unwrap().code,
+            Code::ResourceExhausted as i32
+        );
+        assert_ne!(response.result.unwrap().exit_code, 0);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn actions_with_invalid_limits_are_rejected() {
+    let Some(cgroup) = TestCgroup::new("invalid") else {
+        return;
+    };
+    oryx_test_with_config(cgroup.config(), |channel| async move {
+        let response = execute(channel, "touch out.txt", &[("cpu_limit", "lots")]).await;
+        assert_eq!(response.status.unwrap().code, Code::InvalidArgument as i32);
+    })
+    .await;
+}
@@ -1,6 +1,7 @@
-use crate::oryx_test;
+use crate::{oryx_test, oryx_test_with_config};
 use common::Digest;
 use gemsbok::*;
+use node_lib::{EngineConfig, Platform};
 use prost::Message;
 use protos::{
     longrunning::operation::Result::Response,
@@ -11,6 +12,7 @@ use protos::{
 use std::future::{ready, Future, IntoFuture, Ready};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 use tokio_stream::StreamExt;
 use tonic::Request;
 
@@ -271,6 +273,199 @@ async fn basic_req_with_relative_symlink_output() {
     .await;
 }
 
+#[tokio::test]
+async fn basic_req_with_nested_symlink_input() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let command_digest = client
+            .add_command(&["/bin/sh", "-c", "cat dir/b.txt > out.txt"], &["out.txt"])
+            .await
+            .unwrap();
+        // The target is relative to the directory the symlink is in.
+        let mut input_directory = Directory::root();
+        input_directory.add_path(&PathBuf::from("dir/a.txt"), Some(b"kalahari\n"));
+        input_directory.add_symlink(&PathBuf::from("dir/b.txt"), &PathBuf::from("a.txt"));
+        let root_dir_digest = client.add_directory(input_directory).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let result = client.execute(action_digest).await.unwrap();
+
+        let mut expected_directory = Directory::root();
+        expected_directory.add_path(&PathBuf::from("out.txt"), Some(b"kalahari\n"));
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.directory, expected_directory);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn symlink_inputs_escaping_root_are_rejected() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        for target in ["/etc/passwd", "../etc/passwd", "dir/../../etc/passwd"] {
+            let command_digest = client
+                .add_command(&["/bin/sh", "-c", "cat b.txt > out.txt"], &["out.txt"])
+                .await
+                .unwrap();
+            let mut input_directory = Directory::root();
+            input_directory.add_symlink(&PathBuf::from("b.txt"), &PathBuf::from(target));
+            let root_dir_digest = client.add_directory(input_directory).await.unwrap();
+            let action_digest = client
+                .add_action(command_digest, root_dir_digest)
+                .await
+                .unwrap();
+            let resp = client.execute_response(action_digest).await.unwrap();
+            assert_eq!(
+                resp.status.unwrap().code,
+                Code::InvalidArgument as i32,
+                "{target}"
+            );
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn basic_req_with_symlinks_in_dir_output() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let command_digest = client
+            .add_command(
+                &[
+                    "/bin/sh",
+                    "-c",
+                    "mkdir -p out/sub; echo brandberg > out/a.txt; \
+                    ln -s a.txt out/b.txt; ln -s ../a.txt out/sub/c.txt",
+                ],
+                &["out"],
+            )
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let result = client.execute(action_digest).await.unwrap();
+
+        let mut expected_directory = Directory::root();
+        expected_directory.add_path(&PathBuf::from("out/a.txt"), Some(b"brandberg\n"));
+        expected_directory.add_symlink(&PathBuf::from("out/b.txt"), &PathBuf::from("a.txt"));
+        expected_directory.add_symlink(&PathBuf::from("out/sub/c.txt"), &PathBuf::from("../a.txt"));
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.directory, expected_directory);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn absolute_symlink_outputs_fail() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        for (script, output) in [
+            ("ln -s /etc/passwd out.txt", "out.txt"),
+            ("mkdir out; ln -s /etc/passwd out/passwd", "out"),
+        ] {
+            let command_digest = client
+                .add_command(&["/bin/sh", "-c", script], &[output])
+                .await
+                .unwrap();
+            let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+            let action_digest = client
+                .add_action(command_digest, root_dir_digest)
+                .await
+                .unwrap();
+            let resp = client.execute_response(action_digest).await.unwrap();
+            assert_eq!(
+                resp.status.unwrap().code,
+                Code::FailedPrecondition as i32,
+                "{script}"
+            );
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn outputs_that_cant_be_stored_are_rejected() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        for script in [
+            "mkdir out; mkfifo out/pipe",
+            "mkdir out; touch \"out/$(printf '\\377')\"",
+            "mkdir out; ln -s \"$(printf '\\377')\" out/link",
+        ] {
+            let command_digest = client
+                .add_command(&["/bin/sh", "-c", script], &["out"])
+                .await
+                .unwrap();
+            let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+            let action_digest = client
+                .add_action(command_digest, root_dir_digest)
+                .await
+                .unwrap();
+            let resp = client.execute_response(action_digest).await.unwrap();
+            let status = resp.status.unwrap();
+            assert_eq!(status.code, Code::InvalidArgument as i32, "{script}");
+            assert!(status.message.contains("out/"), "{}", status.message);
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn legacy_req_with_symlink_outputs() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let script = "echo erongo > a.txt; mkdir dir; ln -s a.txt file; ln -s dir link";
+        let command_digest = client
+            .add_legacy_command(&["/bin/sh", "-c", script], &["file"], &["link"])
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let resp = client.execute_response(action_digest).await.unwrap();
+        assert_eq!(resp.status.unwrap().code, Code::Ok as i32);
+
+        // Symlinks are reported by what they point to, as well as in output_symlinks.
+        let result = resp.result.unwrap();
+        let symlink = |path: &str, target: &str| protos::re::OutputSymlink {
+            path: path.to_string(),
+            target: target.to_string(),
+            node_properties: None,
+        };
+        assert_eq!(result.output_file_symlinks, vec![symlink("file", "a.txt")]);
+        assert_eq!(
+            result.output_directory_symlinks,
+            vec![symlink("link", "dir")]
+        );
+        // Each list is sorted by path.
+        assert_eq!(
+            result.output_symlinks,
+            vec![symlink("file", "a.txt"), symlink("link", "dir")]
+        );
+
+        // A symlink to a file can't stand in for a directory.
+        let command_digest = client
+            .add_legacy_command(&["/bin/sh", "-c", script], &[], &["file"])
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let resp = client.execute_response(action_digest).await.unwrap();
+        assert_eq!(resp.status.unwrap().code, Code::FailedPrecondition as i32);
+    })
+    .await;
+}
+
 #[tokio::test]
 async fn basic_req_with_executable_input() {
     oryx_test(|channel| async move {
@@ -295,3 +490,672 @@ async fn basic_req_with_executable_input() {
     })
     .await;
 }
+
+#[tokio::test]
+async fn basic_req_with_executable_output() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let script = "printf '#!/bin/sh\\n' > tool; chmod +x tool; mkdir bin; cp tool bin/tool";
+        let command_digest = client
+            .add_command(&["/bin/sh", "-c", script], &["tool", "bin"])
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let result = client.execute(action_digest).await.unwrap();
+
+        let mut expected_directory = Directory::root();
+        expected_directory.add_exec(&PathBuf::from("tool"), Some(b"#!/bin/sh\n"));
+        expected_directory.add_exec(&PathBuf::from("bin/tool"), Some(b"#!/bin/sh\n"));
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.directory, expected_directory);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn node_properties_are_applied_and_reported() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel.clone());
+        let command_digest = client
+            .add_command_with_properties(
+                &[
+                    "/bin/sh",
+                    "-c",
+                    "cp -p in.txt out.txt; mkdir dir; cp -p in.txt dir/in.txt",
+                ],
+                &["out.txt", "dir"],
+                &["mtime", "unix_mode"],
+            )
+            .await
+            .unwrap();
+        let properties = protos::re::NodeProperties {
+            properties: vec![],
+            mtime: Some(prost_types::Timestamp {
+                seconds: 1_000_000_000,
+                nanos: 0,
+            }),
+            unix_mode: Some(0o640),
+        };
+        let mut input_directory = Directory::root();
+        let path = PathBuf::from("in.txt");
+        input_directory.add_file(&path, b"namib\n", false, Some(properties.clone()));
+        let root_dir_digest = client.add_directory(input_directory).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let result = client.execute(action_digest).await.unwrap();
+
+        // Copies keep the mtime and mode the input was laid out with.
+        let mut expected_directory = Directory::root();
+        let path = PathBuf::from("out.txt");
+        expected_directory.add_file(&path, b"namib\n", false, Some(properties.clone()));
+        let path = PathBuf::from("dir/in.txt");
+        expected_directory.add_file(&path, b"namib\n", false, Some(properties));
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.directory, expected_directory);
+
+        let mut client = protos::CapabilitiesClient::new(channel);
+        let caps = client
+            .get_capabilities(Request::new(protos::re::GetCapabilitiesRequest {
+                instance_name: "".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            caps.execution_capabilities
+                .unwrap()
+                .supported_node_properties,
+            vec!["mtime", "unix_mode"]
+        );
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn unsupported_output_node_property_is_rejected() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let command_digest = client
+            .add_command_with_properties(
+                &["/bin/sh", "-c", "touch out.txt"],
+                &["out.txt"],
+                &["owner"],
+            )
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let resp = client.execute_response(action_digest).await.unwrap();
+        assert_eq!(resp.status.unwrap().code, Code::InvalidArgument as i32);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn req_runs_in_working_directory() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let command_digest = client
+            .add_command_in(
+                "src",
+                &["/bin/sh", "-c", "cat in.txt > gen/out.txt"],
+                &["gen/out.txt"],
+            )
+            .await
+            .unwrap();
+        let mut input_directory = Directory::root();
+        input_directory.add_path(&PathBuf::from("src/in.txt"), Some(b"skeleton\n"));
+        let root_dir_digest = client.add_directory(input_directory).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let result = client.execute(action_digest).await.unwrap();
+
+        // Outputs are relative to the working directory.
+        let mut expected_directory = Directory::root();
+        expected_directory.add_path(&PathBuf::from("gen/out.txt"), Some(b"skeleton\n"));
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.directory, expected_directory);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn working_directory_outside_input_root_is_rejected() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        for working_directory in ["..", "/tmp", "src/../..", "missing"] {
+            let command_digest = client
+                .add_command_in(working_directory, &["/bin/true"], &["out.txt"])
+                .await
+                .unwrap();
+            let mut input_directory = Directory::root();
+            input_directory.add_path(&PathBuf::from("src/in.txt"), Some(b"skeleton\n"));
+            let root_dir_digest = client.add_directory(input_directory).await.unwrap();
+            let action_digest = client
+                .add_action(command_digest, root_dir_digest)
+                .await
+                .unwrap();
+            let resp = client.execute_response(action_digest).await.unwrap();
+            let status = resp.status.unwrap();
+            assert_eq!(
+                status.code,
+                Code::InvalidArgument as i32,
+                "{working_directory}"
+            );
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn output_paths_outside_input_root_are_rejected() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        for output_path in ["../../out.txt", "/tmp/out.txt", "out/../../../out.txt"] {
+            let command_digest = client
+                .add_command_in("src", &["/bin/true"], &[output_path])
+                .await
+                .unwrap();
+            let mut input_directory = Directory::root();
+            input_directory.add_path(&PathBuf::from("src/in.txt"), Some(b"skeleton\n"));
+            let root_dir_digest = client.add_directory(input_directory).await.unwrap();
+            let action_digest = client
+                .add_action(command_digest, root_dir_digest)
+                .await
+                .unwrap();
+            let resp = client.execute_response(action_digest).await.unwrap();
+            let status = resp.status.unwrap();
+            assert_eq!(status.code, Code::InvalidArgument as i32, "{output_path}");
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn legacy_req_with_file_and_dir_outputs() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel.clone());
+        let command_digest = client
+            .add_legacy_command(
+                &[
+                    "/bin/sh",
+                    "-c",
+                    "echo dune > out.txt; mkdir dir; echo sossusvlei > dir/in_dir.txt",
+                ],
+                &["out.txt", "missing.txt"],
+                &["dir"],
+            )
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let result = client.execute(action_digest).await.unwrap();
+
+        // Outputs the command didn't create are left out.
+        let mut expected_directory = Directory::root();
+        expected_directory.add_path(&PathBuf::from("out.txt"), Some(b"dune\n"));
+        expected_directory.add_path(&PathBuf::from("dir/in_dir.txt"), Some(b"sossusvlei\n"));
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.directory, expected_directory);
+
+        // Servers supporting both ways of listing outputs advertise as much.
+        let mut client = protos::CapabilitiesClient::new(channel);
+        let caps = client
+            .get_capabilities(Request::new(protos::re::GetCapabilitiesRequest {
+                instance_name: "".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(caps.low_api_version.unwrap().minor, 0);
+        assert!(caps.high_api_version.unwrap().minor >= 1);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn legacy_req_with_output_of_wrong_kind_fails() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let command_digest = client
+            .add_legacy_command(&["/bin/sh", "-c", "mkdir out.txt"], &["out.txt"], &[])
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let resp = client.execute_response(action_digest).await.unwrap();
+        let status = resp.status.unwrap();
+        assert_eq!(status.code, Code::FailedPrecondition as i32);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn repeated_req_is_cached() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let command_digest = client
+            .add_command(&["/bin/sh", "-c", "echo etosha > out.txt"], &["out.txt"])
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+
+        let mut expected_directory = Directory::root();
+        expected_directory.add_path(&PathBuf::from("out.txt"), Some(b"etosha\n"));
+
+        let result = client.execute(action_digest.clone()).await.unwrap();
+        assert!(!result.cached);
+        assert_eq!(result.directory, expected_directory);
+
+        let result = client.execute(action_digest.clone()).await.unwrap();
+        assert!(result.cached);
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.directory, expected_directory);
+
+        let result = client.execute_skip_cache(action_digest).await.unwrap();
+        assert!(!result.cached);
+        assert_eq!(result.directory, expected_directory);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn failed_req_is_not_cached() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let command_digest = client
+            .add_command(&["/bin/sh", "-c", "touch out.txt; exit 3"], &["out.txt"])
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+
+        let result = client.execute(action_digest.clone()).await.unwrap();
+        assert_eq!(result.exit_code, 3);
+        let result = client.execute(action_digest).await.unwrap();
+        assert_eq!(result.exit_code, 3);
+        assert!(!result.cached);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn do_not_cache_req_is_not_cached() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let command_digest = client
+            .add_command(&["/bin/sh", "-c", "echo etosha > out.txt"], &["out.txt"])
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let action_digest = client
+            .add_uncacheable_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+
+        client.execute(action_digest.clone()).await.unwrap();
+        let result = client.execute(action_digest).await.unwrap();
+        assert!(!result.cached);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn do_not_cache_req_ignores_cached_result() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel.clone());
+        let command_digest = client
+            .add_command(&["/bin/sh", "-c", "echo etosha > out.txt"], &["out.txt"])
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let action_digest = client
+            .add_uncacheable_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+
+        // Results someone else cached for the action must not be used.
+        let mut cache_client = protos::ActionCacheClient::new(channel);
+        cache_client
+            .update_action_result(Request::new(protos::re::UpdateActionResultRequest {
+                instance_name: "".to_string(),
+                action_digest: Some(action_digest.0.clone().into()),
+                action_result: Some(protos::re::ActionResult {
+                    exit_code: 7,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+
+        let result = client.execute(action_digest).await.unwrap();
+        assert!(!result.cached);
+        assert_eq!(result.exit_code, 0);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn timed_out_req_reports_deadline_exceeded() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let command_digest = client
+            .add_command(
+                &[
+                    "/bin/sh",
+                    "-c",
+                    "echo namib; sleep 10; echo naukluft > out.txt",
+                ],
+                &["out.txt"],
+            )
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let action_digest = client
+            .add_action_with_timeout(command_digest, root_dir_digest, Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        let start = Instant::now();
+        let resp = client.execute_response(action_digest).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(resp.status.unwrap().code, Code::DeadlineExceeded as i32);
+        // Output from before the action was killed is kept.
+        let result = resp.result.unwrap();
+        assert_eq!(result.stdout_raw, b"namib\n");
+        assert!(result.output_files.is_empty());
+    })
+    .await;
+}
+
+/// Run a shell command with a timeout of `timeout`, returning the server's
+/// response and how long it took.
+async fn execute_with_timeout(
+    channel: tonic::transport::Channel,
+    script: &str,
+    timeout: Duration,
+) -> (protos::re::ExecuteResponse, Duration) {
+    let mut client = Gemsbok::new(channel);
+    let command_digest = client
+        .add_command(&["/bin/sh", "-c", script], &["out.txt"])
+        .await
+        .unwrap();
+    let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+    let action_digest = client
+        .add_action_with_timeout(command_digest, root_dir_digest, timeout)
+        .await
+        .unwrap();
+    let start = Instant::now();
+    let resp = client.execute_response(action_digest).await.unwrap();
+    (resp, start.elapsed())
+}
+
+#[tokio::test]
+async fn background_processes_holding_output_are_killed() {
+    oryx_test(|channel| async move {
+        let script = "sleep 1000 & echo okahandja; touch out.txt";
+        let (resp, elapsed) = execute_with_timeout(channel, script, Duration::from_secs(60)).await;
+        assert!(elapsed < Duration::from_secs(10));
+        assert_eq!(resp.status.unwrap().code, Code::Ok as i32);
+        assert_eq!(resp.result.unwrap().stdout_raw, b"okahandja\n");
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn escaped_processes_holding_output_time_out() {
+    oryx_test(|channel| async move {
+        // Out of the action's process group, so it can't be killed with it. The
+        // action waits for it to escape, or it may be killed before it does.
+        let script = "setsid sh -c 'touch escaped; exec sleep 5' &
+            until [ -e escaped ]; do sleep 0.01; done; echo otavi; touch out.txt";
+        let (resp, elapsed) = execute_with_timeout(channel, script, Duration::from_secs(1)).await;
+        assert!(elapsed < Duration::from_secs(4));
+        assert_eq!(resp.status.unwrap().code, Code::DeadlineExceeded as i32);
+        assert_eq!(resp.result.unwrap().stdout_raw, b"otavi\n");
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn long_logs_are_stored_in_cas() {
+    let config = EngineConfig {
+        max_inline_output_bytes: 16,
+        ..Default::default()
+    };
+    oryx_test_with_config(config, |channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let script = "printf kunene; printf '%0100d' 0 >&2; touch out.txt";
+        let command_digest = client
+            .add_command(&["/bin/sh", "-c", script], &["out.txt"])
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let resp = client
+            .execute_response(action_digest.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status.unwrap().code, Code::Ok as i32);
+
+        // Only logs longer than the limit are returned by digest.
+        let result = resp.result.unwrap();
+        assert_eq!(result.stdout_raw, b"kunene");
+        assert_eq!(result.stdout_digest, None);
+        assert!(result.stderr_raw.is_empty());
+        assert_eq!(result.stderr_digest.unwrap().size_bytes, 100);
+
+        let result = client.execute(action_digest).await.unwrap();
+        assert_eq!(result.stdout, b"kunene");
+        assert_eq!(result.stderr, [b'0'; 100]);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn logs_too_big_to_batch_are_stored_in_cas() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let script = "head -c 5000000 /dev/zero; touch out.txt";
+        let command_digest = client
+            .add_command(&["/bin/sh", "-c", script], &["out.txt"])
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let resp = client
+            .execute_response(action_digest.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status.unwrap().code, Code::Ok as i32);
+        let result = resp.result.unwrap();
+        assert!(result.stdout_raw.is_empty());
+        assert_eq!(result.stdout_digest.unwrap().size_bytes, 5_000_000);
+
+        let result = client.execute(action_digest).await.unwrap();
+        assert_eq!(result.stdout, vec![0; 5_000_000]);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn running_output_can_be_followed() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let script = "echo kalahari; sleep 2; echo skeleton >&2; echo coast; touch out.txt";
+        let command_digest = client
+            .add_command(&["/bin/sh", "-c", script], &["out.txt"])
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let (mut ops, metadata) = client.execute_until_running(action_digest).await.unwrap();
+        let mut stdout = client
+            .read_stream(&metadata.stdout_stream_name, 0, 0)
+            .await
+            .unwrap();
+        let mut stderr = client
+            .read_stream(&metadata.stderr_stream_name, 4, 0)
+            .await
+            .unwrap();
+
+        // Output arrives as it is written, before the command finishes.
+        let first = stdout.next().await.unwrap().unwrap();
+        assert_eq!(first.data, b"kalahari\n");
+        let mut rest = vec![];
+        while let Some(response) = stdout.next().await {
+            rest.extend(response.unwrap().data);
+        }
+        assert_eq!(rest, b"coast\n");
+        let mut tail = vec![];
+        while let Some(response) = stderr.next().await {
+            tail.extend(response.unwrap().data);
+        }
+        assert_eq!(tail, b"eton\n");
+
+        // Output of finished commands is only available from their result.
+        while ops.next().await.is_some() {}
+        let status = client
+            .read_stream(&metadata.stdout_stream_name, 0, 0)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn execution_stages_are_timestamped() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let command_digest = client
+            .add_command(&["/bin/sh", "-c", "cat in.txt > out.txt"], &["out.txt"])
+            .await
+            .unwrap();
+        let mut input_directory = Directory::root();
+        input_directory.add_path(&PathBuf::from("in.txt"), Some(b"etosha\n"));
+        let root_dir_digest = client.add_directory(input_directory).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let resp = client.execute_response(action_digest).await.unwrap();
+        let metadata = resp.result.unwrap().execution_metadata.unwrap();
+        assert!(!metadata.worker.is_empty());
+
+        // Every stage is timestamped, each starting after the one before it.
+        let stages = [
+            metadata.queued_timestamp,
+            metadata.worker_start_timestamp,
+            metadata.input_fetch_start_timestamp,
+            metadata.input_fetch_completed_timestamp,
+            metadata.execution_start_timestamp,
+            metadata.execution_completed_timestamp,
+            metadata.output_upload_start_timestamp,
+            metadata.output_upload_completed_timestamp,
+            metadata.worker_completed_timestamp,
+        ]
+        .map(|timestamp| {
+            let timestamp = timestamp.unwrap();
+            (timestamp.seconds, timestamp.nanos)
+        });
+        assert!(
+            stages.windows(2).all(|pair| pair[0] <= pair[1]),
+            "{stages:?}"
+        );
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn timeout_above_maximum_is_rejected() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let command_digest = client
+            .add_command(&["/bin/sh", "-c", "echo etosha > out.txt"], &["out.txt"])
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let timeout = Duration::from_secs(EngineConfig::default().max_timeout_secs + 1);
+        let action_digest = client
+            .add_action_with_timeout(command_digest, root_dir_digest, timeout)
+            .await
+            .unwrap();
+
+        let resp = client.execute_response(action_digest).await.unwrap();
+        assert_eq!(resp.status.unwrap().code, Code::InvalidArgument as i32);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn platform_requirements_are_matched() {
+    let config = EngineConfig {
+        platform: Platform::from_iter([
+            (String::from("OSFamily"), String::from("linux")),
+            (String::from("ISA"), String::from("x86-64")),
+        ]),
+        ..Default::default()
+    };
+    oryx_test_with_config(config, |channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let command_digest = client
+            .add_command(&["/bin/sh", "-c", "echo tsodilo > out.txt"], &["out.txt"])
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+
+        let action_digest = client
+            .add_action_with_platform(
+                CommandDigest(command_digest.0.clone()),
+                DirectoryDigest(root_dir_digest.0.clone()),
+                &[("OSFamily", "linux")],
+            )
+            .await
+            .unwrap();
+        let resp = client.execute_response(action_digest).await.unwrap();
+        assert_eq!(resp.status.unwrap().code, Code::Ok as i32);
+
+        let action_digest = client
+            .add_action_with_platform(command_digest, root_dir_digest, &[("ISA", "aarch64")])
+            .await
+            .unwrap();
+        let resp = client.execute_response(action_digest).await.unwrap();
+        assert_eq!(resp.status.unwrap().code, Code::FailedPrecondition as i32);
+    })
+    .await;
+}
@@ -0,0 +1,49 @@
+use crate::oryx_test_with_workers;
+use gemsbok::*;
+use node_lib::worker::WorkerConfig;
+use node_lib::{EngineConfig, ExecutionEngine};
+use std::path::PathBuf;
+
+fn hermetic_worker() -> WorkerConfig {
+    WorkerConfig {
+        instance: String::from(""),
+        name: String::from("hermetic"),
+        token: String::from(crate::WORKER_TOKEN),
+        capacity: 1,
+        execution_engine: ExecutionEngine::Hermetic,
+        platform: Default::default(),
+        sandbox: Default::default(),
+        cgroup: Default::default(),
+    }
+}
+
+// Run with `--ignored` where /dev/fuse and fusermount are available.
+#[tokio::test]
+#[ignore = "needs FUSE"]
+async fn hermetic_actions_read_inputs_and_write_outputs() {
+    let workers = vec![hermetic_worker()];
+    oryx_test_with_workers(EngineConfig::default(), workers, |channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let script = "cat dir/in.txt > out/a.txt; mv out/a.txt out/b.txt; rm dir/in.txt";
+        let command_digest = client
+            .add_command(&["/bin/sh", "-c", script], &["out"])
+            .await
+            .unwrap();
+        let mut input_directory = Directory::root();
+        input_directory.add_path(&PathBuf::from("dir/in.txt"), Some(b"kalahari\n"));
+        input_directory.add_path(&PathBuf::from("out/unused.txt"), Some(b"namib\n"));
+        let root_dir_digest = client.add_directory(input_directory).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let result = client.execute(action_digest).await.unwrap();
+
+        let mut expected_directory = Directory::root();
+        expected_directory.add_path(&PathBuf::from("out/b.txt"), Some(b"kalahari\n"));
+        expected_directory.add_path(&PathBuf::from("out/unused.txt"), Some(b"namib\n"));
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.directory, expected_directory);
+    })
+    .await;
+}
@@ -1,17 +1,102 @@
 use futures::Future;
+use node_lib::worker::{Worker, WorkerConfig};
+use node_lib::{EngineConfig, ExecutionEngine, StorageBackend};
+use std::path::PathBuf;
 use std::sync::Arc;
 use tempfile::NamedTempFile;
 use tokio::net::{UnixListener, UnixStream};
 use tokio_stream::wrappers::UnixListenerStream;
 use tonic::transport::{Channel, Endpoint, Uri};
 
+mod action_cache;
+mod bytestream;
 mod cas;
+mod cgroups;
 mod execute;
+mod hermetic;
+mod operations;
+mod sandbox;
+mod scheduling;
+mod workers;
+
+/// Token the nodes of tests with workers share with them.
+pub const WORKER_TOKEN: &str = "oryx-test-worker-token";
 
 pub async fn oryx_test<F, FRet>(client_test_fut: F)
 where
     F: FnOnce(Channel) -> FRet,
     FRet: Future<Output = ()>,
+{
+    oryx_test_with_storage(StorageBackend::InMemory, None, client_test_fut).await
+}
+
+pub async fn oryx_test_with_storage<F, FRet>(
+    storage_backend: StorageBackend,
+    storage_path: Option<PathBuf>,
+    client_test_fut: F,
+) where
+    F: FnOnce(Channel) -> FRet,
+    FRet: Future<Output = ()>,
+{
+    run_oryx_test(
+        storage_backend,
+        storage_path,
+        ExecutionEngine::Insecure,
+        EngineConfig::default(),
+        vec![],
+        client_test_fut,
+    )
+    .await
+}
+
+pub async fn oryx_test_with_config<F, FRet>(engine_config: EngineConfig, client_test_fut: F)
+where
+    F: FnOnce(Channel) -> FRet,
+    FRet: Future<Output = ()>,
+{
+    run_oryx_test(
+        StorageBackend::InMemory,
+        None,
+        ExecutionEngine::Insecure,
+        engine_config,
+        vec![],
+        client_test_fut,
+    )
+    .await
+}
+
+/// Run a test against a node that leases its actions to the workers given,
+/// sharing `WORKER_TOKEN` with them.
+pub async fn oryx_test_with_workers<F, FRet>(
+    mut engine_config: EngineConfig,
+    workers: Vec<WorkerConfig>,
+    client_test_fut: F,
+) where
+    F: FnOnce(Channel) -> FRet,
+    FRet: Future<Output = ()>,
+{
+    engine_config.worker_token = Some(String::from(WORKER_TOKEN));
+    run_oryx_test(
+        StorageBackend::InMemory,
+        None,
+        ExecutionEngine::Remote,
+        engine_config,
+        workers,
+        client_test_fut,
+    )
+    .await
+}
+
+async fn run_oryx_test<F, FRet>(
+    storage_backend: StorageBackend,
+    storage_path: Option<PathBuf>,
+    execution_engine: ExecutionEngine,
+    engine_config: EngineConfig,
+    workers: Vec<WorkerConfig>,
+    client_test_fut: F,
+) where
+    F: FnOnce(Channel) -> FRet,
+    FRet: Future<Output = ()>,
 {
     // Create a new UDS file
     let socket = NamedTempFile::new().unwrap();
@@ -26,8 +111,10 @@ where
         let result = node_lib::start_oryx(
             String::from(""),
             node_lib::Connection::Uds(stream),
-            node_lib::StorageBackend::InMemory,
-            node_lib::ExecutionEngine::Insecure,
+            storage_backend,
+            storage_path,
+            execution_engine,
+            engine_config,
         )
         .await;
         assert!(result.is_ok());
@@ -44,8 +131,24 @@ where
         .await
         .unwrap();
 
-    // Run the client future
-    let client_fut = async move { client_test_fut(channel).await };
+    // Run the client future once the workers have registered over the same socket
+    let client_fut = async move {
+        let workers = workers
+            .into_iter()
+            .map(|config| Worker::register(config, channel.clone()));
+        let workers = futures::future::try_join_all(workers).await.unwrap();
+        let workers_fut = async {
+            futures::future::try_join_all(workers.into_iter().map(Worker::run))
+                .await
+                .unwrap();
+            // Workers only stop once the node hangs up, but there may be none.
+            std::future::pending::<()>().await
+        };
+        tokio::select! {
+            _ = workers_fut => unreachable!(),
+            _ = client_test_fut(channel) => (),
+        }
+    };
 
     // Run both futures to completion
     tokio::select! {
@@ -0,0 +1,391 @@
+use crate::oryx_test;
+use gemsbok::*;
+use prost::Message;
+use protos::longrunning::{operation::Result::Response, Operation};
+use std::time::{Duration, Instant};
+use tempfile::NamedTempFile;
+use tokio_stream::StreamExt;
+use tonic::transport::Channel;
+use tonic::{Code, Request, Status, Streaming};
+
+/// Upload an action running a shell command that writes `out.txt`.
+async fn add_script(channel: Channel, script: &str) -> ActionDigest {
+    let mut client = Gemsbok::new(channel);
+    let command_digest = client
+        .add_command(&["/bin/sh", "-c", script], &["out.txt"])
+        .await
+        .unwrap();
+    let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+    client
+        .add_action(command_digest, root_dir_digest)
+        .await
+        .unwrap()
+}
+
+/// Start executing an action, returning the stream of its operation's states.
+async fn start_execute(channel: Channel, action_digest: ActionDigest) -> Streaming<Operation> {
+    let mut exec_client = protos::ExecutionClient::new(channel);
+    exec_client
+        .execute(Request::new(protos::re::ExecuteRequest {
+            instance_name: "".to_string(),
+            action_digest: Some(action_digest.0.into()),
+            execution_policy: None,
+            results_cache_policy: None,
+            skip_cache_lookup: false,
+        }))
+        .await
+        .unwrap()
+        .into_inner()
+}
+
+/// The last operation sent on a stream.
+async fn last_op(mut stream: Streaming<Operation>) -> Operation {
+    let mut last = None;
+    while let Some(op) = stream.next().await {
+        last = Some(op.unwrap());
+    }
+    last.unwrap()
+}
+
+/// Execute a shell command, returning the final state of its operation.
+async fn execute(channel: Channel, script: &str) -> Operation {
+    let action_digest = add_script(channel.clone(), script).await;
+    last_op(start_execute(channel, action_digest).await).await
+}
+
+async fn wait_execution(channel: Channel, name: &str) -> Result<Streaming<Operation>, Status> {
+    let mut exec_client = protos::ExecutionClient::new(channel);
+    exec_client
+        .wait_execution(Request::new(protos::re::WaitExecutionRequest {
+            name: name.to_string(),
+        }))
+        .await
+        .map(|response| response.into_inner())
+}
+
+#[tokio::test]
+async fn get_unknown_operation() {
+    oryx_test(|channel| async move {
+        let mut client = protos::OperationsClient::new(channel);
+        let status = client
+            .get_operation(Request::new(protos::longrunning::GetOperationRequest {
+                name: "operations/kaokoveld".to_string(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn get_completed_operation() {
+    oryx_test(|channel| async move {
+        let op = execute(channel.clone(), "echo damaraland > out.txt").await;
+        assert!(op.done);
+
+        let mut client = protos::OperationsClient::new(channel);
+        let fetched = client
+            .get_operation(Request::new(protos::longrunning::GetOperationRequest {
+                name: op.name.clone(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(fetched, op);
+
+        // Waiting on a completed operation returns straight away.
+        let waited = client
+            .wait_operation(Request::new(protos::longrunning::WaitOperationRequest {
+                name: op.name.clone(),
+                timeout: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(waited, op);
+
+        // Cancelling a completed operation leaves it alone.
+        client
+            .cancel_operation(Request::new(protos::longrunning::CancelOperationRequest {
+                name: op.name.clone(),
+            }))
+            .await
+            .unwrap();
+        let fetched = client
+            .get_operation(Request::new(protos::longrunning::GetOperationRequest {
+                name: op.name.clone(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(fetched, op);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn list_operations_paged() {
+    oryx_test(|channel| async move {
+        let first = execute(channel.clone(), "echo erongo > out.txt").await;
+        let second = execute(channel.clone(), "echo kunene > out.txt").await;
+        let mut expected = vec![first.name, second.name];
+        expected.sort();
+
+        let mut client = protos::OperationsClient::new(channel);
+        let mut names = vec![];
+        let mut page_token = String::new();
+        loop {
+            let response = client
+                .list_operations(Request::new(protos::longrunning::ListOperationsRequest {
+                    name: "operations".to_string(),
+                    filter: "done=true".to_string(),
+                    page_size: 1,
+                    page_token,
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(response.operations.len() <= 1);
+            names.extend(response.operations.into_iter().map(|op| op.name));
+            if response.next_page_token.is_empty() {
+                break;
+            }
+            page_token = response.next_page_token;
+        }
+        assert_eq!(names, expected);
+
+        let response = client
+            .list_operations(Request::new(protos::longrunning::ListOperationsRequest {
+                name: "operations".to_string(),
+                filter: "done=false".to_string(),
+                page_size: 0,
+                page_token: String::new(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.operations.is_empty());
+
+        let status = client
+            .list_operations(Request::new(protos::longrunning::ListOperationsRequest {
+                name: "operations".to_string(),
+                filter: "region=erongo".to_string(),
+                page_size: 0,
+                page_token: String::new(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn delete_operation() {
+    oryx_test(|channel| async move {
+        let action_digest =
+            add_script(channel.clone(), "sleep 1; echo otjozondjupa > out.txt").await;
+        let mut stream = start_execute(channel.clone(), action_digest).await;
+        let first = stream.next().await.unwrap().unwrap();
+        assert!(!first.done);
+
+        // Operations still running are kept, as followers are owed their final state.
+        let mut client = protos::OperationsClient::new(channel);
+        let status = client
+            .delete_operation(Request::new(protos::longrunning::DeleteOperationRequest {
+                name: first.name.clone(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        let op = last_op(stream).await;
+        assert!(op.done);
+        assert_eq!(op_status(&op).code, protos::rpc::Code::Ok as i32);
+
+        client
+            .delete_operation(Request::new(protos::longrunning::DeleteOperationRequest {
+                name: op.name.clone(),
+            }))
+            .await
+            .unwrap();
+        let status = client
+            .get_operation(Request::new(protos::longrunning::GetOperationRequest {
+                name: op.name,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn wait_execution_unknown_operation() {
+    oryx_test(|channel| async move {
+        let status = wait_execution(channel, "operations/kalahari")
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn wait_execution_completed() {
+    oryx_test(|channel| async move {
+        let op = execute(channel.clone(), "echo khomas > out.txt").await;
+
+        let stream = wait_execution(channel, &op.name).await.unwrap();
+        assert_eq!(last_op(stream).await, op);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn wait_execution_reattaches() {
+    oryx_test(|channel| async move {
+        let action_digest = add_script(channel.clone(), "sleep 1; echo hardap > out.txt").await;
+
+        // Drop the connection to the execution as soon as it has a name.
+        let mut stream = start_execute(channel.clone(), action_digest).await;
+        let first = stream.next().await.unwrap().unwrap();
+        assert!(!first.done);
+        drop(stream);
+
+        let stream = wait_execution(channel, &first.name).await.unwrap();
+        let op = last_op(stream).await;
+        assert_eq!(op.name, first.name);
+        assert!(op.done);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn concurrent_executions_are_merged() {
+    oryx_test(|channel| async move {
+        let action_digest = add_script(channel.clone(), "sleep 1; echo zambezi > out.txt").await;
+
+        let first = start_execute(channel.clone(), action_digest.clone()).await;
+        let second = start_execute(channel, action_digest).await;
+        let (first, second) = tokio::join!(last_op(first), last_op(second));
+        assert!(first.done);
+        assert_eq!(first, second);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn concurrent_uncacheable_executions_are_not_merged() {
+    oryx_test(|channel| async move {
+        let mut client = Gemsbok::new(channel.clone());
+        let command_digest = client
+            .add_command(
+                &["/bin/sh", "-c", "sleep 1; echo zambezi > out.txt"],
+                &["out.txt"],
+            )
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let action_digest = client
+            .add_uncacheable_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+
+        let first = start_execute(channel.clone(), action_digest.clone()).await;
+        let second = start_execute(channel, action_digest).await;
+        let (first, second) = tokio::join!(last_op(first), last_op(second));
+        assert!(first.done);
+        assert!(second.done);
+        assert_ne!(first.name, second.name);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn execute_streams_progress() {
+    oryx_test(|channel| async move {
+        let action_digest = add_script(channel.clone(), "sleep 2; echo omaheke > out.txt").await;
+
+        // Progress is reported while the action is still running.
+        let start = Instant::now();
+        let mut stream = start_execute(channel, action_digest).await;
+        let first = stream.next().await.unwrap().unwrap();
+        assert!(!first.done);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        let op = last_op(stream).await;
+        assert!(op.done);
+        assert!(start.elapsed() >= Duration::from_secs(2));
+    })
+    .await;
+}
+
+/// The status an operation finished with.
+fn op_status(op: &Operation) -> protos::rpc::Status {
+    let Some(Response(result)) = &op.result else {
+        panic!("operation has no response")
+    };
+    let resp: protos::re::ExecuteResponse = Message::decode(result.value.as_slice()).unwrap();
+    resp.status.unwrap()
+}
+
+#[tokio::test]
+async fn cancel_running_operation() {
+    oryx_test(|channel| async move {
+        // Left behind by a grandchild of the action if it isn't killed along with it.
+        let marker = NamedTempFile::new().unwrap().into_temp_path();
+        std::fs::remove_file(&marker).unwrap();
+        let script = format!(
+            "(sleep 2; touch {}) & wait; echo caprivi > out.txt",
+            marker.display()
+        );
+        let action_digest = add_script(channel.clone(), &script).await;
+
+        let start = Instant::now();
+        let mut stream = start_execute(channel.clone(), action_digest).await;
+        let first = stream.next().await.unwrap().unwrap();
+        assert!(!first.done);
+
+        let mut client = protos::OperationsClient::new(channel);
+        client
+            .cancel_operation(Request::new(protos::longrunning::CancelOperationRequest {
+                name: first.name.clone(),
+            }))
+            .await
+            .unwrap();
+        let op = last_op(stream).await;
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(op.done);
+        assert_eq!(op_status(&op).code, protos::rpc::Code::Cancelled as i32);
+
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert!(!marker.exists());
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn abandoned_operation_is_cancelled() {
+    oryx_test(|channel| async move {
+        let action_digest = add_script(channel.clone(), "sleep 30; echo ohangwena > out.txt").await;
+
+        let mut stream = start_execute(channel.clone(), action_digest).await;
+        let first = stream.next().await.unwrap().unwrap();
+        drop(stream);
+
+        // Nobody reconnects within the grace period.
+        tokio::time::sleep(Duration::from_secs(7)).await;
+        let mut client = protos::OperationsClient::new(channel);
+        let op = client
+            .get_operation(Request::new(protos::longrunning::GetOperationRequest {
+                name: first.name,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(op.done);
+        assert_eq!(op_status(&op).code, protos::rpc::Code::Cancelled as i32);
+    })
+    .await;
+}
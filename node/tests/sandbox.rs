@@ -0,0 +1,108 @@
+use crate::oryx_test_with_workers;
+use gemsbok::*;
+use node_lib::worker::WorkerConfig;
+use node_lib::{EngineConfig, ExecutionEngine, Platform};
+use std::path::PathBuf;
+use tonic::transport::Channel;
+
+fn sandbox_worker(platform: &[(&str, &str)]) -> WorkerConfig {
+    WorkerConfig {
+        instance: String::from(""),
+        name: String::from("sandbox"),
+        token: String::from(crate::WORKER_TOKEN),
+        capacity: 1,
+        execution_engine: ExecutionEngine::Sandbox,
+        platform: platform
+            .iter()
+            .map(|(name, value)| (String::from(*name), String::from(*value)))
+            .collect::<Platform>(),
+        sandbox: Default::default(),
+        cgroup: Default::default(),
+    }
+}
+
+/// Run a shell command in a sandbox, with `in.txt` as its input.
+async fn execute(channel: Channel, script: &str, platform: &[(&str, &str)]) -> ActionResult {
+    let mut client = Gemsbok::new(channel);
+    let command_digest = client
+        .add_command(&["/bin/sh", "-c", script], &["out.txt"])
+        .await
+        .unwrap();
+    let mut input_directory = Directory::root();
+    input_directory.add_path(&PathBuf::from("in.txt"), Some(b"kalahari\n"));
+    let root_dir_digest = client.add_directory(input_directory).await.unwrap();
+    let action_digest = client
+        .add_action_with_platform(command_digest, root_dir_digest, platform)
+        .await
+        .unwrap();
+    client.execute(action_digest).await.unwrap()
+}
+
+#[tokio::test]
+async fn sandboxed_actions_read_inputs_and_write_outputs() {
+    let workers = vec![sandbox_worker(&[])];
+    oryx_test_with_workers(EngineConfig::default(), workers, |channel| async move {
+        let result = execute(channel, "cat in.txt > out.txt", &[]).await;
+
+        let mut expected_directory = Directory::root();
+        expected_directory.add_path(&PathBuf::from("out.txt"), Some(b"kalahari\n"));
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.directory, expected_directory);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn sandboxed_actions_only_see_allowed_paths() {
+    let workers = vec![sandbox_worker(&[])];
+    oryx_test_with_workers(EngineConfig::default(), workers, |channel| async move {
+        let script = "test -d /usr && test ! -e /root && test ! -e /home; echo $? > out.txt";
+        let result = execute(channel.clone(), script, &[]).await;
+        assert_eq!(result.exit_code, 0);
+        let mut expected_directory = Directory::root();
+        expected_directory.add_path(&PathBuf::from("out.txt"), Some(b"0\n"));
+        assert_eq!(result.directory, expected_directory);
+
+        // System paths can't be written to, but /tmp can.
+        let script = "touch /usr/oryx || echo tmp > /tmp/out.txt && cp /tmp/out.txt out.txt";
+        let result = execute(channel, script, &[]).await;
+        assert_eq!(result.exit_code, 0);
+        let mut expected_directory = Directory::root();
+        expected_directory.add_path(&PathBuf::from("out.txt"), Some(b"tmp\n"));
+        assert_eq!(result.directory, expected_directory);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn sandboxed_actions_are_their_own_init() {
+    let workers = vec![sandbox_worker(&[])];
+    oryx_test_with_workers(EngineConfig::default(), workers, |channel| async move {
+        // Nothing but the command, and what it starts, runs in its PID namespace.
+        let script = "echo $$ > out.txt; ls /proc > procs; grep -c '^[0-9]' procs >> out.txt";
+        let result = execute(channel, script, &[]).await;
+        assert_eq!(result.exit_code, 0);
+        let mut expected_directory = Directory::root();
+        expected_directory.add_path(&PathBuf::from("out.txt"), Some(b"1\n2\n"));
+        assert_eq!(result.directory, expected_directory);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn sandboxed_actions_only_have_network_when_asked() {
+    let workers = vec![sandbox_worker(&[("network", "enabled")])];
+    oryx_test_with_workers(EngineConfig::default(), workers, |channel| async move {
+        // Interfaces other than loopback are only there with network access.
+        let script = "grep -v -c -e '|' -e ' lo:' /proc/net/dev > out.txt";
+        let without = execute(channel.clone(), script, &[]).await;
+        assert_eq!(without.exit_code, 1);
+        let mut expected_directory = Directory::root();
+        expected_directory.add_path(&PathBuf::from("out.txt"), Some(b"0\n"));
+        assert_eq!(without.directory, expected_directory);
+
+        let with = execute(channel, script, &[("network", "enabled")]).await;
+        assert_eq!(with.exit_code, 0);
+    })
+    .await;
+}
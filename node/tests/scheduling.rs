@@ -0,0 +1,137 @@
+use crate::oryx_test_with_config;
+use gemsbok::*;
+use node_lib::EngineConfig;
+use prost::Message;
+use protos::longrunning::Operation;
+use protos::re::execution_stage::Value as Stage;
+use std::time::{Duration, Instant};
+use tokio_stream::StreamExt;
+use tonic::transport::Channel;
+use tonic::{Request, Streaming};
+
+/// Start executing a shell command that writes `out.txt`.
+async fn start_execute(channel: Channel, script: &str, priority: i32) -> Streaming<Operation> {
+    let mut client = Gemsbok::new(channel.clone());
+    let command_digest = client
+        .add_command(&["/bin/sh", "-c", script], &["out.txt"])
+        .await
+        .unwrap();
+    let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+    let action_digest = client
+        .add_action(command_digest, root_dir_digest)
+        .await
+        .unwrap();
+
+    let mut exec_client = protos::ExecutionClient::new(channel);
+    exec_client
+        .execute(Request::new(protos::re::ExecuteRequest {
+            instance_name: "".to_string(),
+            action_digest: Some(action_digest.0.into()),
+            execution_policy: Some(protos::re::ExecutionPolicy { priority }),
+            results_cache_policy: None,
+            skip_cache_lookup: true,
+        }))
+        .await
+        .unwrap()
+        .into_inner()
+}
+
+/// The stage an operation is in.
+fn stage(op: &Operation) -> Stage {
+    let metadata = op.metadata.as_ref().unwrap();
+    let metadata = protos::re::ExecuteOperationMetadata::decode(&*metadata.value).unwrap();
+    Stage::from_i32(metadata.stage).unwrap()
+}
+
+/// Every stage an operation goes through, and when it finished.
+async fn stages(mut stream: Streaming<Operation>) -> (Vec<Stage>, Instant) {
+    let mut stages = vec![];
+    while let Some(op) = stream.next().await {
+        let stage = stage(&op.unwrap());
+        if stages.last() != Some(&stage) {
+            stages.push(stage);
+        }
+    }
+    (stages, Instant::now())
+}
+
+#[tokio::test]
+async fn actions_beyond_concurrency_limit_are_queued() {
+    let config = EngineConfig {
+        max_concurrent_actions: 1,
+        ..Default::default()
+    };
+    oryx_test_with_config(config, |channel| async move {
+        let start = Instant::now();
+        let mut first =
+            start_execute(channel.clone(), "sleep 1; echo sossusvlei > out.txt", 0).await;
+        // Wait for the first action to take the only slot.
+        while stage(&first.next().await.unwrap().unwrap()) != Stage::Executing {}
+        let second = start_execute(channel, "sleep 1; echo deadvlei > out.txt", 0).await;
+
+        let ((_, first_done), (second_stages, second_done)) =
+            tokio::join!(stages(first), stages(second));
+        assert_eq!(
+            second_stages,
+            vec![Stage::Queued, Stage::Executing, Stage::Completed]
+        );
+        assert!(first_done <= second_done);
+        assert!(second_done - start >= Duration::from_secs(2));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn important_actions_are_run_first() {
+    let config = EngineConfig {
+        max_concurrent_actions: 1,
+        ..Default::default()
+    };
+    oryx_test_with_config(config, |channel| async move {
+        let mut first =
+            start_execute(channel.clone(), "sleep 1; echo brandberg > out.txt", 0).await;
+        while stage(&first.next().await.unwrap().unwrap()) != Stage::Executing {}
+        let unimportant = start_execute(channel.clone(), "echo erongo > out.txt", 10).await;
+        // Give the unimportant action time to queue up first.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let important = start_execute(channel, "echo spitzkoppe > out.txt", -10).await;
+
+        let (_, (_, unimportant_done), (_, important_done)) =
+            tokio::join!(stages(first), stages(unimportant), stages(important));
+        assert!(important_done < unimportant_done);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn priority_range_is_advertised() {
+    let config = EngineConfig {
+        min_priority: -5,
+        max_priority: 5,
+        ..Default::default()
+    };
+    oryx_test_with_config(config, |channel| async move {
+        let mut client = protos::CapabilitiesClient::new(channel);
+        let caps = client
+            .get_capabilities(Request::new(protos::re::GetCapabilitiesRequest {
+                instance_name: "".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let priorities = caps
+            .execution_capabilities
+            .unwrap()
+            .execution_priority_capabilities
+            .unwrap()
+            .priorities;
+        assert_eq!(
+            priorities,
+            vec![protos::re::priority_capabilities::PriorityRange {
+                min_priority: -5,
+                max_priority: 5,
+            }]
+        );
+    })
+    .await;
+}
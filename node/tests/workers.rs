@@ -0,0 +1,300 @@
+use crate::oryx_test_with_workers;
+use gemsbok::*;
+use node_lib::worker::{run_worker, Worker, WorkerConfig};
+use node_lib::{EngineConfig, ExecutionEngine, Platform};
+use protos::rpc::Code;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio_stream::StreamExt;
+use tonic::transport::Channel;
+
+fn platform(properties: &[(&str, &str)]) -> Platform {
+    properties
+        .iter()
+        .map(|(name, value)| (String::from(*name), String::from(*value)))
+        .collect()
+}
+
+fn worker(name: &str, capacity: u32, properties: &[(&str, &str)]) -> WorkerConfig {
+    WorkerConfig {
+        instance: String::from(""),
+        name: String::from(name),
+        token: String::from(crate::WORKER_TOKEN),
+        capacity,
+        execution_engine: ExecutionEngine::Insecure,
+        platform: platform(properties),
+        sandbox: Default::default(),
+        cgroup: Default::default(),
+    }
+}
+
+/// Run a shell command that writes `out.txt` on a machine with `platform`.
+async fn execute(
+    channel: Channel,
+    script: &str,
+    platform: &[(&str, &str)],
+) -> protos::re::ExecuteResponse {
+    let mut client = Gemsbok::new(channel);
+    let command_digest = client
+        .add_command(&["/bin/sh", "-c", script], &["out.txt"])
+        .await
+        .unwrap();
+    let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+    let action_digest = client
+        .add_action_with_platform(command_digest, root_dir_digest, platform)
+        .await
+        .unwrap();
+    client.execute_response(action_digest).await.unwrap()
+}
+
+#[tokio::test]
+async fn actions_run_on_workers() {
+    let workers = vec![worker("worker-0", 1, &[]), worker("worker-1", 1, &[])];
+    oryx_test_with_workers(EngineConfig::default(), workers, |channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let command_digest = client
+            .add_command(&["/bin/sh", "-c", "cat in.txt > out.txt"], &["out.txt"])
+            .await
+            .unwrap();
+        let mut input_directory = Directory::root();
+        input_directory.add_path(&PathBuf::from("in.txt"), Some(b"okavango\n"));
+        let root_dir_digest = client.add_directory(input_directory).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let result = client.execute(action_digest).await.unwrap();
+
+        // The worker fetched the input from, and stored the output in, the node's CAS.
+        let mut expected_directory = Directory::root();
+        expected_directory.add_path(&PathBuf::from("out.txt"), Some(b"okavango\n"));
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.directory, expected_directory);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn workers_handle_blobs_too_big_to_batch() {
+    let workers = vec![worker("worker-0", 1, &[])];
+    oryx_test_with_workers(EngineConfig::default(), workers, |channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let command_digest = client
+            .add_command(
+                &["/bin/sh", "-c", "cat in.txt in.txt > out.txt"],
+                &["out.txt"],
+            )
+            .await
+            .unwrap();
+        // Bigger than gRPC messages are allowed to be by default.
+        let input: Vec<u8> = (0..5 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut input_directory = Directory::root();
+        input_directory.add_path(&PathBuf::from("in.txt"), Some(&input));
+        let root_dir_digest = client.add_directory(input_directory).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let result = client.execute(action_digest).await.unwrap();
+
+        let mut expected_directory = Directory::root();
+        expected_directory.add_path(&PathBuf::from("out.txt"), Some(&input.repeat(2)));
+        assert_eq!(result.exit_code, 0);
+        // Not assert_eq, which would print megabytes of contents on failure.
+        assert!(result.directory == expected_directory);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn workers_store_long_logs_in_cas() {
+    let workers = vec![worker("worker-0", 1, &[])];
+    let config = EngineConfig {
+        max_inline_output_bytes: 16,
+        ..Default::default()
+    };
+    oryx_test_with_workers(config, workers, |channel| async move {
+        let script = "printf '%0100d' 0; touch out.txt";
+        let response = execute(channel, script, &[]).await;
+        assert_eq!(response.status.unwrap().code, Code::Ok as i32);
+        let result = response.result.unwrap();
+        assert!(result.stdout_raw.is_empty());
+        assert_eq!(result.stdout_digest.unwrap().size_bytes, 100);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn workers_store_logs_too_big_to_batch_in_cas() {
+    let workers = vec![worker("worker-0", 1, &[])];
+    oryx_test_with_workers(Default::default(), workers, |channel| async move {
+        let script = "head -c 5000000 /dev/zero >&2; touch out.txt";
+        let response = execute(channel.clone(), script, &[]).await;
+        assert_eq!(response.status.unwrap().code, Code::Ok as i32);
+        let result = response.result.unwrap();
+        assert!(result.stderr_raw.is_empty());
+        let digest = result.stderr_digest.unwrap();
+        assert_eq!(digest.size_bytes, 5_000_000);
+
+        let name = format!("blobs/{}/{}", digest.hash, digest.size_bytes);
+        let mut client = Gemsbok::new(channel);
+        let mut stream = client.read_stream(&name, 0, 0).await.unwrap();
+        let mut stderr = vec![];
+        while let Some(response) = stream.next().await {
+            stderr.extend(response.unwrap().data);
+        }
+        assert_eq!(stderr, vec![0; 5_000_000]);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn workers_forward_running_output() {
+    let workers = vec![worker("worker-0", 1, &[])];
+    oryx_test_with_workers(Default::default(), workers, |channel| async move {
+        let mut client = Gemsbok::new(channel);
+        let script = "echo damaraland; sleep 2; echo kaokoveld; touch out.txt";
+        let command_digest = client
+            .add_command(&["/bin/sh", "-c", script], &["out.txt"])
+            .await
+            .unwrap();
+        let root_dir_digest = client.add_directory(Directory::root()).await.unwrap();
+        let action_digest = client
+            .add_action(command_digest, root_dir_digest)
+            .await
+            .unwrap();
+        let (_ops, metadata) = client.execute_until_running(action_digest).await.unwrap();
+        let mut stdout = client
+            .read_stream(&metadata.stdout_stream_name, 0, 0)
+            .await
+            .unwrap();
+        let first = stdout.next().await.unwrap().unwrap();
+        assert_eq!(first.data, b"damaraland\n");
+        let mut rest = vec![];
+        while let Some(response) = stdout.next().await {
+            rest.extend(response.unwrap().data);
+        }
+        assert_eq!(rest, b"kaokoveld\n");
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn workers_run_actions_side_by_side() {
+    let workers = vec![worker("worker-0", 1, &[]), worker("worker-1", 1, &[])];
+    let config = EngineConfig {
+        max_concurrent_actions: 8,
+        ..Default::default()
+    };
+    oryx_test_with_workers(config, workers, |channel| async move {
+        // Each worker only has room for one action, so they only finish in time
+        // if both workers are used.
+        let start = Instant::now();
+        let (first, second) = tokio::join!(
+            execute(channel.clone(), "sleep 2; echo first > out.txt", &[]),
+            execute(channel.clone(), "sleep 2; echo second > out.txt", &[]),
+        );
+        assert!(start.elapsed() < Duration::from_secs(4));
+        assert_eq!(first.status.unwrap().code, Code::Ok as i32);
+        assert_eq!(second.status.unwrap().code, Code::Ok as i32);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn actions_are_leased_to_matching_workers() {
+    let workers = vec![
+        worker("small", 1, &[("OSFamily", "linux"), ("pool", "small")]),
+        worker("large", 1, &[("OSFamily", "linux"), ("pool", "large")]),
+    ];
+    let config = EngineConfig {
+        max_concurrent_actions: 8,
+        ..Default::default()
+    };
+    oryx_test_with_workers(config, workers, |channel| async move {
+        // Only the large worker may run these, so they run one after the other
+        // even though the small worker is idle.
+        let large = [("OSFamily", "linux"), ("pool", "large")];
+        let start = Instant::now();
+        let (first, second) = tokio::join!(
+            execute(channel.clone(), "sleep 1; echo first > out.txt", &large),
+            execute(channel.clone(), "sleep 1; echo second > out.txt", &large),
+        );
+        assert!(start.elapsed() >= Duration::from_secs(2));
+        for resp in [first, second] {
+            assert_eq!(resp.status.unwrap().code, Code::Ok as i32);
+            let metadata = resp.result.unwrap().execution_metadata.unwrap();
+            assert_eq!(metadata.worker, "large");
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn actions_no_worker_matches_are_rejected() {
+    let workers = vec![worker("worker-0", 1, &[("OSFamily", "linux")])];
+    let config = EngineConfig {
+        worker_platforms: vec![platform(&[("OSFamily", "linux")])],
+        ..Default::default()
+    };
+    oryx_test_with_workers(config, workers, |channel| async move {
+        let resp = execute(
+            channel,
+            "echo kgalagadi > out.txt",
+            &[("OSFamily", "windows")],
+        )
+        .await;
+        let status = resp.status.unwrap();
+        assert_eq!(status.code, Code::FailedPrecondition as i32);
+        assert!(status.message.contains("OSFamily=windows"));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn actions_wait_for_expected_workers() {
+    let workers = vec![worker("worker-0", 1, &[("pool", "etosha")])];
+    let config = EngineConfig {
+        worker_platforms: vec![
+            platform(&[("pool", "etosha")]),
+            platform(&[("pool", "namib")]),
+        ],
+        ..Default::default()
+    };
+    oryx_test_with_workers(config, workers, |channel| async move {
+        let start = Instant::now();
+        let late_worker = async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let config = worker("worker-1", 1, &[("pool", "namib")]);
+            run_worker(config, channel.clone()).await.unwrap();
+        };
+        let execute = execute(channel.clone(), "touch out.txt", &[("pool", "namib")]);
+        tokio::select! {
+            resp = execute => {
+                assert_eq!(resp.status.unwrap().code, Code::Ok as i32);
+                assert!(start.elapsed() >= Duration::from_secs(1));
+            }
+            _ = late_worker => unreachable!(),
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn workers_with_the_wrong_token_are_rejected() {
+    oryx_test_with_workers(Default::default(), vec![], |channel| async move {
+        for token in ["", "oryx-test-worker-tokem"] {
+            let config = WorkerConfig {
+                token: String::from(token),
+                ..worker("intruder", 1, &[])
+            };
+            let err = Worker::register(config, channel.clone())
+                .await
+                .err()
+                .unwrap();
+            let status = err.downcast_ref::<tonic::Status>().unwrap();
+            assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        }
+    })
+    .await;
+}
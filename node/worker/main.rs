@@ -0,0 +1,54 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::path::PathBuf;
+use tokio::signal;
+use tokio::{fs::File, io::AsyncReadExt};
+use tonic::transport::Endpoint;
+use tracing::info;
+
+#[derive(Parser, Debug)]
+#[command(name = "oryx-worker")]
+#[command(author = "Benjamin Brittain. <ben@brittain.org>")]
+#[command(version = "0.1")]
+#[command(about = "Oryx Remote Build Execution worker", long_about = None)]
+struct Args {
+    /// Path to worker configuration file
+    #[arg(long)]
+    config: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    /// URL of the node to run actions for.
+    node: String,
+    #[serde(flatten)]
+    worker: node_lib::worker::WorkerConfig,
+}
+
+/// Read the oryx worker config
+async fn read_config(config_file: PathBuf) -> Result<Config, Box<dyn std::error::Error>> {
+    let mut file = File::open(config_file).await?;
+    let mut contents = vec![];
+    file.read_to_end(&mut contents).await?;
+    let contents = std::str::from_utf8(&contents)?;
+    Ok(toml::from_str(contents)?)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let config = read_config(args.config).await?;
+
+    tracing_subscriber::fmt::init();
+    info!(node = config.node, "Connecting");
+
+    let channel = Endpoint::from_shared(config.node)?.connect().await?;
+    let worker_fut = node_lib::worker::run_worker(config.worker, channel);
+
+    tokio::select! {
+        _ = signal::ctrl_c() => (),
+        result = worker_fut => result?,
+    }
+    Ok(())
+}
@@ -32,6 +32,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             "google/rpc/code.proto",
             "google/rpc/status.proto",
             "google/rpc/error_details.proto",
+            "oryx/execution/v1/resources.proto",
+            "oryx/worker/v1/worker.proto",
         ],
         &["."],
     )?;
@@ -23,6 +23,12 @@ pub use google::longrunning::{
     operations_server::{Operations, OperationsServer},
 };
 pub use google::rpc;
+pub use oryx::execution::v1 as execution;
+pub use oryx::worker::v1::{
+    self as worker,
+    workers_client::WorkersClient,
+    workers_server::{Workers, WorkersServer},
+};
 
 mod google {
     pub mod longrunning {
@@ -50,3 +56,16 @@ mod build {
         }
     }
 }
+
+mod oryx {
+    pub mod execution {
+        pub mod v1 {
+            tonic::include_proto!("oryx.execution.v1");
+        }
+    }
+    pub mod worker {
+        pub mod v1 {
+            tonic::include_proto!("oryx.worker.v1");
+        }
+    }
+}